@@ -0,0 +1,21 @@
+//! Where the daemon and tools keep their settings
+
+use std::path::PathBuf;
+
+/// Name of our directory under the config dirs
+pub const CONFIG_DIR_NAME: &str = "razer-control-center";
+
+/// System wide settings, e.g. /etc/razer-control-center
+pub fn system_config_dir() -> PathBuf {
+    PathBuf::from("/etc").join(CONFIG_DIR_NAME)
+}
+
+/// Per user settings. $XDG_CONFIG_HOME/razer-control-center, falling back to
+/// ~/.config/razer-control-center. None if neither variable is set
+pub fn user_config_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME").filter(|x| !x.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config")
+    };
+    Some(base.join(CONFIG_DIR_NAME))
+}
@@ -0,0 +1,606 @@
+//! Database of every device we know about.
+//!
+//! The database is a JSON file (`devices.json`). A copy is built in, and entries from
+//! `/etc/razer-control-center/devices.json` then the user's own `devices.json` are layered
+//! on top, replacing any built-in entry with the same product ID. So a new Blade only
+//! needs an entry in one of those files rather than a rebuild.
+//!
+//! ```json
+//! {
+//!   "devices": [
+//!     {
+//!       "product_id": "0x026D",
+//!       "name": "Razer blade 15 early 2021 (Adv)",
+//!       "class": "laptop",
+//!       "transaction_id": "0x1F",
+//!       "matrix_type": "MultiColourMultiZone",
+//!       "matrix": { "rows": 6, "cols": 16 },
+//!       "leds": ["backlight", "logo"],
+//!       "fan_zones": 2,
+//!       "fan_rpm": { "min": 3500, "max": 5000 },
+//!       "modes": ["balanced", "gaming", "creator", "custom"],
+//!       "max_boost": { "cpu": "boost", "gpu": "high" },
+//!       "battery_health": true
+//!     },
+//!     {
+//!       "product_id": "0x0C04",
+//!       "name": "Razer Firefly V2",
+//!       "class": "mousepad",
+//!       "transaction_id": "0x1F",
+//!       "matrix": { "rows": 1, "cols": 19 },
+//!       "extended_matrix": true
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Only `product_id`, `name` and `class` are required. Strips (mousepads, docks) are
+//! a matrix with 1 row, single zone accessories a 1x1 matrix. `max_boost` is the highest
+//! CPU and GPU boost custom mode takes, `high` for both if left out. `battery_health` marks
+//! laptops with the battery health optimizer (charge threshold). `retry` is `wireless` for
+//! devices talked to through a wireless receiver, which need longer waits and more retries
+//! than the default `wired`.
+//!
+//! Some Blades share a product ID but not their fans, keyboard or power modes. Laptop entries
+//! can list `variants`, picked by the laptop's SMBIOS strings. The first variant whose `match`
+//! fits wins, and its fields replace the entry's (`name`, `matrix_type`, `matrix`, `leds`,
+//! `fan_zones`, `fan_rpm`, `modes`, `max_boost` and `battery_health` can be given):
+//!
+//! ```json
+//! "variants": [
+//!   { "match": { "sku": "RZ09-0367B" }, "name": "Razer blade 15 early 2021 (Adv, 1 fan)", "fan_zones": 1 }
+//! ]
+//! ```
+//!
+//! `sku` matches the start of the SKU (The rest is region and config), `product_name`
+//! and `board_version` match the whole string. Case and surrounding spaces are ignored
+
+use std::{collections::HashSet, path::{Path, PathBuf}, sync::{Arc, RwLock}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{RGBControl, config::{system_config_dir, user_config_dir}, hw::{ControlInterface, DEFAULT_TRANSACTION_ID, SmbiosInfo}, laptop::BoostLimits};
+
+/// Name of the database file in each config dir
+pub const DB_FILE_NAME: &str = "devices.json";
+
+/// Most columns a matrix row can have (A channel of the Chroma addressable controller).
+/// Rows longer than a report can take are uploaded in pieces
+pub const MAX_MATRIX_COLS: u8 = 80;
+
+/// Most fan zones a laptop EC has
+pub const MAX_FAN_ZONES: u8 = 2;
+
+const BUILTIN_DB: &str = include_str!("../devices.json");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceClass {
+    Laptop,
+    Keyboard,
+    Mouse,
+    Mousepad,
+    Headset,
+    /// Base stations, mug holders and other desk docks
+    Dock,
+    /// Keypads like the Tartarus and Orbweaver
+    Keypad,
+    /// Charging dock for a wireless mouse
+    MouseDock,
+    /// Chroma addressable RGB controller. Each matrix row is one channel
+    AddressableController
+}
+
+/// LEDs a device can have besides its matrix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedZone {
+    ScrollWheel,
+    Battery,
+    Logo,
+    Backlight,
+    Macro,
+    Game,
+    RedProfile,
+    GreenProfile,
+    BlueProfile,
+    RightSide,
+    LeftSide,
+    Charging,
+    FastCharging,
+    FullyCharged
+}
+
+/// How patient the daemon has to be with a model's replies
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryProfile {
+    #[default]
+    Wired,
+    /// Reports are relayed through a wireless receiver
+    Wireless
+}
+
+/// Power modes a laptop's EC offers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerModeKind {
+    Balanced,
+    Gaming,
+    Creator,
+    Custom
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixSize {
+    pub rows: u8,
+    pub cols: u8
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FanRpmRange {
+    pub min: u32,
+    pub max: u32
+}
+
+/// SMBIOS strings a laptop variant is picked by. Fields left out match anything
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmbiosMatch {
+    pub product_name: Option<String>,
+    /// Prefix of the SKU
+    pub sku: Option<String>,
+    pub board_version: Option<String>
+}
+
+impl SmbiosMatch {
+    pub fn is_empty(&self) -> bool {
+        self.product_name.is_none() && self.sku.is_none() && self.board_version.is_none()
+    }
+
+    pub fn matches(&self, info: &SmbiosInfo) -> bool {
+        let norm = |s: &str| s.trim().to_ascii_lowercase();
+        let whole = |want: &Option<String>, have: &str| want.as_ref().map(|w| norm(w) == norm(have)).unwrap_or(true);
+        let prefix = self.sku.as_ref().map(|w| norm(&info.sku).starts_with(&norm(w))).unwrap_or(true);
+        // An empty match would pick the variant on every machine
+        !self.is_empty() && prefix && whole(&self.product_name, &info.product_name) && whole(&self.board_version, &info.board_version)
+    }
+}
+
+/// A laptop model sharing its product ID with its base entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SpecVariant {
+    pub matches: SmbiosMatch,
+    /// The base entry with the variant's fields applied
+    pub spec: DeviceSpec
+}
+
+/// Everything the database says about one model
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceSpec {
+    pub product_id: u16,
+    pub name: String,
+    pub class: DeviceClass,
+    pub transaction_id: u8,
+    pub matrix_type: RGBControl,
+    /// Size of the per key matrix, if there is one
+    pub matrix: Option<MatrixSize>,
+    /// Frames go through the extended matrix commands (class 0x0F) rather than the standard ones
+    pub extended_matrix: bool,
+    pub leds: Vec<LedZone>,
+    pub fan_zones: u8,
+    pub fan_rpm: Option<FanRpmRange>,
+    pub modes: Vec<PowerModeKind>,
+    /// Highest boost levels in custom mode. Set when `modes` has custom
+    pub max_boost: Option<BoostLimits>,
+    /// Has the battery health optimizer
+    pub battery_health: bool,
+    pub control_interface: ControlInterface,
+    pub retry: RetryProfile,
+    /// Models sharing this product ID, told apart by SMBIOS. Only laptops have them
+    pub variants: Vec<SpecVariant>
+}
+
+impl DeviceSpec {
+    /// The entry for the variant `smbios` matches, or this one if none do
+    pub fn refine(&self, smbios: &SmbiosInfo) -> DeviceSpec {
+        match self.variants.iter().find(|v| v.matches.matches(smbios)) {
+            Some(v) => v.spec.clone(),
+            None => self.clone()
+        }
+    }
+
+    pub fn has_led(&self, led: LedZone) -> bool {
+        self.leds.contains(&led)
+    }
+
+    pub fn has_mode(&self, mode: PowerModeKind) -> bool {
+        self.modes.contains(&mode)
+    }
+}
+
+/// Product IDs and transaction IDs can be written as numbers or "0x" hex strings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Id {
+    Num(u64),
+    Hex(String)
+}
+
+impl Id {
+    fn parse(&self, field: &str, max: u64) -> Result<u64, String> {
+        let value = match self {
+            Id::Num(x) => Some(*x),
+            Id::Hex(s) => s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")).and_then(|h| u64::from_str_radix(h, 16).ok())
+        };
+        match value {
+            Some(x) if x <= max => Ok(x),
+            Some(x) => Err(format!("{} {:#X} is bigger than {:#X}", field, x, max)),
+            None => Err(format!("{} {:?} is not a number or 0x prefixed hex string", field, self))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawVariant {
+    #[serde(rename = "match")]
+    matches: SmbiosMatch,
+    name: Option<String>,
+    matrix_type: Option<RGBControl>,
+    matrix: Option<MatrixSize>,
+    leds: Option<Vec<LedZone>>,
+    fan_zones: Option<u8>,
+    fan_rpm: Option<FanRpmRange>,
+    modes: Option<Vec<PowerModeKind>>,
+    max_boost: Option<BoostLimits>,
+    battery_health: Option<bool>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSpec {
+    product_id: Id,
+    name: String,
+    class: DeviceClass,
+    transaction_id: Option<Id>,
+    matrix_type: Option<RGBControl>,
+    matrix: Option<MatrixSize>,
+    #[serde(default)]
+    extended_matrix: bool,
+    #[serde(default)]
+    leds: Vec<LedZone>,
+    #[serde(default)]
+    fan_zones: u8,
+    fan_rpm: Option<FanRpmRange>,
+    #[serde(default)]
+    modes: Vec<PowerModeKind>,
+    max_boost: Option<BoostLimits>,
+    #[serde(default)]
+    battery_health: bool,
+    control_interface: Option<ControlInterface>,
+    #[serde(default)]
+    retry: RetryProfile,
+    #[serde(default)]
+    variants: Vec<RawVariant>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDb {
+    devices: Vec<RawSpec>
+}
+
+impl RawSpec {
+    /// The entry with a variant's fields applied
+    fn with_variant(&self, v: RawVariant) -> RawSpec {
+        let mut spec = self.clone();
+        spec.variants = Vec::new();
+        spec.name = v.name.unwrap_or(spec.name);
+        spec.matrix_type = v.matrix_type.or(spec.matrix_type);
+        spec.matrix = v.matrix.or(spec.matrix);
+        spec.leds = v.leds.unwrap_or(spec.leds);
+        spec.fan_zones = v.fan_zones.unwrap_or(spec.fan_zones);
+        spec.fan_rpm = v.fan_rpm.or(spec.fan_rpm);
+        spec.modes = v.modes.unwrap_or(spec.modes);
+        spec.max_boost = v.max_boost.or(spec.max_boost);
+        spec.battery_health = v.battery_health.unwrap_or(spec.battery_health);
+        spec
+    }
+
+    fn validate(mut self) -> Result<DeviceSpec, String> {
+        let raw_variants = std::mem::take(&mut self.variants);
+        if !raw_variants.is_empty() && self.class != DeviceClass::Laptop {
+            return Err("only laptops have variants".into())
+        }
+        let product_id = self.product_id.parse("product_id", 0xFFFF)? as u16;
+        let transaction_id = match &self.transaction_id {
+            Some(id) => id.parse("transaction_id", 0xFF)? as u8,
+            None => DEFAULT_TRANSACTION_ID
+        };
+        if self.name.trim().is_empty() {
+            return Err("name is empty".into())
+        }
+        if let Some(m) = self.matrix {
+            if m.rows == 0 || m.cols == 0 {
+                return Err(format!("matrix is {}x{}, it needs at least 1 row and column", m.rows, m.cols))
+            }
+            if m.cols > MAX_MATRIX_COLS {
+                return Err(format!("matrix has {} columns, at most {} are supported", m.cols, MAX_MATRIX_COLS))
+            }
+        }
+        if self.extended_matrix && self.matrix.is_none() {
+            return Err("extended_matrix is set, but there is no matrix".into())
+        }
+        match (self.class, self.matrix) {
+            (DeviceClass::Mousepad | DeviceClass::Dock | DeviceClass::MouseDock, Some(m)) if m.rows != 1 => {
+                return Err(format!("matrix is {}x{}, a {:?} only has a single row", m.rows, m.cols, self.class))
+            },
+            (DeviceClass::AddressableController, None) => return Err("an addressable controller needs a matrix (one row per channel)".into()),
+            _ => {}
+        }
+        let matrix_type = match (self.matrix_type, self.matrix) {
+            (Some(RGBControl::MultiColourMultiZone), None) => return Err("matrix_type is MultiColourMultiZone, but there is no matrix".into()),
+            (Some(t), _) => t,
+            (None, Some(_)) => RGBControl::MultiColourMultiZone,
+            (None, None) => RGBControl::OneColourOneZone
+        };
+        let mut seen = HashSet::new();
+        if let Some(dup) = self.leds.iter().find(|l| !seen.insert(**l)) {
+            return Err(format!("led {:?} is listed twice", dup))
+        }
+        if self.class != DeviceClass::Laptop {
+            if self.fan_zones != 0 || self.fan_rpm.is_some() {
+                return Err("only laptops have fans".into())
+            }
+            if !self.modes.is_empty() {
+                return Err("only laptops have power modes".into())
+            }
+            if self.battery_health {
+                return Err("only laptops have a battery health optimizer".into())
+            }
+        }
+        let has_custom = self.modes.contains(&PowerModeKind::Custom);
+        if self.max_boost.is_some() && !has_custom {
+            return Err("max_boost is set, but custom is not in modes".into())
+        }
+        if self.fan_zones > MAX_FAN_ZONES {
+            return Err(format!("fan_zones is {}, at most {} are supported", self.fan_zones, MAX_FAN_ZONES))
+        }
+        if let Some(rpm) = self.fan_rpm {
+            if rpm.min >= rpm.max {
+                return Err(format!("fan_rpm min ({}) must be below max ({})", rpm.min, rpm.max))
+            }
+            if self.fan_zones == 0 {
+                return Err("fan_rpm is set, but fan_zones is 0".into())
+            }
+        }
+        let mut variants = Vec::with_capacity(raw_variants.len());
+        for (idx, v) in raw_variants.into_iter().enumerate() {
+            if v.matches.is_empty() {
+                return Err(format!("variant {} has nothing to match on", idx))
+            }
+            let matches = v.matches.clone();
+            let spec = self.with_variant(v).validate().map_err(|e| format!("variant {}: {}", idx, e))?;
+            variants.push(SpecVariant { matches, spec });
+        }
+        Ok(DeviceSpec {
+            product_id,
+            name: self.name,
+            class: self.class,
+            transaction_id,
+            matrix_type,
+            matrix: self.matrix,
+            extended_matrix: self.extended_matrix,
+            leds: self.leds,
+            fan_zones: self.fan_zones,
+            fan_rpm: self.fan_rpm,
+            modes: self.modes,
+            max_boost: if has_custom { Some(self.max_boost.unwrap_or_default()) } else { None },
+            battery_health: self.battery_health,
+            control_interface: self.control_interface.unwrap_or(ControlInterface::DEFAULT),
+            retry: self.retry,
+            variants
+        })
+    }
+}
+
+/// A database file that did not load. Says which file, which entry and what is wrong with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbError {
+    /// File the error is in
+    pub source: String,
+    /// Entry the error is in (index and product ID), if it is down to one entry
+    pub device: Option<String>,
+    pub message: String
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.device {
+            Some(dev) => write!(f, "{}: {}: {}", self.source, dev, self.message),
+            None => write!(f, "{}: {}", self.source, self.message)
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceDb {
+    devices: Vec<DeviceSpec>
+}
+
+impl DeviceDb {
+    /// Parses and validates a database. `source` is only used in errors
+    pub fn parse(json: &str, source: &str) -> Result<Self, DbError> {
+        let err = |device: Option<String>, message: String| DbError { source: source.into(), device, message };
+        // serde_json puts the line and column in its message
+        let raw: RawDb = serde_json::from_str(json).map_err(|e| err(None, e.to_string()))?;
+        let mut devices: Vec<DeviceSpec> = Vec::new();
+        for (idx, entry) in raw.devices.into_iter().enumerate() {
+            let desc = match entry.product_id.parse("product_id", 0xFFFF) {
+                Ok(pid) => format!("device {} ({:#06X})", idx, pid),
+                Err(_) => format!("device {}", idx)
+            };
+            let spec = entry.validate().map_err(|m| err(Some(desc.clone()), m))?;
+            if let Some(other) = devices.iter().position(|d| d.product_id == spec.product_id) {
+                return Err(err(Some(desc), format!("product_id is already used by device {}", other)))
+            }
+            devices.push(spec);
+        }
+        Ok(Self { devices })
+    }
+
+    /// The database built into the binary
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN_DB, "built-in devices.json").expect("Built-in device database is invalid")
+    }
+
+    /// Loads a database file. Ok(None) if there is no such file
+    pub fn load_file(path: &Path) -> Result<Option<Self>, DbError> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => Self::parse(&json, &path.display().to_string()).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(DbError { source: path.display().to_string(), device: None, message: e.to_string() })
+        }
+    }
+
+    /// /etc then the user's config dir, lowest priority first
+    pub fn default_paths() -> Vec<PathBuf> {
+        let mut paths = vec![system_config_dir().join(DB_FILE_NAME)];
+        if let Some(dir) = user_config_dir() {
+            paths.push(dir.join(DB_FILE_NAME));
+        }
+        paths
+    }
+
+    /// The built-in database with every file in `paths` layered on top, in order.
+    /// A file that fails to load is left out entirely, and its error returned
+    pub fn load_layers(paths: &[PathBuf]) -> (Self, Vec<DbError>) {
+        let mut db = Self::builtin();
+        let mut errors = Vec::new();
+        for path in paths {
+            match Self::load_file(path) {
+                Ok(Some(layer)) => db.merge(layer),
+                Ok(None) => {},
+                Err(e) => errors.push(e)
+            }
+        }
+        (db, errors)
+    }
+
+    /// Adds every entry of `other`, replacing entries with the same product ID
+    pub fn merge(&mut self, other: DeviceDb) {
+        for spec in other.devices {
+            match self.devices.iter_mut().find(|d| d.product_id == spec.product_id) {
+                Some(existing) => *existing = spec,
+                None => self.devices.push(spec)
+            }
+        }
+    }
+
+    pub fn get(&self, product_id: u16) -> Option<&DeviceSpec> {
+        self.devices.iter().find(|d| d.product_id == product_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceSpec> {
+        self.devices.iter()
+    }
+}
+
+static ACTIVE: RwLock<Option<Arc<DeviceDb>>> = RwLock::new(None);
+
+/// Makes `db` the database [active] hands out (and so what [DeviceType::from_id](crate::hw::DeviceType::from_id) uses)
+pub fn install(db: DeviceDb) {
+    *ACTIVE.write().unwrap() = Some(Arc::new(db));
+}
+
+/// The database in use. The built-in one unless something else was [install]ed
+pub fn active() -> Arc<DeviceDb> {
+    if let Some(db) = ACTIVE.read().unwrap().as_ref() {
+        return db.clone()
+    }
+    ACTIVE.write().unwrap().get_or_insert_with(|| Arc::new(DeviceDb::builtin())).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hw::DeviceType;
+
+    use super::*;
+
+    fn parse(devices: &[&str]) -> Result<DeviceDb, DbError> {
+        DeviceDb::parse(&format!(r#"{{ "devices": [{}] }}"#, devices.join(",")), "test.json")
+    }
+
+    /// Why a single entry was rejected
+    fn rejected(device: &str) -> String {
+        parse(&[device]).unwrap_err().message
+    }
+
+    #[test]
+    fn builtin_is_valid() {
+        assert!(DeviceDb::builtin().iter().count() > 0);
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        let cases = [
+            (r#"{ "product_id": "0x0084", "name": " ", "class": "mouse" }"#, "name is empty"),
+            (r#"{ "product_id": "0x10000", "name": "A", "class": "mouse" }"#, "product_id 0x10000 is bigger than 0xFFFF"),
+            (r#"{ "product_id": "84", "name": "A", "class": "mouse" }"#, "product_id Hex(\"84\") is not a number or 0x prefixed hex string"),
+            (r#"{ "product_id": 132, "name": "A", "class": "mouse", "transaction_id": 256 }"#, "transaction_id 0x100 is bigger than 0xFF"),
+            (r#"{ "product_id": 1, "name": "A", "class": "keyboard", "matrix": { "rows": 0, "cols": 22 } }"#, "matrix is 0x22, it needs at least 1 row and column"),
+            (r#"{ "product_id": 1, "name": "A", "class": "keyboard", "matrix": { "rows": 6, "cols": 81 } }"#, "matrix has 81 columns, at most 80 are supported"),
+            (r#"{ "product_id": 1, "name": "A", "class": "keyboard", "extended_matrix": true }"#, "extended_matrix is set, but there is no matrix"),
+            (r#"{ "product_id": 1, "name": "A", "class": "mousepad", "matrix": { "rows": 2, "cols": 15 } }"#, "matrix is 2x15, a Mousepad only has a single row"),
+            (r#"{ "product_id": 1, "name": "A", "class": "addressable_controller" }"#, "an addressable controller needs a matrix (one row per channel)"),
+            (r#"{ "product_id": 1, "name": "A", "class": "keyboard", "matrix_type": "MultiColourMultiZone" }"#, "matrix_type is MultiColourMultiZone, but there is no matrix"),
+            (r#"{ "product_id": 1, "name": "A", "class": "mouse", "leds": ["logo", "scroll_wheel", "logo"] }"#, "led Logo is listed twice"),
+            (r#"{ "product_id": 1, "name": "A", "class": "mouse", "fan_zones": 1 }"#, "only laptops have fans"),
+            (r#"{ "product_id": 1, "name": "A", "class": "mouse", "modes": ["gaming"] }"#, "only laptops have power modes"),
+            (r#"{ "product_id": 1, "name": "A", "class": "mouse", "battery_health": true }"#, "only laptops have a battery health optimizer"),
+            (r#"{ "product_id": 1, "name": "A", "class": "mouse", "variants": [{ "match": { "sku": "RZ01" } }] }"#, "only laptops have variants"),
+            (r#"{ "product_id": 1, "name": "A", "class": "laptop", "modes": ["balanced"], "max_boost": { "cpu": "boost", "gpu": "high" } }"#, "max_boost is set, but custom is not in modes"),
+            (r#"{ "product_id": 1, "name": "A", "class": "laptop", "fan_zones": 3 }"#, "fan_zones is 3, at most 2 are supported"),
+            (r#"{ "product_id": 1, "name": "A", "class": "laptop", "fan_zones": 1, "fan_rpm": { "min": 5000, "max": 5000 } }"#, "fan_rpm min (5000) must be below max (5000)"),
+            (r#"{ "product_id": 1, "name": "A", "class": "laptop", "fan_rpm": { "min": 3500, "max": 5000 } }"#, "fan_rpm is set, but fan_zones is 0"),
+            (r#"{ "product_id": 1, "name": "A", "class": "laptop", "variants": [{ "match": {} }] }"#, "variant 0 has nothing to match on"),
+            (r#"{ "product_id": 1, "name": "A", "class": "laptop", "variants": [{ "match": { "sku": "RZ09" }, "fan_zones": 3 }] }"#, "variant 0: fan_zones is 3, at most 2 are supported"),
+        ];
+        for (device, message) in cases {
+            assert_eq!(rejected(device), message, "{}", device);
+        }
+    }
+
+    #[test]
+    fn errors_say_where() {
+        let ok = r#"{ "product_id": "0x0084", "name": "A", "class": "mouse" }"#;
+        let err = parse(&[ok, r#"{ "product_id": "0x0085", "name": "", "class": "mouse" }"#]).unwrap_err();
+        assert_eq!(err.to_string(), "test.json: device 1 (0x0085): name is empty");
+
+        let err = parse(&[ok, ok]).unwrap_err();
+        assert_eq!(err.device.as_deref(), Some("device 1 (0x0084)"));
+        assert_eq!(err.message, "product_id is already used by device 0");
+
+        // serde's own errors are not down to one entry
+        let err = parse(&[r#"{ "product_id": 1, "name": "A", "class": "mouse", "colour": "red" }"#]).unwrap_err();
+        assert_eq!(err.device, None);
+        assert!(err.message.contains("unknown field `colour`"), "{}", err.message);
+        assert!(rejected(r#"{ "product_id": 1, "class": "mouse" }"#).contains("missing field `name`"));
+    }
+
+    #[test]
+    fn device_types_own_their_names() {
+        let device = r#"{ "product_id": 1, "name": "Razer Test Mouse", "class": "mouse", "leds": ["logo"] }"#;
+        let db = parse(&[device]).unwrap();
+        let device_type = DeviceType::from_spec(db.get(1).unwrap());
+        assert_eq!(device_type, DeviceType::Mouse(1, "Razer Test Mouse".into()));
+        // Outlives the database it came from, reloading does not leak the old names
+        drop(db);
+        assert_eq!(device_type.get_name(), "Razer Test Mouse");
+    }
+}
@@ -29,6 +29,10 @@ impl Colour {
         Self([r,g,b])
     }
 
+    pub const fn as_rgb(&self) -> [u8; 3] {
+        self.0
+    }
+
     // Creates an interoplated gradient between 2 colours, with [steps] entries
     // Returning entires size will always be steps + 2
     pub fn gradient(&self, other: &Colour, steps: usize) -> Vec<Colour> {
@@ -58,6 +62,7 @@ impl Colour {
     }
 }
 
+#[derive(Clone)]
 pub struct EffectLayer<const X: usize, const Y: usize> {
     internal_matrix: [[Colour; X]; Y], // This gets sent for updating!
 
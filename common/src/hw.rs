@@ -1,70 +1,77 @@
+use std::sync::Arc;
 
-// Keyboards
-const KEYBOARD_IDS: &[(u16, &'static str)] = &[
-    (0x0235, "Blackwidow Lite (2018)")
-];
-
-// Laptops
-const LAPTOP_IDS: &[(u16, &'static str)] = &[
-    // 15"
-    (0x0224, "Razer blade 15 2016"),
-    (0x0233, "Razer Blade 15 2018 (Adv)"),
-    (0x023A, "Razer blade 15 2019 (Adv)"),
-    (0x023B, "Razer blade 15 2018 (Base)"),
-    (0x0240, "Razer blade 15 2018 (Mercury edition)"),
-    (0x0245, "Razer blade 15 mid 2019 (Mercury edition)"),
-    (0x024B, "Razer blade 15 late 2019 (Adv)"),
-    (0x024D, "Razer blade 15 2019 (Studio edition)"),
-    (0x0253, "Razer blade 15 2020 (Adv)"),
-    (0x0255, "Razer blade 15 2020 (Base)"),
-
-    // Stealth
-    (0x022D, "Razer blade Stealth 2017 (Mid)"),
-    (0x0232, "Razer blade Stealth 2017 (End)"),
-    (0x0239, "Razer blade Stealth 2019"),
-    (0x024A, "Razer blade Stealth 2019 (GTX)"),
-    (0x0252, "Razer blade Stealth 2020"),
-
-    // Pro 17"
-    (0x0116, "Razer blade Pro 2015"),
-    (0x0210, "Razer blade Pro 2016"),
-    (0x0225, "Razer blade Pro 2017"),
-    (0x022F, "Razer blade Pro 2018 (FHD)"),
-    (0x0234, "Razer blade Pro 2019"),
-    (0x024C, "Razer blade Pro late 2019"),
-    (0x0256, "Razer blade Pro 2020 (FHD)"),
-
-    // Other
-    (0x020F, "Razer blade QHD"),
-];
-
-// Mice
-const MICE_IDS: &[(u16, &'static str)] = &[
-
-];
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+use serde::{Deserialize, Serialize};
+
+use crate::devicedb::{self, DeviceClass, DeviceSpec};
+
+/// Transaction ID most devices answer to
+pub const DEFAULT_TRANSACTION_ID: u8 = 0xFF;
+
+/// Which of a device's HID interfaces takes control reports.
+/// Fields left out of the device database take their value from [ControlInterface::DEFAULT]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlInterface {
+    /// USB interface number, if the model always uses the same one
+    pub interface: Option<i32>,
+    pub usage_page: u16,
+    pub usage: u16
+}
+
+impl ControlInterface {
+    /// Generic desktop / mouse. Most devices hang their feature reports off this collection
+    pub const DEFAULT: Self = Self { interface: None, usage_page: 0x01, usage: 0x02 };
+}
+
+impl Default for ControlInterface {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// What kind of device a product ID is, and its model name from the device database
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum DeviceType {
-    Laptop(u16, &'static str),
-    Keyboard(u16, &'static str),
-    Mouse(u16, &'static str),
+    Laptop(u16, Arc<str>),
+    Keyboard(u16, Arc<str>),
+    Mouse(u16, Arc<str>),
+    Mousepad(u16, Arc<str>),
+    Headset(u16, Arc<str>),
+    Dock(u16, Arc<str>),
+    Keypad(u16, Arc<str>),
+    MouseDock(u16, Arc<str>),
+    AddressableController(u16, Arc<str>),
     Unknown(u16)
 }
 
 impl DeviceType {
+    /// Looks the product ID up in the [active](devicedb::active) device database
     pub fn from_id(id: u16) -> Self {
-        if let Some(kbd) = KEYBOARD_IDS.iter().find(|x| x.0 == id) {
-            return Self::Keyboard(kbd.0, kbd.1)
+        match devicedb::active().get(id) {
+            Some(spec) => Self::from_spec(spec),
+            None => DeviceType::Unknown(id)
         }
+    }
 
-        if let Some(laptop) = LAPTOP_IDS.iter().find(|x| x.0 == id) {
-            return Self::Laptop(laptop.0, laptop.1)
+    /// The device type a database entry describes
+    pub fn from_spec(spec: &DeviceSpec) -> Self {
+        let (id, name) = (spec.product_id, Arc::from(spec.name.as_str()));
+        match spec.class {
+            DeviceClass::Laptop => Self::Laptop(id, name),
+            DeviceClass::Keyboard => Self::Keyboard(id, name),
+            DeviceClass::Mouse => Self::Mouse(id, name),
+            DeviceClass::Mousepad => Self::Mousepad(id, name),
+            DeviceClass::Headset => Self::Headset(id, name),
+            DeviceClass::Dock => Self::Dock(id, name),
+            DeviceClass::Keypad => Self::Keypad(id, name),
+            DeviceClass::MouseDock => Self::MouseDock(id, name),
+            DeviceClass::AddressableController => Self::AddressableController(id, name),
         }
+    }
 
-        if let Some(mouse) = MICE_IDS.iter().find(|x| x.0 == id) {
-            return Self::Mouse(mouse.0, mouse.1)
-        }
-        DeviceType::Unknown(id)
+    /// The device's database entry, None for unknown devices
+    pub fn spec(&self) -> Option<DeviceSpec> {
+        devicedb::active().get(self.get_id()).cloned()
     }
 
     pub fn is_laptop(&self) -> bool {
@@ -91,12 +98,87 @@ impl DeviceType {
         }
     }
 
-    pub fn get_name(&self) -> &'static str {
+    pub fn get_id(&self) -> u16 {
+        match self {
+            DeviceType::Laptop(id, _) => *id,
+            DeviceType::Keyboard(id, _) => *id,
+            DeviceType::Mouse(id, _) => *id,
+            DeviceType::Mousepad(id, _) => *id,
+            DeviceType::Headset(id, _) => *id,
+            DeviceType::Dock(id, _) => *id,
+            DeviceType::Keypad(id, _) => *id,
+            DeviceType::MouseDock(id, _) => *id,
+            DeviceType::AddressableController(id, _) => *id,
+            DeviceType::Unknown(id) => *id,
+        }
+    }
+
+    /// Transaction ID the device expects in every packet. Newer models ignore
+    /// anything sent with the wrong one
+    pub fn transaction_id(&self) -> u8 {
+        devicedb::active().get(self.get_id())
+            .map(|x| x.transaction_id)
+            .unwrap_or(DEFAULT_TRANSACTION_ID)
+    }
+
+    /// How to pick the interface the device takes control reports on
+    pub fn control_interface(&self) -> ControlInterface {
+        devicedb::active().get(self.get_id())
+            .map(|x| x.control_interface)
+            .unwrap_or(ControlInterface::DEFAULT)
+    }
+
+    pub fn get_name(&self) -> &str {
         match self {
             DeviceType::Laptop(_, s) => s,
             DeviceType::Keyboard(_, s) => s,
             DeviceType::Mouse(_, s) => s,
+            DeviceType::Mousepad(_, s) => s,
+            DeviceType::Headset(_, s) => s,
+            DeviceType::Dock(_, s) => s,
+            DeviceType::Keypad(_, s) => s,
+            DeviceType::MouseDock(_, s) => s,
+            DeviceType::AddressableController(_, s) => s,
             DeviceType::Unknown(_) => "UNKNOWN",
         }
     }
-} 
\ No newline at end of file
+} 
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8
+}
+
+impl std::fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}.{}", self.major, self.minor)
+    }
+}
+
+/// What a laptop's SMBIOS tables say it is. Empty strings for anything the tables do not have
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SmbiosInfo {
+    /// System product name (e.g. Blade 15 Advanced Model (Early 2021) - RZ09-0367)
+    pub product_name: String,
+    /// System SKU number (e.g. RZ09-0367BEA3)
+    pub sku: String,
+    /// Baseboard version
+    pub board_version: String,
+    /// System serial number. Laptops do not report one over USB, so this is theirs
+    pub serial: String
+}
+
+/// Identity of a device, as reported by the daemon
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub product_id: u16,
+    /// Model name from [DeviceType]
+    pub name: String,
+    pub serial: String,
+    pub firmware: FirmwareVersion,
+    /// USB interface the daemon talks to the device on, if known
+    pub interface: Option<i32>,
+    /// Path of the interface on the transport it was opened with (e.g. hidraw or usb path)
+    pub path: String
+}
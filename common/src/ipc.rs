@@ -0,0 +1,89 @@
+#[cfg(unix)]
+use std::{io::{BufRead, BufReader, Write}, os::unix::net::UnixStream};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{DeviceCapabilities, hw::DeviceInfo, lighting::LogoState, laptop::{BatteryHealth, BatteryStatus, FanMode, FanStatus, PowerMode, TempSensor}, lighting::LightingState};
+
+/// Socket the daemon listens on. Only unix builds have it, the messages themselves are portable
+pub const SOCKET_PATH: &str = "/tmp/razer-control-center.sock";
+
+/// Daemon assigned ID of a device, only valid for as long as the device stays connected
+pub type DeviceId = u32;
+
+/// Requests are sent to the daemon as one JSON object per line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DaemonRequest {
+    ListDevices,
+    GetCapabilities(DeviceId),
+    /// Applies lighting to a device and saves it, so it comes back when the device is replugged
+    SetLighting(DeviceId, LightingState),
+    /// What a laptop's lid logo is doing
+    GetLogo(DeviceId),
+    /// Sets a laptop's lid logo and saves it with the rest of its lighting
+    SetLogo(DeviceId, LogoState),
+    /// Current fan mode and speeds of a laptop
+    GetFans(DeviceId),
+    /// Sets a laptop's fans and saves the mode, so it comes back after suspend or a restart
+    SetFanMode(DeviceId, FanMode),
+    /// Every hwmon temperature sensor a fan curve can use
+    ListTempSensors,
+    /// The power mode a laptop is in
+    GetPowerMode(DeviceId),
+    /// Sets a laptop's power mode and saves it, so it comes back after suspend or a restart
+    SetPowerMode(DeviceId, PowerMode),
+    /// A laptop's batteries and battery health optimizer
+    GetBattery(DeviceId),
+    /// Sets a laptop's battery health optimizer and saves it, so it comes back after suspend or a restart
+    SetBatteryHealth(DeviceId, BatteryHealth),
+    /// Keeps the connection open and sends a [DaemonResponse::Event] down it whenever something changes
+    Subscribe,
+}
+
+/// Something that happened in the daemon, pushed to subscribed clients
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DaemonEvent {
+    DeviceAdded(DeviceId, DeviceInfo),
+    DeviceRemoved(DeviceId),
+}
+
+/// Every request gets exactly one response line back. Subscribed connections
+/// additionally get one [DaemonResponse::Event] line per event
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DaemonResponse {
+    Devices(Vec<(DeviceId, DeviceInfo)>),
+    Capabilities(DeviceCapabilities),
+    Logo(LogoState),
+    Fans(FanStatus),
+    /// Sensors with their current reading in degrees C
+    TempSensors(Vec<(TempSensor, f32)>),
+    PowerMode(PowerMode),
+    Battery(BatteryStatus),
+    Ok,
+    Subscribed,
+    Event(DaemonEvent),
+    Error(String),
+}
+
+/// Sends a single request to the daemon and waits for its response
+#[cfg(unix)]
+pub fn send_request(req: &DaemonRequest) -> std::io::Result<DaemonResponse> {
+    let mut stream = UnixStream::connect(SOCKET_PATH)?;
+    writeln!(stream, "{}", serde_json::to_string(req)?)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Subscribes to daemon events. The iterator ends when the daemon goes away
+#[cfg(unix)]
+pub fn subscribe() -> std::io::Result<impl Iterator<Item = DaemonEvent>> {
+    let mut stream = UnixStream::connect(SOCKET_PATH)?;
+    writeln!(stream, "{}", serde_json::to_string(&DaemonRequest::Subscribe)?)?;
+    Ok(BufReader::new(stream).lines()
+        .map_while(|line| line.ok())
+        .filter_map(|line| match serde_json::from_str(&line) {
+            Ok(DaemonResponse::Event(ev)) => Some(ev),
+            _ => None
+        }))
+}
@@ -0,0 +1,240 @@
+use serde::{Deserialize, Serialize};
+
+use crate::RazerLaptop;
+
+/// How a laptop's fans are driven
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FanMode {
+    /// The EC picks the speed
+    #[default]
+    Auto,
+    /// Every fan held at this RPM. Clamped to the model's range when applied
+    Manual(u32),
+    /// The daemon sets the speed from a temperature
+    Curve(FanCurve)
+}
+
+/// A hwmon temperature sensor
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TempSensor {
+    /// Name of the hwmon chip (its `name` file), e.g. coretemp, k10temp, amdgpu
+    pub chip: String,
+    /// Label of the input (e.g. "Package id 0") or its name (e.g. "temp1").
+    /// None for the chip's first input
+    #[serde(default)]
+    pub input: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CurvePoint {
+    /// Degrees C
+    pub temp: i32,
+    pub rpm: u32
+}
+
+/// Maps a temperature to fan speeds. Between points the speed is interpolated, below the
+/// first and above the last point it stays at that point's speed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FanCurve {
+    pub sensor: TempSensor,
+    /// Points for each fan zone, zone 1 first. A single list is used for every zone
+    pub zones: Vec<Vec<CurvePoint>>,
+    /// Degrees C the temperature has to drop before the fans slow down
+    #[serde(default)]
+    pub hysteresis: u32,
+    /// Most the speed may change per second, 0 for no limit
+    #[serde(default)]
+    pub max_rpm_per_sec: u32
+}
+
+/// Largest hysteresis a curve may have. Any more and the fans would barely ever slow down
+pub const MAX_CURVE_HYSTERESIS: u32 = 20;
+
+impl FanCurve {
+    /// Checks the curve makes sense for a laptop with `fan_zones` zones
+    pub fn validate(&self, fan_zones: u8) -> Result<(), String> {
+        if self.zones.len() != 1 && self.zones.len() != fan_zones as usize {
+            return Err(format!("curve has {} zones, the laptop has {}", self.zones.len(), fan_zones))
+        }
+        for (idx, points) in self.zones.iter().enumerate() {
+            if points.is_empty() {
+                return Err(format!("zone {} has no points", idx + 1))
+            }
+            if points.windows(2).any(|w| w[0].temp >= w[1].temp) {
+                return Err(format!("zone {} temperatures must go up from one point to the next", idx + 1))
+            }
+        }
+        if self.hysteresis > MAX_CURVE_HYSTERESIS {
+            return Err(format!("hysteresis is {}C, at most {}C is allowed", self.hysteresis, MAX_CURVE_HYSTERESIS))
+        }
+        Ok(())
+    }
+
+    /// Points for a fan zone (starting at 1)
+    pub fn points(&self, zone: u8) -> &[CurvePoint] {
+        let idx = if self.zones.len() == 1 { 0 } else { zone.saturating_sub(1) as usize };
+        self.zones.get(idx).map(|p| p.as_slice()).unwrap_or(&[])
+    }
+}
+
+/// What a laptop's fans are doing right now
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FanStatus {
+    pub mode: FanMode,
+    /// Current speed of each fan zone, zone 1 first
+    pub rpm: Vec<u32>
+}
+
+/// CPU boost level in [PowerMode::Custom], lowest first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CpuBoost {
+    Low,
+    #[default]
+    Medium,
+    High,
+    /// Only on models with an unlocked CPU
+    Boost
+}
+
+/// GPU boost level in [PowerMode::Custom], lowest first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GpuBoost {
+    Low,
+    #[default]
+    Medium,
+    High
+}
+
+/// Highest boost levels a model takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BoostLimits {
+    pub cpu: CpuBoost,
+    pub gpu: GpuBoost
+}
+
+impl Default for BoostLimits {
+    fn default() -> Self {
+        Self { cpu: CpuBoost::High, gpu: GpuBoost::High }
+    }
+}
+
+/// The Blade's power mode, which sets how much power the CPU and GPU may draw
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PowerMode {
+    #[default]
+    Balanced,
+    Gaming,
+    Creator,
+    /// CPU and GPU boost picked by the user
+    Custom { cpu: CpuBoost, gpu: GpuBoost }
+}
+
+impl PowerMode {
+    /// Checks the laptop has this mode, and for [PowerMode::Custom] takes the boost levels
+    pub fn validate(&self, laptop: &RazerLaptop) -> Result<(), String> {
+        match self {
+            PowerMode::Balanced => Ok(()),
+            PowerMode::Gaming if !laptop.has_gaming_mode => Err("this laptop has no gaming mode".into()),
+            PowerMode::Creator if !laptop.has_creator_mode => Err("this laptop has no creator mode".into()),
+            PowerMode::Gaming | PowerMode::Creator => Ok(()),
+            PowerMode::Custom { cpu, gpu } => {
+                let max = laptop.max_boost.ok_or_else(|| "this laptop has no custom mode".to_string())?;
+                if *cpu > max.cpu {
+                    return Err(format!("CPU boost {:?} is above this laptop's highest ({:?})", cpu, max.cpu))
+                }
+                if *gpu > max.gpu {
+                    return Err(format!("GPU boost {:?} is above this laptop's highest ({:?})", gpu, max.gpu))
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Lowest charge threshold the battery health optimizer takes, in %
+pub const MIN_CHARGE_THRESHOLD: u8 = 50;
+/// Highest charge threshold the battery health optimizer takes, in %
+pub const MAX_CHARGE_THRESHOLD: u8 = 80;
+/// The firmware only takes thresholds in steps of this many %
+pub const CHARGE_THRESHOLD_STEP: u8 = 5;
+
+/// The Blade's battery health optimizer. When enabled the EC stops charging at the threshold,
+/// for laptops that spend their life plugged in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatteryHealth {
+    pub enabled: bool,
+    /// Charge to stop at, in %
+    pub threshold: u8
+}
+
+impl BatteryHealth {
+    // is_multiple_of is newer than our MSRV
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn validate(&self) -> Result<(), String> {
+        if !(MIN_CHARGE_THRESHOLD..=MAX_CHARGE_THRESHOLD).contains(&self.threshold) || self.threshold % CHARGE_THRESHOLD_STEP != 0 {
+            return Err(format!("threshold is {}%, it must be {}-{}% in steps of {}%",
+                self.threshold, MIN_CHARGE_THRESHOLD, MAX_CHARGE_THRESHOLD, CHARGE_THRESHOLD_STEP))
+        }
+        Ok(())
+    }
+}
+
+/// What a battery is doing, as the kernel reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChargeState {
+    Charging,
+    Discharging,
+    /// Plugged in but held, e.g. at the battery health optimizer's threshold
+    NotCharging,
+    Full,
+    Unknown
+}
+
+/// A battery under /sys/class/power_supply
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatteryState {
+    /// e.g. BAT0
+    pub name: String,
+    /// Charge in %
+    pub capacity: Option<u8>,
+    pub state: ChargeState
+}
+
+/// Everything under /sys/class/power_supply we care about
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PowerSupply {
+    /// If the laptop is on AC. None if there is no mains supply to ask
+    pub ac_online: Option<bool>,
+    pub batteries: Vec<BatteryState>
+}
+
+/// Where the machine is drawing power from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PowerSource {
+    Ac,
+    Battery,
+    /// On battery, at or below the low battery threshold
+    LowBattery
+}
+
+/// A laptop's batteries and its battery health optimizer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatteryStatus {
+    /// None if the laptop has no battery health optimizer
+    pub health: Option<BatteryHealth>,
+    pub supply: PowerSupply
+}
+
+/// Laptop settings the user asked for. Anything left as None is left to the EC
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LaptopSettings {
+    #[serde(default)]
+    pub fan: Option<FanMode>,
+    #[serde(default)]
+    pub power: Option<PowerMode>,
+    #[serde(default)]
+    pub battery_health: Option<BatteryHealth>,
+}
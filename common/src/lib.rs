@@ -1,26 +1,43 @@
 pub mod keyboard;
 pub mod effects;
 pub mod hw;
+pub mod devicedb;
+pub mod ipc;
+pub mod config;
+pub mod lighting;
+pub mod laptop;
+
+use hw::FirmwareVersion;
+use laptop::BoostLimits;
+use serde::{Deserialize, Serialize};
 
 /// Struct's in this library get passed between  the daemon and CLI/GUI
 
 
-struct RazerLaptop {
-    has_logo_control: bool,
-    fan_zone_count: u8,
-    min_fan_rpm: u32,
-    max_fan_rpm: u32,
-    has_gaming_mode: bool,
-    has_creator_mode: bool,
-    keyboard: RazerKeyboard
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RazerLaptop {
+    pub has_logo_control: bool,
+    pub fan_zone_count: u8,
+    pub min_fan_rpm: u32,
+    pub max_fan_rpm: u32,
+    pub has_gaming_mode: bool,
+    pub has_creator_mode: bool,
+    /// Highest boost levels custom power mode takes, None if the laptop has no custom mode
+    pub max_boost: Option<BoostLimits>,
+    /// Has the battery health optimizer (charge threshold)
+    pub has_battery_health: bool,
+    pub keyboard: RazerKeyboard
 }
 
-struct RazerKeyboard {
-    matrix_type: RGBControl,
-
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RazerKeyboard {
+    pub matrix_type: RGBControl,
+    /// Backlight brightness can be read and changed
+    pub has_brightness: bool,
 }
 
-enum RGBControl {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RGBControl {
     // Device has 1 colour, and 1 zone
     OneColourOneZone,
     // Device has  multiple colours but only 1 zone
@@ -29,6 +46,87 @@ enum RGBControl {
     MultiColourMultiZone,
 }
 
-struct RazerCommonDevice {
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RazerMouse {
+    pub has_battery: bool,
+    pub has_dpi: bool,
+}
+
+/// A mousepad's edge lighting
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RazerMousepad {
+    /// LEDs along the strip, 1 for pads lit as a single zone
+    pub led_count: u16,
+    pub has_brightness: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RazerHeadset {
+    /// Lit zones (ear cups, ears...), 0 for headsets without lighting
+    pub led_count: u16,
+    pub has_battery: bool,
+}
+
+/// Docks and mouse docks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RazerDock {
+    /// LEDs around the dock, 1 for a single zone
+    pub led_count: u16,
+    pub has_brightness: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RazerKeypad {
+    pub rows: u8,
+    pub cols: u8,
+    pub keyboard: RazerKeyboard
+}
+
+/// Chroma addressable RGB controller, driving strips plugged into its channels
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RazerAddressableController {
+    pub channels: u8,
+    /// Most LEDs each channel can drive
+    pub leds_per_channel: u8,
+}
+
+/// Things every Razer device has (or does not have)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RazerCommonDevice {
+    pub product_id: u16,
+    pub firmware: FirmwareVersion,
+    pub has_polling_rate: bool,
+}
 
-}
\ No newline at end of file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RazerDeviceKind {
+    Laptop(RazerLaptop),
+    Keyboard(RazerKeyboard),
+    Mouse(RazerMouse),
+    Mousepad(RazerMousepad),
+    Headset(RazerHeadset),
+    Dock(RazerDock),
+    Keypad(RazerKeypad),
+    MouseDock(RazerDock),
+    AddressableController(RazerAddressableController),
+    Unknown
+}
+
+/// A command a device replied NotSupported to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct UnsupportedCmd {
+    pub class: u8,
+    pub cmd: u8,
+    /// LED, fan zone or boost target the command was for, if it takes one
+    pub target: Option<u8>,
+}
+
+/// Everything the daemon worked out a device can do
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceCapabilities {
+    pub common: RazerCommonDevice,
+    pub kind: RazerDeviceKind,
+    /// Commands the device has said it does not support, so clients can grey out their controls
+    #[serde(default)]
+    pub unsupported: Vec<UnsupportedCmd>,
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::laptop::PowerSource;
+
+/// Lighting the user asked for on a device. Anything left as None is not touched
+/// when the state is applied
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LightingState {
+    /// Backlight on or off
+    #[serde(default)]
+    pub on: Option<bool>,
+    /// Backlight brightness (0-255)
+    #[serde(default)]
+    pub brightness: Option<u8>,
+    /// Lid logo, on laptops that have logo control
+    #[serde(default)]
+    pub logo: Option<LogoState>,
+}
+
+impl LightingState {
+    /// Takes every field `other` sets, keeping ours for the ones it leaves as None
+    pub fn merge(&mut self, other: &LightingState) {
+        self.on = other.on.or(self.on);
+        self.brightness = other.brightness.or(self.brightness);
+        self.logo = other.logo.or(self.logo);
+    }
+}
+
+/// What a laptop's lid logo does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogoMode {
+    Off,
+    #[default]
+    Static,
+    Breathing
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogoState {
+    pub mode: LogoMode,
+    /// 0-255
+    pub brightness: u8
+}
+
+/// Most frames per second a [PowerProfile] may ask for
+pub const MAX_FPS: u8 = 60;
+
+/// What the daemon draws on devices that take custom frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FrameEffect {
+    /// Mirrors the screen
+    Capture,
+    /// One colour on every key. Costs next to nothing to draw
+    Static([u8; 3])
+}
+
+/// Lighting for one [PowerSource]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PowerProfile {
+    /// Backlight brightness (0-255). None keeps the brightness the user saved
+    #[serde(default)]
+    pub brightness: Option<u8>,
+    pub effect: FrameEffect,
+    /// Frames sent per second
+    pub fps: u8
+}
+
+impl PowerProfile {
+    /// Time between frames
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.fps.max(1) as u32
+    }
+}
+
+/// Lighting to use on AC, on battery and on low battery. Loaded from `power.json`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowerProfiles {
+    pub ac: PowerProfile,
+    pub battery: PowerProfile,
+    pub low_battery: PowerProfile,
+    /// Battery % at or below which `low_battery` is used, 0 to never use it
+    pub low_battery_threshold: u8
+}
+
+impl Default for PowerProfiles {
+    fn default() -> Self {
+        Self {
+            ac: PowerProfile { brightness: None, effect: FrameEffect::Capture, fps: 25 },
+            battery: PowerProfile { brightness: None, effect: FrameEffect::Capture, fps: 10 },
+            low_battery: PowerProfile { brightness: Some(32), effect: FrameEffect::Static([0xFF, 0xFF, 0xFF]), fps: 1 },
+            low_battery_threshold: 20
+        }
+    }
+}
+
+impl PowerProfiles {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, profile) in [("ac", &self.ac), ("battery", &self.battery), ("low_battery", &self.low_battery)] {
+            if profile.fps == 0 || profile.fps > MAX_FPS {
+                return Err(format!("{} fps is {}, it must be 1-{}", name, profile.fps, MAX_FPS))
+            }
+        }
+        if self.low_battery_threshold >= 100 {
+            return Err(format!("low_battery_threshold is {}%, it must be below 100%", self.low_battery_threshold))
+        }
+        Ok(())
+    }
+
+    pub fn profile(&self, source: PowerSource) -> PowerProfile {
+        match source {
+            PowerSource::Ac => self.ac,
+            PowerSource::Battery => self.battery,
+            PowerSource::LowBattery => self.low_battery
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_keeps_unset_fields() {
+        let logo = LogoState { mode: LogoMode::Breathing, brightness: 120 };
+        let mut saved = LightingState { on: Some(true), brightness: Some(200), logo: Some(logo) };
+        saved.merge(&LightingState { brightness: Some(40), ..Default::default() });
+        assert_eq!(saved, LightingState { on: Some(true), brightness: Some(40), logo: Some(logo) });
+
+        let off = LogoState { mode: LogoMode::Off, brightness: 0 };
+        saved.merge(&LightingState { on: Some(false), brightness: None, logo: Some(off) });
+        assert_eq!(saved, LightingState { on: Some(false), brightness: Some(40), logo: Some(off) });
+
+        saved.merge(&LightingState::default());
+        assert_eq!(saved, LightingState { on: Some(false), brightness: Some(40), logo: Some(off) });
+    }
+}
@@ -0,0 +1,143 @@
+//! Laptop batteries: the Blade's battery health optimizer, and the charge and AC
+//! state the kernel reports under [POWER_SUPPLY_ROOT].
+//!
+//! The root is passed in, so a temporary tree can stand in for sysfs
+
+use std::path::{Path, PathBuf};
+
+use common::{RazerLaptop, laptop::{BatteryHealth, BatteryState, ChargeState, PowerSupply}};
+
+use crate::{commands::{GetBatteryHealth, SetBatteryHealth}, device::RazerDevice, razer::{RazerError, RazerResult}};
+
+/// Where the kernel lists batteries and chargers
+pub const POWER_SUPPLY_ROOT: &str = "/sys/class/power_supply";
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+fn charge_state(status: Option<&str>) -> ChargeState {
+    match status {
+        Some("Charging") => ChargeState::Charging,
+        Some("Discharging") => ChargeState::Discharging,
+        Some("Not charging") => ChargeState::NotCharging,
+        Some("Full") => ChargeState::Full,
+        _ => ChargeState::Unknown
+    }
+}
+
+/// Batteries and mains supplies under `root` (normally [POWER_SUPPLY_ROOT]).
+/// Anything else there (USB-C ports, mouse batteries) is left out
+pub fn read_power_supply(root: &Path) -> PowerSupply {
+    let mut supplies: Vec<PathBuf> = match std::fs::read_dir(root) {
+        Ok(dir) => dir.flatten().map(|e| e.path()).collect(),
+        Err(_) => return PowerSupply::default()
+    };
+    supplies.sort();
+    let mut found = PowerSupply::default();
+    for dir in supplies {
+        match read_trimmed(&dir.join("type")).as_deref() {
+            Some("Mains") => {
+                // Some laptops list more than one, any of them being online counts
+                let online = read_trimmed(&dir.join("online")).as_deref() == Some("1");
+                found.ac_online = Some(found.ac_online.unwrap_or(false) || online);
+            },
+            // Wireless peripherals show up as batteries too, but with scope Device
+            Some("Battery") if read_trimmed(&dir.join("scope")).as_deref() != Some("Device") => {
+                found.batteries.push(BatteryState {
+                    name: dir.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+                    capacity: read_trimmed(&dir.join("capacity")).and_then(|c| c.parse().ok()),
+                    state: charge_state(read_trimmed(&dir.join("status")).as_deref())
+                });
+            },
+            _ => {}
+        }
+    }
+    found
+}
+
+pub fn read_battery_health(dev: &mut RazerDevice, laptop: &RazerLaptop) -> RazerResult<BatteryHealth> {
+    if !laptop.has_battery_health {
+        return Err(RazerError::CmdNotSupported)
+    }
+    dev.execute(&GetBatteryHealth)
+}
+
+/// Sets the battery health optimizer. Fails with [RazerError::InvalidConfig] if the threshold is out of range
+pub fn apply_battery_health(dev: &mut RazerDevice, laptop: &RazerLaptop, health: BatteryHealth) -> RazerResult<()> {
+    if !laptop.has_battery_health {
+        return Err(RazerError::CmdNotSupported)
+    }
+    health.validate().map_err(RazerError::InvalidConfig)?;
+    dev.execute(&SetBatteryHealth { health })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use common::laptop::ChargeState;
+
+    use crate::test_util::{TempDir, fake_device, test_laptop};
+
+    use super::*;
+
+    fn laptop(has_battery_health: bool) -> RazerLaptop {
+        RazerLaptop { has_battery_health, ..test_laptop() }
+    }
+
+    #[test]
+    fn validates_threshold() {
+        for threshold in [50, 55, 70, 80] {
+            assert!(BatteryHealth { enabled: true, threshold }.validate().is_ok(), "{}", threshold);
+        }
+        for threshold in [0, 45, 52, 79, 85, 100] {
+            assert!(BatteryHealth { enabled: true, threshold }.validate().is_err(), "{}", threshold);
+        }
+        assert_eq!(BatteryHealth { enabled: false, threshold: 62 }.validate().unwrap_err(), "threshold is 62%, it must be 50-80% in steps of 5%");
+    }
+
+    #[test]
+    fn bad_threshold_never_reaches_the_ec() {
+        let (mut dev, fake) = fake_device(0x026D);
+        let res = apply_battery_health(&mut dev, &laptop(true), BatteryHealth { enabled: true, threshold: 90 });
+        assert!(matches!(res, Err(RazerError::InvalidConfig(_))));
+        let res = apply_battery_health(&mut dev, &laptop(false), BatteryHealth { enabled: true, threshold: 60 });
+        assert!(matches!(res, Err(RazerError::CmdNotSupported)));
+        assert!(fake.sent().is_empty());
+
+        apply_battery_health(&mut dev, &laptop(true), BatteryHealth { enabled: true, threshold: 60 }).unwrap();
+        assert_eq!(fake.sent().len(), 1);
+    }
+
+    #[test]
+    fn reads_power_supply() {
+        let sysfs = TempDir::new("power-supply");
+        sysfs.write("AC0/type", "Mains\n");
+        sysfs.write("AC0/online", "0\n");
+        sysfs.write("ADP1/type", "Mains\n");
+        sysfs.write("ADP1/online", "1\n");
+        sysfs.write("BAT0/type", "Battery\n");
+        sysfs.write("BAT0/scope", "System\n");
+        sysfs.write("BAT0/capacity", "80\n");
+        sysfs.write("BAT0/status", "Not charging\n");
+        sysfs.write("BAT1/type", "Battery\n");
+        sysfs.write("BAT1/status", "Discharging\n");
+        // A wireless mouse, and a USB-C port
+        sysfs.write("hidpp_battery_0/type", "Battery\n");
+        sysfs.write("hidpp_battery_0/scope", "Device\n");
+        sysfs.write("hidpp_battery_0/capacity", "30\n");
+        sysfs.write("ucsi-source-psy-USBC000:001/type", "USB\n");
+        sysfs.write("ucsi-source-psy-USBC000:001/online", "1\n");
+
+        let supply = read_power_supply(sysfs.path());
+        assert_eq!(supply.ac_online, Some(true));
+        assert_eq!(supply.batteries, vec![
+            BatteryState { name: "BAT0".into(), capacity: Some(80), state: ChargeState::NotCharging },
+            BatteryState { name: "BAT1".into(), capacity: None, state: ChargeState::Discharging }
+        ]);
+
+        sysfs.write("ADP1/online", "0\n");
+        assert_eq!(read_power_supply(sysfs.path()).ac_online, Some(false));
+        // No power_supply class at all (desktops in containers and such)
+        assert_eq!(read_power_supply(&sysfs.path().join("missing")), PowerSupply::default());
+    }
+}
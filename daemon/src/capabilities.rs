@@ -0,0 +1,237 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use common::{DeviceCapabilities, RGBControl, RazerAddressableController, RazerCommonDevice, RazerDeviceKind, RazerDock, RazerHeadset, RazerKeyboard, RazerKeypad, RazerLaptop, RazerMouse, RazerMousepad, devicedb::{DeviceSpec, FanRpmRange, LedZone, PowerModeKind}, hw::{DeviceType, FirmwareVersion}};
+
+use crate::{chroma::{Led, LedStorage}, commands::{BoostTarget, GetBatteryHealth, GetBatteryLevel, GetBoost, GetDpi, GetFanRpm, GetLedBrightness, GetLedState, GetPollingRate, GetPowerMode, RazerCommand}, device::RazerDevice, razer::RazerError};
+
+// Fan range for laptops whose database entry does not give one
+const DEFAULT_FAN_RPM: FanRpmRange = FanRpmRange { min: 3500, max: 5000 };
+
+/// Runs read only commands against a device, keeping track of whether every answer was definite
+struct Prober<'a> {
+    dev: &'a mut RazerDevice,
+    /// Set once a probe fails some other way than NotSupported (Timeout, transport error...)
+    inconclusive: bool
+}
+
+impl Prober<'_> {
+    /// Runs a read only command to see if the device has a feature.
+    /// Only a NotSupported reply counts as a no, if the device fails any other way `default` is kept
+    fn probe<C: RazerCommand>(&mut self, cmd: &C, default: bool) -> bool {
+        match self.dev.execute(cmd) {
+            Ok(_) => true,
+            Err(RazerError::CmdNotSupported) => false,
+            Err(_) => {
+                self.inconclusive = true;
+                default
+            }
+        }
+    }
+}
+
+fn probe_keyboard(p: &mut Prober, matrix_type: RGBControl) -> RazerKeyboard {
+    RazerKeyboard {
+        matrix_type,
+        has_brightness: p.probe(&GetLedBrightness { storage: LedStorage::VarStore, led: Led::Backlight }, true)
+    }
+}
+
+fn probe_laptop(p: &mut Prober, spec: &DeviceSpec) -> RazerLaptop {
+    let fan_rpm = spec.fan_rpm.unwrap_or(DEFAULT_FAN_RPM);
+
+    let has_power_modes = p.probe(&GetPowerMode { zone: 1 }, true);
+    let mut fan_zone_count = 0;
+    if has_power_modes {
+        // Stop at the first zone the EC does not know about
+        for zone in 1..=spec.fan_zones {
+            if !p.probe(&GetFanRpm { zone }, true) {
+                break;
+            }
+            fan_zone_count = zone;
+        }
+    }
+
+    RazerLaptop {
+        has_logo_control: spec.has_led(LedZone::Logo) && p.probe(&GetLedState { storage: LedStorage::VarStore, led: Led::Logo }, true),
+        fan_zone_count,
+        min_fan_rpm: fan_rpm.min,
+        max_fan_rpm: fan_rpm.max,
+        has_gaming_mode: spec.has_mode(PowerModeKind::Gaming) && has_power_modes,
+        has_creator_mode: spec.has_mode(PowerModeKind::Creator) && has_power_modes,
+        max_boost: spec.max_boost.filter(|_| has_power_modes && p.probe(&GetBoost { target: BoostTarget::Cpu }, true)),
+        has_battery_health: spec.battery_health && p.probe(&GetBatteryHealth, true),
+        keyboard: probe_keyboard(p, spec.matrix_type)
+    }
+}
+
+/// LEDs in the device's matrix, 0 if it has none
+fn led_count(spec: &DeviceSpec) -> u16 {
+    spec.matrix.map(|m| u16::from(m.rows) * u16::from(m.cols)).unwrap_or(0)
+}
+
+fn probe_dock(p: &mut Prober, spec: &DeviceSpec) -> RazerDock {
+    RazerDock {
+        led_count: led_count(spec),
+        has_brightness: p.probe(&GetLedBrightness { storage: LedStorage::VarStore, led: Led::Backlight }, true)
+    }
+}
+
+/// Works out what a device can do from its device database entry, then confirms it with read only probes.
+/// The bool is false if any probe failed without a definite answer, and its feature was guessed at
+pub fn probe_capabilities(dev: &mut RazerDevice, firmware: FirmwareVersion) -> (DeviceCapabilities, bool) {
+    let id = dev.device_type.get_id();
+    let (device_type, spec) = (dev.device_type.clone(), dev.spec.clone());
+    let mut p = Prober { dev, inconclusive: false };
+    let kind = match (&device_type, spec) {
+        (DeviceType::Laptop(_, _), Some(spec)) => RazerDeviceKind::Laptop(probe_laptop(&mut p, &spec)),
+        (DeviceType::Keyboard(_, _), Some(spec)) => RazerDeviceKind::Keyboard(probe_keyboard(&mut p, spec.matrix_type)),
+        (DeviceType::Mouse(_, _), _) => RazerDeviceKind::Mouse(RazerMouse {
+            has_battery: p.probe(&GetBatteryLevel, false),
+            has_dpi: p.probe(&GetDpi { storage: LedStorage::NoStore }, true)
+        }),
+        (DeviceType::Mousepad(_, _), Some(spec)) => RazerDeviceKind::Mousepad(RazerMousepad {
+            led_count: led_count(&spec),
+            has_brightness: p.probe(&GetLedBrightness { storage: LedStorage::VarStore, led: Led::Backlight }, true)
+        }),
+        (DeviceType::Headset(_, _), Some(spec)) => RazerDeviceKind::Headset(RazerHeadset {
+            led_count: led_count(&spec),
+            has_battery: p.probe(&GetBatteryLevel, false)
+        }),
+        (DeviceType::Dock(_, _), Some(spec)) => RazerDeviceKind::Dock(probe_dock(&mut p, &spec)),
+        (DeviceType::MouseDock(_, _), Some(spec)) => RazerDeviceKind::MouseDock(probe_dock(&mut p, &spec)),
+        (DeviceType::Keypad(_, _), Some(spec)) => RazerDeviceKind::Keypad(RazerKeypad {
+            rows: spec.matrix.map(|m| m.rows).unwrap_or(0),
+            cols: spec.matrix.map(|m| m.cols).unwrap_or(0),
+            keyboard: probe_keyboard(&mut p, spec.matrix_type)
+        }),
+        // Every controller has a matrix, the database makes sure of it
+        (DeviceType::AddressableController(_, _), Some(spec)) => RazerDeviceKind::AddressableController(RazerAddressableController {
+            channels: spec.matrix.map(|m| m.rows).unwrap_or(0),
+            leds_per_channel: spec.matrix.map(|m| m.cols).unwrap_or(0)
+        }),
+        _ => RazerDeviceKind::Unknown
+    };
+    let common = RazerCommonDevice {
+        product_id: id,
+        firmware,
+        has_polling_rate: !device_type.is_laptop() && p.probe(&GetPollingRate, true)
+    };
+    (DeviceCapabilities { common, kind, unsupported: p.dev.unsupported_cmds() }, !p.inconclusive)
+}
+
+/// (Product ID, model name, firmware) capabilities are cached under
+type CacheKey = (u16, String, FirmwareVersion);
+
+/// Probing takes a handful of round trips per device, and the answer only changes
+/// with a firmware update, so results are kept per (product ID, model, firmware).
+/// The model is there for laptop variants sharing a product ID. Results with a guessed
+/// feature (a probe that timed out or hit a transport error) are not kept, so the next
+/// device to turn up gets probed again.
+///
+/// Clones share the same results, and nothing is locked while a device is being probed
+#[derive(Debug, Default, Clone)]
+pub struct CapabilityCache {
+    entries: Arc<Mutex<HashMap<CacheKey, DeviceCapabilities>>>
+}
+
+impl CapabilityCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Capabilities of `dev`, which is running `firmware`. Only probes if no device
+    /// of the same model and firmware has been probed yet
+    pub fn get_or_probe(&self, dev: &mut RazerDevice, firmware: FirmwareVersion) -> DeviceCapabilities {
+        let key = (dev.device_type.get_id(), dev.device_type.get_name().to_owned(), firmware);
+        if let Some(caps) = self.entries.lock().unwrap().get(&key) {
+            return caps.clone()
+        }
+        let (caps, conclusive) = probe_capabilities(dev, firmware);
+        if conclusive {
+            self.entries.lock().unwrap().insert(key, caps.clone());
+        }
+        caps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::devicedb::DeviceDb;
+
+    use crate::{razer::RazerCmdStatus, test_util::fake_device, transport::FakeResponse};
+
+    use super::*;
+
+    fn laptop(caps: &DeviceCapabilities) -> &RazerLaptop {
+        match &caps.kind {
+            RazerDeviceKind::Laptop(l) => l,
+            other => panic!("not a laptop: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn not_supported_marks_feature_absent() {
+        let (mut dev, fake) = fake_device(0x026D);
+        fake.respond_to(GetLedState::CLASS, GetLedState::ID, FakeResponse::Status(RazerCmdStatus::NotSupported));
+        fake.respond_to(GetBatteryHealth::CLASS, GetBatteryHealth::ID, FakeResponse::Status(RazerCmdStatus::NotSupported));
+        let (caps, conclusive) = probe_capabilities(&mut dev, FirmwareVersion { major: 1, minor: 2 });
+        assert!(conclusive);
+        let l = laptop(&caps);
+        assert!(!l.has_logo_control && !l.has_battery_health);
+        assert!(l.has_gaming_mode && l.has_creator_mode && l.max_boost.is_some() && l.keyboard.has_brightness);
+        assert_eq!(l.fan_zone_count, 2);
+        assert_eq!(caps.unsupported.len(), 2);
+    }
+
+    #[test]
+    fn fan_zones_stop_at_first_unsupported() {
+        let (mut dev, fake) = fake_device(0x026D);
+        fake.respond_to(GetFanRpm::CLASS, GetFanRpm::ID, FakeResponse::Status(RazerCmdStatus::NotSupported));
+        fake.push_response(FakeResponse::Echo);
+        fake.push_response(FakeResponse::Echo);
+        let (caps, _) = probe_capabilities(&mut dev, FirmwareVersion { major: 1, minor: 2 });
+        assert_eq!(laptop(&caps).fan_zone_count, 1);
+    }
+
+    #[test]
+    fn counts_leds_past_255() {
+        let db = DeviceDb::parse(r#"{ "devices": [
+            { "product_id": 1, "name": "Headset", "class": "headset", "matrix": { "rows": 4, "cols": 80 } },
+            { "product_id": 2, "name": "Dock", "class": "dock" }
+        ] }"#, "test.json").unwrap();
+        assert_eq!(led_count(db.get(1).unwrap()), 320);
+        assert_eq!(led_count(db.get(2).unwrap()), 0);
+    }
+
+    #[test]
+    fn cache_keeps_conclusive_probes() {
+        let cache = CapabilityCache::new();
+        let firmware = FirmwareVersion { major: 1, minor: 2 };
+        let (mut first, _) = fake_device(0x026D);
+        let caps = cache.get_or_probe(&mut first, firmware);
+        let (mut second, fake) = fake_device(0x026D);
+        assert_eq!(cache.clone().get_or_probe(&mut second, firmware), caps);
+        // Nothing was asked of the second one
+        assert!(fake.sent().is_empty());
+        // Other firmware gets probed
+        cache.get_or_probe(&mut second, FirmwareVersion { major: 1, minor: 3 });
+        assert!(!fake.sent().is_empty());
+    }
+
+    #[test]
+    fn cache_skips_inconclusive_probes() {
+        let cache = CapabilityCache::new();
+        let firmware = FirmwareVersion { major: 1, minor: 2 };
+        let (mut first, fake) = fake_device(0x026D);
+        fake.respond_to(GetBatteryHealth::CLASS, GetBatteryHealth::ID, FakeResponse::Status(RazerCmdStatus::Timeout));
+        let caps = cache.get_or_probe(&mut first, firmware);
+        // A timeout is not a no, so the feature is assumed present
+        assert!(laptop(&caps).has_battery_health);
+
+        let (mut second, fake) = fake_device(0x026D);
+        fake.respond_to(GetBatteryHealth::CLASS, GetBatteryHealth::ID, FakeResponse::Status(RazerCmdStatus::NotSupported));
+        let caps = cache.get_or_probe(&mut second, firmware);
+        assert!(!laptop(&caps).has_battery_health);
+        assert!(fake.sent().len() > 1);
+    }
+}
@@ -1,12 +1,19 @@
-use common::effects::{self, Colour, Effect, EffectLayer};
-use rusb::UsbContext;
+use std::convert::TryFrom;
 
-use crate::{device::RazerDevice, razer::RazerPacket};
+use common::{RGBControl, devicedb::{DeviceClass, DeviceSpec, MatrixSize}, effects::EffectLayer};
+
+use crate::{commands::{GetLedBrightness, GetLedEffect, GetLedRgb, GetLedState, SetCustomFrameRow, SetExtCustomFrameRow, SetLedBrightness, SetLedEffect, SetLedRgb, SetLedState, ShowCustomFrame, ShowExtCustomFrame}, device::RazerDevice, razer::{RazerError, RazerResult}};
+
+/// Most colours one frame row report can carry. Longer rows are sent in pieces
+pub const MAX_ROW_COLOURS: usize = 25;
 
 #[repr(u8)]
-enum LedStorage {
-    NoStore,
-    VarStore
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LedStorage {
+    /// Lost when the device is power cycled
+    NoStore = 0x00,
+    /// Saved on the device, comes back after a power cycle
+    VarStore = 0x01
 }
 
 #[repr(u8)]
@@ -29,48 +36,267 @@ pub enum Led {
     FullyCharged = 0x22
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct LedController{ 
-    led: Led
+/// Effects a single LED can run by itself
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LedEffect {
+    Static = 0x00,
+    Blinking = 0x01,
+    Breathing = 0x02,
+    Spectrum = 0x04
 }
-/*
+
+impl TryFrom<u8> for LedEffect {
+    type Error = RazerError;
+
+    fn try_from(x: u8) -> RazerResult<Self> {
+        match x {
+            0x00 => Ok(Self::Static),
+            0x01 => Ok(Self::Blinking),
+            0x02 => Ok(Self::Breathing),
+            0x04 => Ok(Self::Spectrum),
+            _ => Err(RazerError::InvalidResponse)
+        }
+    }
+}
+
+/// Reads and changes one LED of a device. Changes go to [LedStorage::VarStore]
+/// unless another storage is picked with [LedController::with_storage]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LedController {
+    led: Led,
+    storage: LedStorage
+}
+
 impl LedController {
     pub fn new(led: Led) -> Self {
-        Self { led }
+        Self { led, storage: LedStorage::VarStore }
     }
 
-    pub fn read_led<T: UsbContext>(&self, dev: &mut RazerDevice<T>,brightness: u8) -> u8 {
-        if let Ok(resp) = dev.write_and_read_cmd(RazerPacket::new(0x03, 0x83, &[LedStorage::VarStore as u8, self.led as u8, 0x00])){
-            return resp.args[2]
-        }
-        0
+    pub fn with_storage(self, storage: LedStorage) -> Self {
+        Self { storage, ..self }
+    }
+
+    pub fn led(&self) -> Led {
+        self.led
+    }
+
+    pub fn storage(&self) -> LedStorage {
+        self.storage
+    }
+
+    pub fn read_brightness(&self, dev: &mut RazerDevice) -> RazerResult<u8> {
+        dev.execute(&GetLedBrightness { storage: self.storage, led: self.led })
+    }
+
+    pub fn set_brightness(&self, dev: &mut RazerDevice, brightness: u8) -> RazerResult<()> {
+        dev.execute(&SetLedBrightness { storage: self.storage, led: self.led, brightness })
+    }
+
+    pub fn is_on(&self, dev: &mut RazerDevice) -> RazerResult<bool> {
+        dev.execute(&GetLedState { storage: self.storage, led: self.led })
+    }
+
+    pub fn set_on(&self, dev: &mut RazerDevice, on: bool) -> RazerResult<()> {
+        dev.execute(&SetLedState { storage: self.storage, led: self.led, on })
     }
 
-    pub fn set_led<T: UsbContext>(&self, dev: &mut RazerDevice<T>,brightness: u8) -> bool {
-        dev.write_and_read_cmd(RazerPacket::new(0x03, 0x03, &[LedStorage::VarStore as u8, self.led as u8, brightness])).is_ok()
+    pub fn read_colour(&self, dev: &mut RazerDevice) -> RazerResult<[u8; 3]> {
+        dev.execute(&GetLedRgb { storage: self.storage, led: self.led })
+    }
+
+    /// Sets the colour the LED's effect uses. Single colour LEDs ignore it
+    pub fn set_colour(&self, dev: &mut RazerDevice, rgb: [u8; 3]) -> RazerResult<()> {
+        dev.execute(&SetLedRgb { storage: self.storage, led: self.led, rgb })
+    }
+
+    pub fn read_effect(&self, dev: &mut RazerDevice) -> RazerResult<LedEffect> {
+        dev.execute(&GetLedEffect { storage: self.storage, led: self.led })
+    }
+
+    pub fn set_effect(&self, dev: &mut RazerDevice, effect: LedEffect) -> RazerResult<()> {
+        dev.execute(&SetLedEffect { storage: self.storage, led: self.led, effect })
     }
 }
-*/
 
 
-pub fn set_keyboard_effect<const X: usize, const Y: usize>(dev: &mut RazerDevice, layer: &EffectLayer<X, Y>) {
+/// Uploads a frame row, splitting it over as many reports as it needs
+fn upload_row(dev: &mut RazerDevice, row: u8, colours: &[[u8; 3]], extended: bool) -> RazerResult<()> {
+    for (idx, chunk) in colours.chunks(MAX_ROW_COLOURS).enumerate() {
+        let start_col = (idx * MAX_ROW_COLOURS) as u8;
+        if extended {
+            dev.execute_no_reply(&SetExtCustomFrameRow { row, start_col, colours: chunk.to_vec() })?;
+        } else {
+            dev.execute_no_reply(&SetCustomFrameRow { row, start_col, colours: chunk.to_vec() })?;
+        }
+    }
+    Ok(())
+}
+
+pub fn set_keyboard_effect<const X: usize, const Y: usize>(dev: &mut RazerDevice, layer: &EffectLayer<X, Y>) -> RazerResult<()> {
     // Assume effects have been executed, so we just have to build the final matrix and submit to the keyboard
-    let mut buffer: Vec<u8> = Vec::with_capacity(80); //vec![0xFF, 0x00, 0x00, X as u8, 0x00, 0x00, 0x00];
-    buffer.extend_from_slice(&[0xFF, 0x00, 0x00, X as u8, 0x00, 0x00, 0x00]);
     for (idx_row, row) in layer.matrix.iter().enumerate() {
-        buffer[1] = idx_row as u8;
-        for key in row.iter() {
-            buffer.extend_from_slice(
-                unsafe { ::std::slice::from_raw_parts((key as *const Colour) as *const u8, ::std::mem::size_of::<Colour>()) }
-            );
-        }
+        // Column 0 has no key, so it is always black
+        let mut colours = Vec::with_capacity(X + 1);
+        colours.push([0u8; 3]);
+        colours.extend(row.iter().map(|key| key.as_rgb()));
         // Dispatch row
-        let pkt = RazerPacket::new(0x03, 0x0b, &buffer);
-        buffer.truncate(7);
-        dev.write_cmd(pkt);
+        upload_row(dev, idx_row as u8, &colours, false)?;
     }
 
     // Now tell the keyboard to display the frame!
-    let pkt = RazerPacket::new(0x03, 0x0a, &[0x05u8, 0x00u8]);
-    dev.write_and_read_cmd(pkt);
-}
\ No newline at end of file
+    dev.execute(&ShowCustomFrame { storage: LedStorage::NoStore })
+}
+
+/// Squashes (or stretches) a layer to `size`. Each LED gets the average of the keys it covers,
+/// so a 1x1 matrix ends up with the average of the whole layer
+pub fn resample_layer<const X: usize, const Y: usize>(layer: &EffectLayer<X, Y>, size: MatrixSize) -> Vec<Vec<[u8; 3]>> {
+    // Key range [start, end) covering LED `idx` of `count`, at least 1 key wide
+    let span = |idx: usize, count: usize, keys: usize| {
+        let start = (idx * keys / count).min(keys - 1);
+        (start, ((idx + 1) * keys / count).max(start + 1))
+    };
+    (0..size.rows as usize).map(|r| {
+        let (y0, y1) = span(r, size.rows as usize, Y);
+        (0..size.cols as usize).map(|c| {
+            let (x0, x1) = span(c, size.cols as usize, X);
+            let mut sum = [0u32; 3];
+            for row in &layer.matrix[y0..y1] {
+                for key in &row[x0..x1] {
+                    let rgb = key.as_rgb();
+                    (0..3).for_each(|i| sum[i] += rgb[i] as u32);
+                }
+            }
+            let n = ((y1 - y0) * (x1 - x0)) as u32;
+            [(sum[0] / n) as u8, (sum[1] / n) as u8, (sum[2] / n) as u8]
+        }).collect()
+    }).collect()
+}
+
+/// Sends a layer to a device whose LEDs do not line up with the layer's keys
+/// (Strips, keypads, single zone accessories)
+pub fn set_matrix_effect<const X: usize, const Y: usize>(dev: &mut RazerDevice, layer: &EffectLayer<X, Y>, size: MatrixSize, extended: bool) -> RazerResult<()> {
+    for (idx_row, row) in resample_layer(layer, size).iter().enumerate() {
+        upload_row(dev, idx_row as u8, row, extended)?;
+    }
+    if extended {
+        dev.execute(&ShowExtCustomFrame { storage: LedStorage::NoStore })
+    } else {
+        dev.execute(&ShowCustomFrame { storage: LedStorage::NoStore })
+    }
+}
+
+/// How effect frames get to a device
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameTarget {
+    /// Per key keyboard (or laptop), the layer maps straight onto its keys
+    Keyboard,
+    /// Anything else with a matrix, the layer is resampled to fit
+    Matrix { size: MatrixSize, extended: bool }
+}
+
+impl FrameTarget {
+    /// None if the device cannot show custom frames
+    pub fn for_device(spec: &DeviceSpec) -> Option<Self> {
+        match spec.class {
+            DeviceClass::Laptop | DeviceClass::Keyboard => {
+                if spec.matrix_type == RGBControl::MultiColourMultiZone { Some(Self::Keyboard) } else { None }
+            },
+            // No mouse lighting support yet
+            DeviceClass::Mouse => None,
+            _ => spec.matrix.map(|size| Self::Matrix { size, extended: spec.extended_matrix })
+        }
+    }
+
+    pub fn send<const X: usize, const Y: usize>(&self, dev: &mut RazerDevice, layer: &EffectLayer<X, Y>) -> RazerResult<()> {
+        match *self {
+            Self::Keyboard => set_keyboard_effect(dev, layer),
+            Self::Matrix { size, extended } => set_matrix_effect(dev, layer, size, extended)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{devicedb::DeviceDb, effects::Colour};
+
+    use crate::{commands::RazerCommand, test_util::fake_device};
+
+    use super::*;
+
+    const DB: &str = r#"{ "devices": [
+        { "product_id": 1, "name": "Keyboard", "class": "keyboard", "matrix_type": "MultiColourMultiZone", "matrix": { "rows": 6, "cols": 22 } },
+        { "product_id": 2, "name": "Single colour keyboard", "class": "keyboard", "matrix_type": "OneColourOneZone" },
+        { "product_id": 3, "name": "Mouse", "class": "mouse", "matrix": { "rows": 1, "cols": 3 } },
+        { "product_id": 4, "name": "Firefly", "class": "mousepad", "matrix": { "rows": 1, "cols": 15 } },
+        { "product_id": 5, "name": "Mouse dock", "class": "mouse_dock", "matrix": { "rows": 1, "cols": 1 }, "extended_matrix": true },
+        { "product_id": 6, "name": "Headset", "class": "headset" }
+    ] }"#;
+
+    #[test]
+    fn frame_target_per_class() {
+        let db = DeviceDb::parse(DB, "test.json").unwrap();
+        let target = |id| FrameTarget::for_device(db.get(id).unwrap());
+        assert_eq!(target(1), Some(FrameTarget::Keyboard));
+        assert_eq!(target(2), None);
+        assert_eq!(target(3), None);
+        assert_eq!(target(4), Some(FrameTarget::Matrix { size: MatrixSize { rows: 1, cols: 15 }, extended: false }));
+        assert_eq!(target(5), Some(FrameTarget::Matrix { size: MatrixSize { rows: 1, cols: 1 }, extended: true }));
+        // Nothing to draw on
+        assert_eq!(target(6), None);
+    }
+
+    #[test]
+    fn resamples_by_averaging() {
+        // Left half red, right half blue, bottom row brighter
+        let mut layer = EffectLayer::create_blank([[true; 4]; 2]);
+        layer.matrix = [
+            [Colour::new_colour(100, 0, 0), Colour::new_colour(100, 0, 0), Colour::new_colour(0, 0, 100), Colour::new_colour(0, 0, 100)],
+            [Colour::new_colour(200, 0, 0), Colour::new_colour(200, 0, 0), Colour::new_colour(0, 0, 200), Colour::new_colour(0, 0, 200)]
+        ];
+        assert_eq!(resample_layer(&layer, MatrixSize { rows: 1, cols: 1 }), vec![vec![[75, 0, 75]]]);
+        assert_eq!(resample_layer(&layer, MatrixSize { rows: 1, cols: 2 }), vec![vec![[150, 0, 0], [0, 0, 150]]]);
+        assert_eq!(resample_layer(&layer, MatrixSize { rows: 2, cols: 4 }).concat(), layer.matrix.concat().iter().map(|c| c.as_rgb()).collect::<Vec<_>>());
+        // More LEDs than keys, each key covers several LEDs
+        let stretched = resample_layer(&layer, MatrixSize { rows: 4, cols: 8 });
+        assert_eq!(stretched.len(), 4);
+        assert_eq!(stretched[1][..4], [[100, 0, 0]; 4]);
+        assert_eq!(stretched[3][4..], [[0, 0, 200]; 4]);
+    }
+
+    /// (row, start, stop, first colour) of every frame row report sent with (class, id),
+    /// reading the row from `args[offset]` onwards
+    fn rows_sent(sent: &[Vec<u8>], class: u8, id: u8, offset: usize) -> Vec<(u8, u8, u8, u8)> {
+        sent.iter()
+            .filter(|raw| raw[7..9] == [class, id])
+            .map(|raw| {
+                let args = &raw[9 + offset..];
+                (args[0], args[1], args[2], args[3])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_long_rows() {
+        // Each LED's red is its column, so the first colour of a report says where it starts
+        let colours: Vec<[u8; 3]> = (0..60).map(|c| [c as u8, 0, 0]).collect();
+        let (mut dev, fake) = fake_device(0x0235);
+        upload_row(&mut dev, 3, &colours, false).unwrap();
+        assert_eq!(rows_sent(&fake.sent(), SetCustomFrameRow::CLASS, SetCustomFrameRow::ID, 1),
+            vec![(3, 0, 24, 0), (3, 25, 49, 25), (3, 50, 59, 50)]);
+        // 4 args before the colours, 3 bytes per colour
+        assert_eq!(fake.sent().iter().map(|raw| raw[6]).collect::<Vec<_>>(), vec![79, 79, 34]);
+
+        let (mut dev, fake) = fake_device(0x0235);
+        upload_row(&mut dev, 0, &colours[..30], true).unwrap();
+        assert_eq!(rows_sent(&fake.sent(), SetExtCustomFrameRow::CLASS, SetExtCustomFrameRow::ID, 2),
+            vec![(0, 0, 24, 0), (0, 25, 29, 25)]);
+        // The extended report has one more arg, and still fits 25 colours
+        assert_eq!(fake.sent().iter().map(|raw| raw[6]).collect::<Vec<_>>(), vec![80, 20]);
+
+        // Exactly one report's worth needs one report
+        let (mut dev, fake) = fake_device(0x0235);
+        upload_row(&mut dev, 1, &colours[..MAX_ROW_COLOURS], false).unwrap();
+        assert_eq!(rows_sent(&fake.sent(), SetCustomFrameRow::CLASS, SetCustomFrameRow::ID, 1), vec![(1, 0, 24, 0)]);
+    }
+}
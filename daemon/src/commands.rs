@@ -0,0 +1,927 @@
+//! Every Razer command we know about, one type per command.
+//!
+//! Each type knows which class/ID it is, how to build its args and how to
+//! pull its reply apart, so the byte layout of the protocol lives in one place.
+//! Run them with [RazerDevice::execute](crate::device::RazerDevice::execute)
+
+use std::{cmp::min, convert::TryFrom};
+
+use common::{hw::FirmwareVersion, laptop::BatteryHealth};
+
+use crate::{chroma::{Led, LedEffect, LedStorage}, razer::{RAZER_MAX_ARGS, RazerError, RazerPacket, RazerResult}, retry::RetryPolicy};
+
+pub trait RazerCommand {
+    /// What the command returns once parsed
+    type Response;
+    const CLASS: u8;
+    const ID: u8;
+    /// Retry policy for this command, if it needs something other than the device's
+    const RETRY_POLICY: Option<RetryPolicy> = None;
+
+    /// Args sent with the request. The length of this becomes the packet's data_size
+    fn args(&self) -> Vec<u8>;
+
+    /// Parses the device's reply
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<Self::Response>;
+
+    /// The LED, fan zone or boost target the command is for, if it takes one.
+    /// A device can support a command for one target but not another
+    fn target(&self) -> Option<u8> {
+        None
+    }
+
+    fn build(&self, transaction_id: u8) -> RazerPacket {
+        RazerPacket::new_with_id(transaction_id, Self::CLASS, Self::ID, &self.args())
+    }
+}
+
+/// Returns the first `len` args of a reply, or [RazerError::InvalidResponse] if the device sent less
+fn reply_args(resp: &RazerPacket, len: usize) -> RazerResult<&[u8]> {
+    if (resp.data_size as usize) < len {
+        return Err(RazerError::InvalidResponse)
+    }
+    Ok(&resp.args[0..len])
+}
+
+// --- Device info (Class 0x00) ---
+
+/// Reads the device's serial number
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetSerial;
+
+impl RazerCommand for GetSerial {
+    type Response = String;
+    const CLASS: u8 = 0x00;
+    const ID: u8 = 0x82;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0; 0x16]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<String> {
+        let size = min(resp.data_size as usize, RAZER_MAX_ARGS);
+        let raw = &resp.args[0..size];
+        // Serial is null terminated if it is shorter than the buffer
+        let end = raw.iter().position(|x| *x == 0).unwrap_or(raw.len());
+        String::from_utf8(raw[0..end].to_vec()).map_err(|_| RazerError::InvalidResponse)
+    }
+}
+
+/// Reads the device's firmware version
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetFirmware;
+
+impl RazerCommand for GetFirmware {
+    type Response = FirmwareVersion;
+    const CLASS: u8 = 0x00;
+    const ID: u8 = 0x81;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0; 2]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<FirmwareVersion> {
+        let args = reply_args(resp, 2)?;
+        Ok(FirmwareVersion { major: args[0], minor: args[1] })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PollingRate {
+    Hz1000 = 0x01,
+    Hz500 = 0x02,
+    Hz125 = 0x08
+}
+
+impl TryFrom<u8> for PollingRate {
+    type Error = RazerError;
+
+    fn try_from(x: u8) -> RazerResult<Self> {
+        match x {
+            0x01 => Ok(Self::Hz1000),
+            0x02 => Ok(Self::Hz500),
+            0x08 => Ok(Self::Hz125),
+            _ => Err(RazerError::InvalidResponse)
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetPollingRate;
+
+impl RazerCommand for GetPollingRate {
+    type Response = PollingRate;
+    const CLASS: u8 = 0x00;
+    const ID: u8 = 0x85;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<PollingRate> {
+        PollingRate::try_from(reply_args(resp, 1)?[0])
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SetPollingRate(pub PollingRate);
+
+impl RazerCommand for SetPollingRate {
+    type Response = ();
+    const CLASS: u8 = 0x00;
+    const ID: u8 = 0x05;
+
+    fn args(&self) -> Vec<u8> {
+        vec![self.0 as u8]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+}
+
+// --- LEDs (Class 0x03) ---
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SetLedBrightness {
+    pub storage: LedStorage,
+    pub led: Led,
+    pub brightness: u8
+}
+
+impl RazerCommand for SetLedBrightness {
+    type Response = ();
+    const CLASS: u8 = 0x03;
+    const ID: u8 = 0x03;
+
+    fn args(&self) -> Vec<u8> {
+        vec![self.storage as u8, self.led as u8, self.brightness]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.led as u8)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetLedBrightness {
+    pub storage: LedStorage,
+    pub led: Led
+}
+
+impl RazerCommand for GetLedBrightness {
+    type Response = u8;
+    const CLASS: u8 = 0x03;
+    const ID: u8 = 0x83;
+
+    fn args(&self) -> Vec<u8> {
+        vec![self.storage as u8, self.led as u8, 0x00]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<u8> {
+        Ok(reply_args(resp, 3)?[2])
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.led as u8)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SetLedState {
+    pub storage: LedStorage,
+    pub led: Led,
+    pub on: bool
+}
+
+impl RazerCommand for SetLedState {
+    type Response = ();
+    const CLASS: u8 = 0x03;
+    const ID: u8 = 0x00;
+
+    fn args(&self) -> Vec<u8> {
+        vec![self.storage as u8, self.led as u8, self.on as u8]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.led as u8)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetLedState {
+    pub storage: LedStorage,
+    pub led: Led
+}
+
+impl RazerCommand for GetLedState {
+    type Response = bool;
+    const CLASS: u8 = 0x03;
+    const ID: u8 = 0x80;
+
+    fn args(&self) -> Vec<u8> {
+        vec![self.storage as u8, self.led as u8, 0x00]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<bool> {
+        Ok(reply_args(resp, 3)?[2] != 0)
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.led as u8)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SetLedRgb {
+    pub storage: LedStorage,
+    pub led: Led,
+    pub rgb: [u8; 3]
+}
+
+impl RazerCommand for SetLedRgb {
+    type Response = ();
+    const CLASS: u8 = 0x03;
+    const ID: u8 = 0x01;
+
+    fn args(&self) -> Vec<u8> {
+        vec![self.storage as u8, self.led as u8, self.rgb[0], self.rgb[1], self.rgb[2]]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.led as u8)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetLedRgb {
+    pub storage: LedStorage,
+    pub led: Led
+}
+
+impl RazerCommand for GetLedRgb {
+    type Response = [u8; 3];
+    const CLASS: u8 = 0x03;
+    const ID: u8 = 0x81;
+
+    fn args(&self) -> Vec<u8> {
+        vec![self.storage as u8, self.led as u8, 0x00, 0x00, 0x00]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<[u8; 3]> {
+        let args = reply_args(resp, 5)?;
+        Ok([args[2], args[3], args[4]])
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.led as u8)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SetLedEffect {
+    pub storage: LedStorage,
+    pub led: Led,
+    pub effect: LedEffect
+}
+
+impl RazerCommand for SetLedEffect {
+    type Response = ();
+    const CLASS: u8 = 0x03;
+    const ID: u8 = 0x02;
+
+    fn args(&self) -> Vec<u8> {
+        vec![self.storage as u8, self.led as u8, self.effect as u8]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.led as u8)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetLedEffect {
+    pub storage: LedStorage,
+    pub led: Led
+}
+
+impl RazerCommand for GetLedEffect {
+    type Response = LedEffect;
+    const CLASS: u8 = 0x03;
+    const ID: u8 = 0x82;
+
+    fn args(&self) -> Vec<u8> {
+        vec![self.storage as u8, self.led as u8, 0x00]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<LedEffect> {
+        LedEffect::try_from(reply_args(resp, 3)?[2])
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.led as u8)
+    }
+}
+
+/// Uploads one row of a custom frame. Colours are RGB, starting at `start_col`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetCustomFrameRow {
+    pub row: u8,
+    pub start_col: u8,
+    pub colours: Vec<[u8; 3]>
+}
+
+impl RazerCommand for SetCustomFrameRow {
+    type Response = ();
+    const CLASS: u8 = 0x03;
+    const ID: u8 = 0x0B;
+    const RETRY_POLICY: Option<RetryPolicy> = Some(RetryPolicy::FAST);
+
+    fn args(&self) -> Vec<u8> {
+        let stop_col = self.start_col as usize + self.colours.len().saturating_sub(1);
+        let mut args = vec![0xFF, self.row, self.start_col, stop_col as u8];
+        self.colours.iter().for_each(|c| args.extend_from_slice(c));
+        args
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+}
+
+/// Tells the device to display the custom frame uploaded with [SetCustomFrameRow]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ShowCustomFrame {
+    pub storage: LedStorage
+}
+
+impl RazerCommand for ShowCustomFrame {
+    type Response = ();
+    const CLASS: u8 = 0x03;
+    const ID: u8 = 0x0A;
+    const RETRY_POLICY: Option<RetryPolicy> = Some(RetryPolicy::FAST);
+
+    fn args(&self) -> Vec<u8> {
+        vec![0x05, self.storage as u8]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+}
+
+// --- DPI (Class 0x04) ---
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Dpi {
+    pub x: u16,
+    pub y: u16
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetDpi {
+    pub storage: LedStorage
+}
+
+impl RazerCommand for GetDpi {
+    type Response = Dpi;
+    const CLASS: u8 = 0x04;
+    const ID: u8 = 0x85;
+
+    fn args(&self) -> Vec<u8> {
+        vec![self.storage as u8, 0, 0, 0, 0, 0, 0]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<Dpi> {
+        let args = reply_args(resp, 5)?;
+        Ok(Dpi {
+            x: u16::from_be_bytes([args[1], args[2]]),
+            y: u16::from_be_bytes([args[3], args[4]])
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SetDpi {
+    pub storage: LedStorage,
+    pub dpi: Dpi
+}
+
+impl RazerCommand for SetDpi {
+    type Response = ();
+    const CLASS: u8 = 0x04;
+    const ID: u8 = 0x05;
+
+    fn args(&self) -> Vec<u8> {
+        let x = self.dpi.x.to_be_bytes();
+        let y = self.dpi.y.to_be_bytes();
+        vec![self.storage as u8, x[0], x[1], y[0], y[1], 0, 0]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+}
+
+// --- Power (Class 0x07) ---
+
+/// Reads battery level of a wireless device, 0-255
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetBatteryLevel;
+
+impl RazerCommand for GetBatteryLevel {
+    type Response = u8;
+    const CLASS: u8 = 0x07;
+    const ID: u8 = 0x80;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0; 2]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<u8> {
+        Ok(reply_args(resp, 2)?[1])
+    }
+}
+
+/// Reads if a wireless device is charging
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetChargingStatus;
+
+impl RazerCommand for GetChargingStatus {
+    type Response = bool;
+    const CLASS: u8 = 0x07;
+    const ID: u8 = 0x84;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0; 2]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<bool> {
+        Ok(reply_args(resp, 2)?[1] != 0)
+    }
+}
+
+// Battery health optimizer: top bit is on/off, the rest the charge threshold in %
+const BATTERY_HEALTH_ON: u8 = 0x80;
+
+/// Reads a laptop's battery health optimizer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetBatteryHealth;
+
+impl RazerCommand for GetBatteryHealth {
+    type Response = BatteryHealth;
+    const CLASS: u8 = 0x07;
+    const ID: u8 = 0x92;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0x00]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<BatteryHealth> {
+        let x = reply_args(resp, 1)?[0];
+        Ok(BatteryHealth { enabled: x & BATTERY_HEALTH_ON != 0, threshold: x & !BATTERY_HEALTH_ON })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SetBatteryHealth {
+    pub health: BatteryHealth
+}
+
+impl RazerCommand for SetBatteryHealth {
+    type Response = ();
+    const CLASS: u8 = 0x07;
+    const ID: u8 = 0x12;
+
+    fn args(&self) -> Vec<u8> {
+        let on = if self.health.enabled { BATTERY_HEALTH_ON } else { 0 };
+        vec![on | (self.health.threshold & !BATTERY_HEALTH_ON)]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+}
+
+// --- Laptop EC (Class 0x0D) ---
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PowerMode {
+    Balanced = 0,
+    Gaming = 1,
+    Creator = 2,
+    Custom = 4
+}
+
+impl TryFrom<u8> for PowerMode {
+    type Error = RazerError;
+
+    fn try_from(x: u8) -> RazerResult<Self> {
+        match x {
+            0 => Ok(Self::Balanced),
+            1 => Ok(Self::Gaming),
+            2 => Ok(Self::Creator),
+            4 => Ok(Self::Custom),
+            _ => Err(RazerError::InvalidResponse)
+        }
+    }
+}
+
+/// Reads the current fan speed of a fan zone (1 or 2)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetFanRpm {
+    pub zone: u8
+}
+
+impl RazerCommand for GetFanRpm {
+    type Response = u32;
+    const CLASS: u8 = 0x0D;
+    const ID: u8 = 0x81;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0x00, self.zone, 0x00]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<u32> {
+        // EC works in units of 100 RPM
+        Ok(reply_args(resp, 3)?[2] as u32 * 100)
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.zone)
+    }
+}
+
+/// Sets a fan zone to a fixed speed. Only sticks when the power mode is set to manual fan control
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SetFanRpm {
+    pub zone: u8,
+    pub rpm: u32
+}
+
+impl RazerCommand for SetFanRpm {
+    type Response = ();
+    const CLASS: u8 = 0x0D;
+    const ID: u8 = 0x01;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0x00, self.zone, min(self.rpm / 100, 0xFF) as u8]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.zone)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PowerState {
+    pub mode: PowerMode,
+    /// True if the fans are under manual control
+    pub manual_fan: bool
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetPowerMode {
+    pub zone: u8
+}
+
+impl RazerCommand for GetPowerMode {
+    type Response = PowerState;
+    const CLASS: u8 = 0x0D;
+    const ID: u8 = 0x82;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0x00, self.zone, 0x00, 0x00]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<PowerState> {
+        let args = reply_args(resp, 4)?;
+        Ok(PowerState { mode: PowerMode::try_from(args[2])?, manual_fan: args[3] != 0 })
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.zone)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SetPowerMode {
+    pub zone: u8,
+    pub state: PowerState
+}
+
+impl RazerCommand for SetPowerMode {
+    type Response = ();
+    const CLASS: u8 = 0x0D;
+    const ID: u8 = 0x02;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0x00, self.zone, self.state.mode as u8, self.state.manual_fan as u8]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.zone)
+    }
+}
+
+/// What a boost level applies to. Boost only takes effect in [PowerMode::Custom]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BoostTarget {
+    Cpu = 1,
+    Gpu = 2
+}
+
+/// Reads the CPU or GPU boost level
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GetBoost {
+    pub target: BoostTarget
+}
+
+impl RazerCommand for GetBoost {
+    type Response = u8;
+    const CLASS: u8 = 0x0D;
+    const ID: u8 = 0x87;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0x00, self.target as u8, 0x00]
+    }
+
+    fn parse(&self, resp: &RazerPacket) -> RazerResult<u8> {
+        Ok(reply_args(resp, 3)?[2])
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.target as u8)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SetBoost {
+    pub target: BoostTarget,
+    pub level: u8
+}
+
+impl RazerCommand for SetBoost {
+    type Response = ();
+    const CLASS: u8 = 0x0D;
+    const ID: u8 = 0x07;
+
+    fn args(&self) -> Vec<u8> {
+        vec![0x00, self.target as u8, self.level]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+
+    fn target(&self) -> Option<u8> {
+        Some(self.target as u8)
+    }
+}
+
+// --- Extended matrix (Class 0x0F) ---
+// Newer accessories (Firefly V2, Tartarus V2, docks...) take frames through these
+// rather than the class 0x03 ones
+
+/// Extended version of [SetCustomFrameRow]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetExtCustomFrameRow {
+    pub row: u8,
+    pub start_col: u8,
+    pub colours: Vec<[u8; 3]>
+}
+
+impl RazerCommand for SetExtCustomFrameRow {
+    type Response = ();
+    const CLASS: u8 = 0x0F;
+    const ID: u8 = 0x03;
+    const RETRY_POLICY: Option<RetryPolicy> = Some(RetryPolicy::FAST);
+
+    fn args(&self) -> Vec<u8> {
+        let stop_col = self.start_col as usize + self.colours.len().saturating_sub(1);
+        let mut args = vec![0x00, 0x00, self.row, self.start_col, stop_col as u8];
+        self.colours.iter().for_each(|c| args.extend_from_slice(c));
+        args
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+}
+
+/// Extended version of [ShowCustomFrame]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ShowExtCustomFrame {
+    pub storage: LedStorage
+}
+
+impl RazerCommand for ShowExtCustomFrame {
+    type Response = ();
+    const CLASS: u8 = 0x0F;
+    const ID: u8 = 0x02;
+    const RETRY_POLICY: Option<RetryPolicy> = Some(RetryPolicy::FAST);
+
+    fn args(&self) -> Vec<u8> {
+        // Storage, LED (Zero is the whole matrix), effect 0x08 (custom frame)
+        vec![self.storage as u8, Led::Zero as u8, 0x08]
+    }
+
+    fn parse(&self, _resp: &RazerPacket) -> RazerResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks the request `cmd` goes out as, byte for byte from the class onwards
+    fn assert_request<C: RazerCommand>(cmd: &C, class: u8, id: u8, args: &[u8]) {
+        let raw = cmd.build(0x1F).encode();
+        assert_eq!(raw[2], 0x1F, "transaction id");
+        assert_eq!(raw[6] as usize, args.len(), "data_size");
+        assert_eq!(&raw[7..9], &[class, id], "class/cmd");
+        assert_eq!(&raw[9..9 + args.len()], args, "args");
+        assert!(raw[9 + args.len()..89].iter().all(|&b| b == 0), "padding");
+    }
+
+    fn reply<C: RazerCommand>(args: &[u8]) -> RazerPacket {
+        RazerPacket::new(C::CLASS, C::ID, args)
+    }
+
+    fn parse<C: RazerCommand>(cmd: &C, args: &[u8]) -> RazerResult<C::Response> {
+        cmd.parse(&reply::<C>(args))
+    }
+
+    fn assert_short<C: RazerCommand>(cmd: &C, len: usize) {
+        assert!(matches!(parse(cmd, &vec![0; len - 1]), Err(RazerError::InvalidResponse)));
+    }
+
+    #[test]
+    fn serial() {
+        assert_request(&GetSerial, 0x00, 0x82, &[0; 0x16]);
+        assert_eq!(parse(&GetSerial, b"PM1234567890\0\0\0\0").unwrap(), "PM1234567890");
+        assert_eq!(parse(&GetSerial, b"ABC").unwrap(), "ABC");
+        assert_eq!(parse(&GetSerial, &[]).unwrap(), "");
+        assert!(matches!(parse(&GetSerial, &[0xC3, 0x28]), Err(RazerError::InvalidResponse)));
+    }
+
+    #[test]
+    fn firmware() {
+        assert_request(&GetFirmware, 0x00, 0x81, &[0, 0]);
+        assert_eq!(parse(&GetFirmware, &[1, 7]).unwrap(), FirmwareVersion { major: 1, minor: 7 });
+        assert_short(&GetFirmware, 2);
+    }
+
+    #[test]
+    fn polling_rate() {
+        assert_request(&GetPollingRate, 0x00, 0x85, &[0]);
+        assert_request(&SetPollingRate(PollingRate::Hz500), 0x00, 0x05, &[0x02]);
+        assert_eq!(parse(&GetPollingRate, &[0x01]).unwrap(), PollingRate::Hz1000);
+        assert_eq!(parse(&GetPollingRate, &[0x08]).unwrap(), PollingRate::Hz125);
+        assert!(matches!(parse(&GetPollingRate, &[0x04]), Err(RazerError::InvalidResponse)));
+        assert_short(&GetPollingRate, 1);
+    }
+
+    #[test]
+    fn led_brightness() {
+        assert_request(&SetLedBrightness { storage: LedStorage::VarStore, led: Led::Backlight, brightness: 0x80 }, 0x03, 0x03, &[0x01, 0x05, 0x80]);
+        let get = GetLedBrightness { storage: LedStorage::NoStore, led: Led::Logo };
+        assert_request(&get, 0x03, 0x83, &[0x00, 0x04, 0x00]);
+        assert_eq!(parse(&get, &[0x00, 0x04, 0xC8]).unwrap(), 0xC8);
+        assert_short(&get, 3);
+    }
+
+    #[test]
+    fn led_state() {
+        assert_request(&SetLedState { storage: LedStorage::VarStore, led: Led::Logo, on: true }, 0x03, 0x00, &[0x01, 0x04, 0x01]);
+        assert_request(&SetLedState { storage: LedStorage::NoStore, led: Led::Logo, on: false }, 0x03, 0x00, &[0x00, 0x04, 0x00]);
+        let get = GetLedState { storage: LedStorage::VarStore, led: Led::Backlight };
+        assert_request(&get, 0x03, 0x80, &[0x01, 0x05, 0x00]);
+        assert!(parse(&get, &[0x01, 0x05, 0x01]).unwrap());
+        assert!(!parse(&get, &[0x01, 0x05, 0x00]).unwrap());
+        assert_short(&get, 3);
+    }
+
+    #[test]
+    fn led_rgb() {
+        assert_request(&SetLedRgb { storage: LedStorage::VarStore, led: Led::ScrollWheel, rgb: [0x10, 0x20, 0x30] }, 0x03, 0x01, &[0x01, 0x01, 0x10, 0x20, 0x30]);
+        let get = GetLedRgb { storage: LedStorage::VarStore, led: Led::ScrollWheel };
+        assert_request(&get, 0x03, 0x81, &[0x01, 0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(parse(&get, &[0x01, 0x01, 0xAA, 0xBB, 0xCC]).unwrap(), [0xAA, 0xBB, 0xCC]);
+        assert_short(&get, 5);
+    }
+
+    #[test]
+    fn led_effect() {
+        assert_request(&SetLedEffect { storage: LedStorage::VarStore, led: Led::Logo, effect: LedEffect::Breathing }, 0x03, 0x02, &[0x01, 0x04, 0x02]);
+        let get = GetLedEffect { storage: LedStorage::VarStore, led: Led::Logo };
+        assert_request(&get, 0x03, 0x82, &[0x01, 0x04, 0x00]);
+        assert_eq!(parse(&get, &[0x01, 0x04, 0x00]).unwrap(), LedEffect::Static);
+        assert_eq!(parse(&get, &[0x01, 0x04, 0x04]).unwrap(), LedEffect::Spectrum);
+        assert!(matches!(parse(&get, &[0x01, 0x04, 0x03]), Err(RazerError::InvalidResponse)));
+        assert_short(&get, 3);
+    }
+
+    #[test]
+    fn custom_frame() {
+        let row = SetCustomFrameRow { row: 2, start_col: 1, colours: vec![[1, 2, 3], [4, 5, 6]] };
+        assert_request(&row, 0x03, 0x0B, &[0xFF, 0x02, 0x01, 0x02, 1, 2, 3, 4, 5, 6]);
+        assert_request(&SetCustomFrameRow { row: 0, start_col: 0, colours: vec![] }, 0x03, 0x0B, &[0xFF, 0x00, 0x00, 0x00]);
+        assert_request(&ShowCustomFrame { storage: LedStorage::NoStore }, 0x03, 0x0A, &[0x05, 0x00]);
+        assert_eq!(SetCustomFrameRow::RETRY_POLICY, Some(RetryPolicy::FAST));
+    }
+
+    #[test]
+    fn ext_custom_frame() {
+        let row = SetExtCustomFrameRow { row: 5, start_col: 0, colours: vec![[9, 8, 7]; 3] };
+        assert_request(&row, 0x0F, 0x03, &[0x00, 0x00, 0x05, 0x00, 0x02, 9, 8, 7, 9, 8, 7, 9, 8, 7]);
+        assert_request(&ShowExtCustomFrame { storage: LedStorage::VarStore }, 0x0F, 0x02, &[0x01, 0x00, 0x08]);
+    }
+
+    #[test]
+    fn dpi() {
+        let get = GetDpi { storage: LedStorage::NoStore };
+        assert_request(&get, 0x04, 0x85, &[0x00, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(parse(&get, &[0x00, 0x06, 0x40, 0x0C, 0x80]).unwrap(), Dpi { x: 1600, y: 3200 });
+        assert_short(&get, 5);
+        assert_request(&SetDpi { storage: LedStorage::VarStore, dpi: Dpi { x: 800, y: 16000 } }, 0x04, 0x05, &[0x01, 0x03, 0x20, 0x3E, 0x80, 0, 0]);
+    }
+
+    #[test]
+    fn wireless_battery() {
+        assert_request(&GetBatteryLevel, 0x07, 0x80, &[0, 0]);
+        assert_eq!(parse(&GetBatteryLevel, &[0x00, 0xFF]).unwrap(), 0xFF);
+        assert_short(&GetBatteryLevel, 2);
+        assert_request(&GetChargingStatus, 0x07, 0x84, &[0, 0]);
+        assert!(parse(&GetChargingStatus, &[0x00, 0x01]).unwrap());
+        assert!(!parse(&GetChargingStatus, &[0x00, 0x00]).unwrap());
+        assert_short(&GetChargingStatus, 2);
+    }
+
+    #[test]
+    fn battery_health() {
+        assert_request(&GetBatteryHealth, 0x07, 0x92, &[0x00]);
+        assert_eq!(parse(&GetBatteryHealth, &[0xD0]).unwrap(), BatteryHealth { enabled: true, threshold: 80 });
+        assert_eq!(parse(&GetBatteryHealth, &[0x3C]).unwrap(), BatteryHealth { enabled: false, threshold: 60 });
+        assert_short(&GetBatteryHealth, 1);
+        assert_request(&SetBatteryHealth { health: BatteryHealth { enabled: true, threshold: 55 } }, 0x07, 0x12, &[0xB7]);
+        assert_request(&SetBatteryHealth { health: BatteryHealth { enabled: false, threshold: 80 } }, 0x07, 0x12, &[0x50]);
+    }
+
+    #[test]
+    fn fan_rpm() {
+        let get = GetFanRpm { zone: 2 };
+        assert_request(&get, 0x0D, 0x81, &[0x00, 0x02, 0x00]);
+        assert_eq!(parse(&get, &[0x00, 0x02, 0x2D]).unwrap(), 4500);
+        assert_short(&get, 3);
+        assert_request(&SetFanRpm { zone: 1, rpm: 3550 }, 0x0D, 0x01, &[0x00, 0x01, 0x23]);
+        // Clamped to what fits in a byte
+        assert_request(&SetFanRpm { zone: 1, rpm: 100_000 }, 0x0D, 0x01, &[0x00, 0x01, 0xFF]);
+    }
+
+    #[test]
+    fn power_mode() {
+        let get = GetPowerMode { zone: 1 };
+        assert_request(&get, 0x0D, 0x82, &[0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(parse(&get, &[0x00, 0x01, 0x04, 0x01]).unwrap(), PowerState { mode: PowerMode::Custom, manual_fan: true });
+        assert_eq!(parse(&get, &[0x00, 0x01, 0x01, 0x00]).unwrap(), PowerState { mode: PowerMode::Gaming, manual_fan: false });
+        assert!(matches!(parse(&get, &[0x00, 0x01, 0x03, 0x00]), Err(RazerError::InvalidResponse)));
+        assert_short(&get, 4);
+        let set = SetPowerMode { zone: 2, state: PowerState { mode: PowerMode::Creator, manual_fan: true } };
+        assert_request(&set, 0x0D, 0x02, &[0x00, 0x02, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn boost() {
+        let get = GetBoost { target: BoostTarget::Gpu };
+        assert_request(&get, 0x0D, 0x87, &[0x00, 0x02, 0x00]);
+        assert_eq!(parse(&get, &[0x00, 0x02, 0x01]).unwrap(), 1);
+        assert_short(&get, 3);
+        assert_request(&SetBoost { target: BoostTarget::Cpu, level: 3 }, 0x0D, 0x07, &[0x00, 0x01, 0x03]);
+    }
+}
@@ -0,0 +1,423 @@
+//! Temperature driven fan curves.
+//!
+//! [CurveEngine] turns temperatures into fan speeds (hysteresis, ramp limits) and knows
+//! nothing about where they come from. [CurveController] feeds it from hwmon, and
+//! [CurveTask] runs a controller against a laptop in the background. The hwmon root and
+//! clock are both passed in, so a fake hwmon tree and a [FakeClock](crate::retry::FakeClock)
+//! can stand in for the real ones
+
+use std::{path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicBool, Ordering}}, time::{Duration, Instant}};
+
+use common::{RazerLaptop, laptop::{CurvePoint, FanCurve, FanMode, TempSensor}};
+
+use crate::{fan::{apply_fan_mode, clamp_rpm, set_fan_rpm, set_manual_fan}, retry::Clock, worker::WorkerHandle};
+
+/// Where the kernel lists hwmon chips
+pub const HWMON_ROOT: &str = "/sys/class/hwmon";
+
+/// How often a [CurveTask] reads the sensor and updates the fans
+pub const CURVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A temperature input found under the hwmon root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HwmonInput {
+    pub chip: String,
+    /// Input name, e.g. temp1
+    pub input: String,
+    /// The input's label, if the driver gives one
+    pub label: Option<String>,
+    /// The tempN_input file
+    pub path: PathBuf
+}
+
+impl HwmonInput {
+    /// The [TempSensor] picking this input, by label if it has one
+    pub fn sensor(&self) -> TempSensor {
+        TempSensor { chip: self.chip.clone(), input: Some(self.label.clone().unwrap_or_else(|| self.input.clone())) }
+    }
+
+    fn matches(&self, sensor: &TempSensor) -> bool {
+        self.chip == sensor.chip && match &sensor.input {
+            Some(input) => *input == self.input || Some(input) == self.label.as_ref(),
+            None => true
+        }
+    }
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+/// Every temperature input under `root` (normally [HWMON_ROOT]), ordered by chip then input
+pub fn find_temp_inputs(root: &Path) -> Vec<HwmonInput> {
+    let mut chips: Vec<PathBuf> = match std::fs::read_dir(root) {
+        Ok(dir) => dir.flatten().map(|e| e.path()).collect(),
+        Err(_) => return Vec::new()
+    };
+    chips.sort();
+    let mut found = Vec::new();
+    for chip_dir in chips {
+        let chip = match read_trimmed(&chip_dir.join("name")) {
+            Some(c) => c,
+            None => continue
+        };
+        let mut inputs: Vec<(u32, String)> = match std::fs::read_dir(&chip_dir) {
+            Ok(dir) => dir.flatten()
+                .filter_map(|e| e.file_name().to_str().map(|s| s.to_string()))
+                .filter_map(|name| {
+                    let input = name.strip_suffix("_input")?;
+                    Some((input.strip_prefix("temp")?.parse().ok()?, input.to_string()))
+                })
+                .collect(),
+            Err(_) => continue
+        };
+        // temp10 goes after temp9
+        inputs.sort();
+        for (_, input) in inputs {
+            found.push(HwmonInput {
+                label: read_trimmed(&chip_dir.join(format!("{}_label", input))),
+                path: chip_dir.join(format!("{}_input", input)),
+                chip: chip.clone(),
+                input
+            });
+        }
+    }
+    found
+}
+
+/// Finds the input a [TempSensor] picks
+pub fn find_sensor(root: &Path, sensor: &TempSensor) -> Option<HwmonInput> {
+    find_temp_inputs(root).into_iter().find(|i| i.matches(sensor))
+}
+
+/// Reads an input in degrees C
+pub fn read_temp(input: &HwmonInput) -> Option<f32> {
+    // Millidegrees
+    read_trimmed(&input.path)?.parse::<i64>().ok().map(|x| x as f32 / 1000.0)
+}
+
+/// Speed for `temp` on a curve, interpolating between points
+pub fn interpolate(points: &[CurvePoint], temp: f32) -> u32 {
+    let (first, last) = match (points.first(), points.last()) {
+        (Some(f), Some(l)) => (f, l),
+        _ => return 0
+    };
+    if temp <= first.temp as f32 {
+        return first.rpm
+    }
+    if temp >= last.temp as f32 {
+        return last.rpm
+    }
+    for w in points.windows(2) {
+        let (a, b) = (w[0], w[1]);
+        if temp <= b.temp as f32 {
+            let t = (temp - a.temp as f32) / (b.temp - a.temp) as f32;
+            return (a.rpm as f32 + (b.rpm as f32 - a.rpm as f32) * t).round() as u32
+        }
+    }
+    last.rpm
+}
+
+/// Works out fan speeds from temperatures, one step at a time
+#[derive(Debug, Clone)]
+pub struct CurveEngine {
+    curve: FanCurve,
+    laptop: RazerLaptop,
+    /// Temperature the curve is read at. Follows the sensor up straight away,
+    /// but only comes down once the sensor is `hysteresis` below it
+    effective_temp: Option<f32>,
+    /// Speed each zone was last set to
+    rpm: Vec<f32>,
+    last_step: Option<Instant>
+}
+
+impl CurveEngine {
+    pub fn new(curve: FanCurve, laptop: RazerLaptop) -> Self {
+        Self { curve, laptop, effective_temp: None, rpm: Vec::new(), last_step: None }
+    }
+
+    pub fn curve(&self) -> &FanCurve {
+        &self.curve
+    }
+
+    /// Forgets all history, so the next step goes straight to the curve's speed
+    pub fn reset(&mut self) {
+        self.effective_temp = None;
+        self.rpm.clear();
+        self.last_step = None;
+    }
+
+    /// Speeds for each zone at `temp`, as of `now`
+    pub fn step(&mut self, temp: f32, now: Instant) -> Vec<u32> {
+        let hysteresis = self.curve.hysteresis as f32;
+        let effective = match self.effective_temp {
+            Some(prev) if temp < prev => prev.min(temp + hysteresis),
+            _ => temp
+        };
+        self.effective_temp = Some(effective);
+
+        let targets: Vec<f32> = (1..=self.laptop.fan_zone_count)
+            .map(|zone| clamp_rpm(&self.laptop, interpolate(self.curve.points(zone), effective)) as f32)
+            .collect();
+        let max_change = match (self.last_step, self.curve.max_rpm_per_sec) {
+            (Some(last), limit) if limit != 0 => Some(now.saturating_duration_since(last).as_secs_f32() * limit as f32),
+            _ => None
+        };
+        self.rpm = if self.rpm.len() == targets.len() {
+            self.rpm.iter().zip(&targets).map(|(cur, target)| match max_change {
+                Some(max) => cur + (target - cur).clamp(-max, max),
+                None => *target
+            }).collect()
+        } else {
+            targets
+        };
+        self.last_step = Some(now);
+        self.rpm.iter().map(|x| x.round() as u32).collect()
+    }
+}
+
+/// What the fans should do after a [CurveController::tick]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CurveAction {
+    /// Set each zone to this speed, zone 1 first
+    SetRpm(Vec<u32>),
+    /// The sensor is back after a [CurveAction::FallBackToAuto]. Take the fans off auto, then set these speeds
+    TakeOver(Vec<u32>),
+    /// The sensor is gone, hand the fans back to the EC
+    FallBackToAuto,
+    /// Fans are already on auto and the sensor is still missing
+    Wait
+}
+
+/// Reads the curve's sensor and steps its engine
+pub struct CurveController {
+    engine: CurveEngine,
+    hwmon_root: PathBuf,
+    clock: Arc<dyn Clock>,
+    /// The sensor's input, once found. hwmon numbering can change between boots, so it is looked up by name
+    input: Option<HwmonInput>,
+    on_auto: bool
+}
+
+impl CurveController {
+    pub fn new<P: Into<PathBuf>>(curve: FanCurve, laptop: RazerLaptop, hwmon_root: P, clock: Arc<dyn Clock>) -> Self {
+        Self { engine: CurveEngine::new(curve, laptop), hwmon_root: hwmon_root.into(), clock, input: None, on_auto: false }
+    }
+
+    fn read(&mut self) -> Option<f32> {
+        if let Some(temp) = self.input.as_ref().and_then(read_temp) {
+            return Some(temp)
+        }
+        // Not found yet, or it went away (driver unloaded, renumbered)
+        self.input = find_sensor(&self.hwmon_root, &self.engine.curve().sensor);
+        self.input.as_ref().and_then(read_temp)
+    }
+
+    pub fn tick(&mut self) -> CurveAction {
+        match self.read() {
+            Some(temp) if self.on_auto => {
+                // The fans are wherever the EC left them, start the curve afresh
+                self.engine.reset();
+                self.on_auto = false;
+                CurveAction::TakeOver(self.engine.step(temp, self.clock.now()))
+            },
+            Some(temp) => CurveAction::SetRpm(self.engine.step(temp, self.clock.now())),
+            None if self.on_auto => CurveAction::Wait,
+            None => {
+                self.on_auto = true;
+                CurveAction::FallBackToAuto
+            }
+        }
+    }
+}
+
+/// Runs a [CurveController] against a laptop on a background thread. Dropping it stops the curve
+pub struct CurveTask {
+    stop: Arc<AtomicBool>
+}
+
+impl CurveTask {
+    pub fn spawn(mut controller: CurveController, laptop: RazerLaptop, worker: WorkerHandle, name: String) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let task_stop = stop.clone();
+        let clock = controller.clock.clone();
+        std::thread::spawn(move || loop {
+            if task_stop.load(Ordering::SeqCst) {
+                break
+            }
+            let action = controller.tick();
+            let laptop = laptop.clone();
+            let job_stop = task_stop.clone();
+            // Checked again on the worker, in case a new fan mode was queued in the meantime
+            let res = worker.call(move |dev| match action {
+                _ if job_stop.load(Ordering::SeqCst) => Ok(()),
+                CurveAction::SetRpm(rpm) => set_fan_rpm(dev, &laptop, &rpm),
+                CurveAction::TakeOver(rpm) => set_manual_fan(dev, &laptop, true).and_then(|_| set_fan_rpm(dev, &laptop, &rpm)),
+                CurveAction::FallBackToAuto => {
+                    eprintln!("Fan curve sensor is gone, handing the fans back to the EC");
+                    apply_fan_mode(dev, &laptop, FanMode::Auto).map(|_| ())
+                },
+                CurveAction::Wait => Ok(())
+            });
+            match res {
+                Ok(Err(e)) => eprintln!("Error updating fans on {}! {:?}", name, e),
+                Err(_) => break, // Device went away
+                Ok(Ok(_)) => {}
+            }
+            clock.sleep(CURVE_INTERVAL);
+        });
+        Self { stop }
+    }
+}
+
+impl Drop for CurveTask {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::{retry::FakeClock, test_util::{TempDir, test_laptop}};
+
+    use super::*;
+
+    fn point(temp: i32, rpm: u32) -> CurvePoint {
+        CurvePoint { temp, rpm }
+    }
+
+    fn curve(hysteresis: u32, max_rpm_per_sec: u32) -> FanCurve {
+        FanCurve {
+            sensor: TempSensor { chip: "coretemp".into(), input: Some("Package id 0".into()) },
+            zones: vec![vec![point(40, 3000), point(60, 4000), point(80, 6000)]],
+            hysteresis,
+            max_rpm_per_sec
+        }
+    }
+
+    /// hwmon with an ACPI zone first and the CPU package sensor second, at `temp` degrees C
+    fn fake_hwmon(name: &str, temp: f32) -> TempDir {
+        let hwmon = TempDir::new(name);
+        hwmon.write("hwmon0/name", "acpitz\n");
+        hwmon.write("hwmon0/temp1_input", "27800\n");
+        hwmon.write("hwmon1/name", "coretemp\n");
+        hwmon.write("hwmon1/temp1_label", "Package id 0\n");
+        set_temp(&hwmon, temp);
+        hwmon.write("hwmon1/temp2_label", "Core 0\n");
+        hwmon.write("hwmon1/temp2_input", "41000\n");
+        hwmon
+    }
+
+    fn set_temp(hwmon: &TempDir, temp: f32) {
+        hwmon.write("hwmon1/temp1_input", format!("{}\n", (temp * 1000.0) as i64));
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let points = curve(0, 0).zones.remove(0);
+        assert_eq!(interpolate(&points, 20.0), 3000);
+        assert_eq!(interpolate(&points, 40.0), 3000);
+        assert_eq!(interpolate(&points, 45.0), 3250);
+        assert_eq!(interpolate(&points, 60.0), 4000);
+        assert_eq!(interpolate(&points, 70.5), 5050);
+        assert_eq!(interpolate(&points, 95.0), 6000);
+        assert_eq!(interpolate(&[point(50, 4200)], 10.0), 4200);
+        assert_eq!(interpolate(&[], 50.0), 0);
+    }
+
+    #[test]
+    fn finds_inputs_by_label_or_name() {
+        let hwmon = fake_hwmon("curve-inputs", 55.0);
+        hwmon.write("hwmon1/temp10_input", "50000\n");
+        let inputs = find_temp_inputs(hwmon.path());
+        let names: Vec<(&str, &str)> = inputs.iter().map(|i| (i.chip.as_str(), i.input.as_str())).collect();
+        assert_eq!(names, vec![("acpitz", "temp1"), ("coretemp", "temp1"), ("coretemp", "temp2"), ("coretemp", "temp10")]);
+        assert_eq!(read_temp(&inputs[1]), Some(55.0));
+        assert_eq!(inputs[0].sensor(), TempSensor { chip: "acpitz".into(), input: Some("temp1".into()) });
+
+        let by_label = find_sensor(hwmon.path(), &curve(0, 0).sensor).unwrap();
+        assert_eq!(by_label.input, "temp1");
+        let by_name = find_sensor(hwmon.path(), &TempSensor { chip: "coretemp".into(), input: Some("temp2".into()) }).unwrap();
+        assert_eq!(by_name.label.as_deref(), Some("Core 0"));
+        let first = find_sensor(hwmon.path(), &TempSensor { chip: "coretemp".into(), input: None }).unwrap();
+        assert_eq!(first.input, "temp1");
+        assert!(find_sensor(hwmon.path(), &TempSensor { chip: "k10temp".into(), input: None }).is_none());
+    }
+
+    #[test]
+    fn hysteresis_holds_speed_while_cooling() {
+        let clock = FakeClock::new();
+        let mut engine = CurveEngine::new(curve(5, 0), test_laptop());
+        assert_eq!(engine.step(65.0, clock.now()), vec![4500, 4500]);
+        // Within 5C of 65, so nothing changes
+        assert_eq!(engine.step(63.0, clock.now()), vec![4500, 4500]);
+        // Slows down as far as 5C above the sensor
+        assert_eq!(engine.step(58.0, clock.now()), vec![4300, 4300]);
+        assert_eq!(engine.step(61.0, clock.now()), vec![4300, 4300]);
+        // Warming up is followed straight away
+        assert_eq!(engine.step(66.0, clock.now()), vec![4600, 4600]);
+        // Clamped to the laptop's range
+        assert_eq!(engine.step(20.0, clock.now()), vec![3500, 3500]);
+        // Forgetting the history drops the hysteresis too
+        assert_eq!(engine.step(66.0, clock.now()), vec![4600, 4600]);
+        engine.reset();
+        assert_eq!(engine.step(62.0, clock.now()), vec![4200, 4200]);
+    }
+
+    #[test]
+    fn ramp_limits_speed_changes() {
+        let clock = FakeClock::new();
+        let mut engine = CurveEngine::new(curve(5, 100), RazerLaptop { fan_zone_count: 1, ..test_laptop() });
+        assert_eq!(engine.step(45.0, clock.now()), vec![3500]);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(engine.step(70.0, clock.now()), vec![3700]);
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(engine.step(70.0, clock.now()), vec![4500]);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(engine.step(67.0, clock.now()), vec![4600]);
+        // Hysteresis and the ramp limit together on the way down
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(engine.step(60.0, clock.now()), vec![4500]);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(engine.step(50.0, clock.now()), vec![4400]);
+        // No time passed, no change allowed
+        assert_eq!(engine.step(50.0, clock.now()), vec![4400]);
+    }
+
+    #[test]
+    fn controller_follows_the_sensor() {
+        let hwmon = fake_hwmon("curve-controller", 70.0);
+        let clock = FakeClock::new();
+        let mut controller = CurveController::new(curve(5, 0), test_laptop(), hwmon.path(), Arc::new(clock.clone()));
+        assert_eq!(controller.tick(), CurveAction::SetRpm(vec![5000, 5000]));
+        set_temp(&hwmon, 67.0);
+        clock.advance(CURVE_INTERVAL);
+        assert_eq!(controller.tick(), CurveAction::SetRpm(vec![5000, 5000]));
+        set_temp(&hwmon, 45.0);
+        clock.advance(CURVE_INTERVAL);
+        assert_eq!(controller.tick(), CurveAction::SetRpm(vec![3500, 3500]));
+    }
+
+    #[test]
+    fn controller_falls_back_to_auto_without_a_sensor() {
+        let hwmon = fake_hwmon("curve-fallback", 70.0);
+        let clock = FakeClock::new();
+        let mut controller = CurveController::new(curve(5, 100), RazerLaptop { fan_zone_count: 1, ..test_laptop() }, hwmon.path(), Arc::new(clock.clone()));
+        assert_eq!(controller.tick(), CurveAction::SetRpm(vec![5000]));
+
+        // Driver unloaded
+        std::fs::remove_dir_all(hwmon.path().join("hwmon1")).unwrap();
+        assert_eq!(controller.tick(), CurveAction::FallBackToAuto);
+        assert_eq!(controller.tick(), CurveAction::Wait);
+
+        // Back under another number. The curve starts afresh, so the ramp limit does not apply
+        hwmon.write("hwmon4/name", "coretemp\n");
+        hwmon.write("hwmon4/temp1_label", "Package id 0\n");
+        hwmon.write("hwmon4/temp1_input", "45000\n");
+        clock.advance(CURVE_INTERVAL);
+        assert_eq!(controller.tick(), CurveAction::TakeOver(vec![3500]));
+        clock.advance(CURVE_INTERVAL);
+        hwmon.write("hwmon4/temp1_input", "80000\n");
+        assert_eq!(controller.tick(), CurveAction::SetRpm(vec![3600]));
+    }
+}
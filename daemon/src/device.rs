@@ -1,123 +1,509 @@
-use core::panic;
+use std::{collections::HashSet, path::Path, sync::Arc};
+#[cfg(all(feature = "hidapi", unix))]
+use std::ffi::CString;
 
-use common::hw::DeviceType;
-use hidapi::{HidApi, HidDevice};
-use smbioslib::{SMBiosSystemInformation, table_load_from_device};
+use common::{UnsupportedCmd, devicedb::DeviceSpec, hw::{DeviceInfo, DeviceType, FirmwareVersion, SmbiosInfo}};
+#[cfg(feature = "hidapi")]
+use hidapi::HidApi;
+#[cfg(feature = "usb")]
+use rusb::{Context, UsbContext};
 
-use crate::razer::{RazerCmdStatus, RazerError, RazerPacket, RazerResult};
+use crate::{commands::{GetFirmware, GetSerial, RazerCommand}, razer::{RAZER_REPORT_LEN, RazerCmdStatus, RazerError, RazerPacket, RazerResult}, retry::{Clock, RetryPolicy, SystemClock}, topology::HidInterface, trace::{TraceDirection, TraceEntry, TraceWriter, read_trace}, transport::{RazerTransport, ReplayTransport, TransportKind}};
+#[cfg(feature = "hidapi")]
+use crate::transport::HidTransport;
+#[cfg(feature = "usb")]
+use crate::transport::{KEYBOARD_USAGE, UsbTransport, hid_interfaces, usb_bus_path};
+#[cfg(any(feature = "usb", target_os = "linux"))]
+use crate::topology::PhysicalDevice;
+#[cfg(any(target_os = "linux", all(feature = "hidapi", unix)))]
+use crate::topology::{bus_path, group_interfaces};
+#[cfg(target_os = "linux")]
+use crate::transport::{HidrawTransport, OpenRazerTransport, find_hidraw_nodes, find_openrazer_devices};
 
 
 
 pub const RAZER_VENDOR_ID: u16 = 0x1532;
 
+/// True if a device at `bus_path`, opened through `path`, is in `known`
+/// (Paths and bus paths of devices we already drive)
+fn is_known(known: &[String], bus_path: &str, path: &str) -> bool {
+    known.iter().any(|k| (!bus_path.is_empty() && k == bus_path) || k == path)
+}
+
 
 pub struct RazerDevice {
     pub device_type: DeviceType,
+    /// Device database entry, refined to the exact model where we can tell. None for unknown devices
+    pub spec: Option<DeviceSpec>,
     pub serial: String,
-    pub device: HidDevice,
+    /// Transaction ID stamped onto every packet sent to the device
+    pub transaction_id: u8,
+    pub device: Box<dyn RazerTransport>,
+    /// USB interface number the transport is bound to, if known
+    pub interface: Option<i32>,
+    /// Where the transport found the device
+    pub path: String,
+    /// Where the physical device sits on the bus (e.g. usb-0000:00:14.0-8), if known.
+    /// Matches [bus_path](crate::topology::bus_path) of the kernel's HID_PHYS
+    pub bus_path: String,
+    /// Every HID interface of the physical device, the control one included.
+    /// Empty if the backend does not know about interfaces
+    pub interfaces: Vec<HidInterface>,
+    /// Commands the device has told us it does not support
+    unsupported: HashSet<UnsupportedCmd>,
+    /// Policy for commands that do not ask for their own
+    pub retry: RetryPolicy,
+    clock: Arc<dyn Clock>,
+    /// If set, every report sent or received gets written here
+    trace: Option<TraceWriter>,
 }
  
 
 impl RazerDevice {
-    pub fn scan_devices(api: &mut HidApi) -> RazerResult<Vec<Self>> {
+    /// Creates a device on top of any transport. Serial is left unknown
+    pub fn new(device_type: DeviceType, transport: Box<dyn RazerTransport>) -> Self {
+        let spec = device_type.spec();
+        Self {
+            transaction_id: device_type.transaction_id(),
+            device_type,
+            retry: RetryPolicy::for_spec(spec.as_ref()),
+            spec,
+            serial: "UNKNOWN SN".into(),
+            device: transport,
+            interface: None,
+            path: String::new(),
+            bus_path: String::new(),
+            interfaces: Vec::new(),
+            unsupported: HashSet::new(),
+            clock: Arc::new(SystemClock),
+            trace: None
+        }
+    }
+
+    /// Picks the laptop's exact model out of its database entry's variants using `smbios`,
+    /// and takes its serial number from there. Does nothing for other devices
+    pub fn refine_model(&mut self, smbios: &SmbiosInfo) {
+        if !self.device_type.is_laptop() {
+            return
+        }
+        if let Some(spec) = &self.spec {
+            let refined = spec.refine(smbios);
+            self.device_type = DeviceType::from_spec(&refined);
+            self.spec = Some(refined);
+        }
+        if !smbios.serial.is_empty() {
+            self.serial = smbios.serial.clone();
+        }
+    }
+
+    /// Builds devices that replay a trace recorded with [RazerDevice::enable_trace],
+    /// one per (product ID, serial) found in the trace
+    pub fn from_trace<P: AsRef<Path>>(path: P) -> RazerResult<Vec<Self>> {
+        let source = format!("replay:{}", path.as_ref().display());
+        let mut grouped: Vec<((u16, String), Vec<TraceEntry>)> = Vec::new();
+        for entry in read_trace(path)? {
+            let key = (entry.product_id, entry.serial.clone());
+            match grouped.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, entries)) => entries.push(entry),
+                None => grouped.push((key, vec![entry]))
+            }
+        }
+        Ok(grouped.into_iter().map(|((pid, serial), entries)| {
+            let mut dev = Self::new(DeviceType::from_id(pid), Box::new(ReplayTransport::new(entries)));
+            dev.serial = serial;
+            dev.path = source.clone();
+            dev
+        }).collect())
+    }
+
+    /// Starts recording every report to and from the device
+    pub fn enable_trace(&mut self, trace: TraceWriter) {
+        self.trace = Some(trace);
+    }
+
+    fn trace(&self, dir: TraceDirection, result: Result<&[u8], &RazerError>) {
+        if let Some(t) = &self.trace {
+            t.record(self.device_type.get_id(), self.device_type.get_name(), &self.serial, dir, result);
+        }
+    }
+
+    fn send_report(&mut self, buf: &[u8]) -> RazerResult<()> {
+        let res = self.device.send_feature_report(buf);
+        self.trace(TraceDirection::Out, res.as_ref().map(|_| buf));
+        res
+    }
+
+    fn get_report(&mut self, buf: &mut [u8]) -> RazerResult<usize> {
+        let res = self.device.get_feature_report(buf);
+        self.trace(TraceDirection::In, res.as_ref().map(|n| &buf[0..*n]));
+        res
+    }
+
+    /// Finds devices through hidapi. Product IDs in `claimed` are left alone
+    /// (They are already driven by another backend), as are devices in `known`
+    #[cfg(feature = "hidapi")]
+    pub fn scan_devices(api: &mut HidApi, claimed: &[u16], known: &[String]) -> RazerResult<Vec<Self>> {
         let mut located: Vec<Self> = Vec::new();
+        #[cfg(windows)]
         for d in api.device_list() {
-            #[cfg(windows)]
-            if d.vendor_id() == RAZER_VENDOR_ID && d.usage() == 0x02 {
+            let path = d.path().to_string_lossy();
+            if d.vendor_id() == RAZER_VENDOR_ID && d.usage() == 0x02 && !claimed.contains(&d.product_id()) && !is_known(known, "", &path) {
                 let device = DeviceType::from_id(d.product_id());
                 if let Ok(data) = d.open_device(api) {
-                    located.push(Self {
-                        device_type: device,
-                        serial: "UNKNOWN SN".into(),
-                        device: data
-                    })
+                    let mut dev = Self::new(device, Box::new(HidTransport::new(data)));
+                    dev.interface = Some(d.interface_number());
+                    dev.path = path.into();
+                    located.push(dev)
                 }
             }
-            #[cfg(unix)]
-            if d.vendor_id() == RAZER_VENDOR_ID {
-                let device = DeviceType::from_id(d.product_id());
-                if let Ok(data) = d.open_device(api) {
-                    let mut maybe = Self {
-                        device_type: device,
-                        serial: "UNKNOWN SN".into(),
-                        device: data,
-                    };
-                    maybe.get_serial_number();
-                    if located.iter().find(|x| x.serial == maybe.serial).is_none() {
-                        located.push(maybe)
+        }
+        #[cfg(unix)]
+        {
+            // hidapi's hidraw backend only hands out /dev/hidrawN, sysfs knows where that is on the bus
+            #[cfg(target_os = "linux")]
+            let nodes = find_hidraw_nodes(Path::new("/sys"), Path::new("/dev"), RAZER_VENDOR_ID).unwrap_or_default();
+            let found: Vec<(u16, String, HidInterface)> = api.device_list()
+                .filter(|d| d.vendor_id() == RAZER_VENDOR_ID && !claimed.contains(&d.product_id()))
+                .map(|d| {
+                    let path: String = d.path().to_string_lossy().into();
+                    #[cfg(target_os = "linux")]
+                    let bus = nodes.iter()
+                        .find(|n| n.node.to_string_lossy() == path)
+                        .map(|n| bus_path(&n.phys).to_string())
+                        .unwrap_or_else(|| bus_path(&path).to_string());
+                    #[cfg(not(target_os = "linux"))]
+                    let bus = bus_path(&path).to_string();
+                    let interface = Some(d.interface_number()).filter(|x| *x >= 0);
+                    (d.product_id(), bus, HidInterface { path, interface, usage_page: d.usage_page(), usage: d.usage() })
+                })
+                .collect();
+            for phys in group_interfaces(found) {
+                let device = DeviceType::from_id(phys.product_id);
+                let control = match phys.control_interface(&device.control_interface()) {
+                    Some(c) => c.clone(),
+                    None => continue
+                };
+                if is_known(known, &phys.bus_path, &control.path) {
+                    continue
+                }
+                let data = match CString::new(control.path.clone()).map(|p| api.open_path(&p)) {
+                    Ok(Ok(data)) => data,
+                    _ => {
+                        eprintln!("Error opening {} ({})", device.get_name(), control.path);
+                        continue
                     }
+                };
+                let mut dev = Self::new(device, Box::new(HidTransport::new(data)));
+                dev.interface = control.interface;
+                dev.path = control.path;
+                dev.bus_path = phys.bus_path;
+                dev.interfaces = phys.interfaces;
+                dev.get_serial_number();
+                located.push(dev)
+            }
+        }
+
+        Ok(located)
+    }
+
+    /// Finds devices through libusb, sending reports as raw control transfers.
+    /// Devices in `known` are left alone
+    #[cfg(feature = "usb")]
+    pub fn scan_usb_devices(context: &Context, claimed: &[u16], known: &[String]) -> RazerResult<Vec<Self>> {
+        let mut located: Vec<Self> = Vec::new();
+        for d in context.devices()?.iter() {
+            let desc = match d.device_descriptor() {
+                Ok(desc) => desc,
+                Err(_) => continue
+            };
+            if desc.vendor_id() != RAZER_VENDOR_ID || claimed.contains(&desc.product_id()) {
+                continue;
+            }
+            let device = DeviceType::from_id(desc.product_id());
+            // Same naming as sysfs (bus-port.port)
+            let port_numbers = d.port_numbers().unwrap_or_default();
+            let ports: Vec<String> = port_numbers.iter().map(|p| p.to_string()).collect();
+            let path = format!("usb:{}-{}", d.bus_number(), ports.join("."));
+            let bus = usb_bus_path(Path::new("/sys"), d.bus_number(), &port_numbers);
+            // Checked before reading descriptors, let alone claiming
+            if is_known(known, &bus, "") {
+                continue
+            }
+            let interfaces = match hid_interfaces(&d, &path) {
+                Ok(i) => i,
+                Err(e) => {
+                    eprintln!("Error reading {} config descriptor! {:?}", device.get_name(), e);
+                    continue
+                }
+            };
+            let phys = PhysicalDevice { product_id: desc.product_id(), bus_path: bus, interfaces };
+            let spec = device.control_interface();
+            let control = match phys.control_interface(&spec) {
+                Some(c) => c.clone(),
+                None => continue
+            };
+            if is_known(known, "", &control.path) {
+                continue
+            }
+            // Claiming takes the interface off usbhid, fine for the control collection but not the keyboard
+            if (control.usage_page, control.usage) == KEYBOARD_USAGE && spec.interface != control.interface {
+                eprintln!("Not opening {} over USB, its control interface looks like the keyboard", device.get_name());
+                continue
+            }
+            let number = control.interface.unwrap_or(0) as u8;
+            match UsbTransport::open(&d, number) {
+                Ok(transport) => {
+                    let mut dev = Self::new(device, Box::new(transport));
+                    dev.interface = control.interface;
+                    dev.path = control.path;
+                    dev.bus_path = phys.bus_path;
+                    dev.interfaces = phys.interfaces;
+                    dev.get_serial_number();
+                    located.push(dev)
+                },
+                Err(e) => eprintln!("Error opening {} over USB: {:?}", device.get_name(), e)
+            }
+        }
+        Ok(located)
+    }
+
+    /// Razer devices under `<sysfs_root>/class/hidraw`, with their nodes under `dev_root`.
+    /// Product IDs in `claimed` are left out, as are devices in `known`
+    #[cfg(target_os = "linux")]
+    fn find_hidraw_devices(sysfs_root: &Path, dev_root: &Path, claimed: &[u16], known: &[String]) -> RazerResult<Vec<PhysicalDevice>> {
+        let nodes = find_hidraw_nodes(sysfs_root, dev_root, RAZER_VENDOR_ID)?;
+        let found = nodes.iter()
+            .filter(|n| !claimed.contains(&n.product_id))
+            .map(|n| {
+                let intf = HidInterface { path: n.node.to_string_lossy().into(), interface: n.interface, usage_page: n.usage_page, usage: n.usage };
+                (n.product_id, bus_path(&n.phys).to_string(), intf)
+            });
+        Ok(group_interfaces(found).into_iter()
+            .filter(|p| !p.interfaces.iter().any(|i| is_known(known, &p.bus_path, &i.path)))
+            .collect())
+    }
+
+    /// Finds devices by walking `<sysfs_root>/class/hidraw` and opening the matching
+    /// nodes under `dev_root` (Normally /sys and /dev). Devices in `known` are left alone
+    #[cfg(target_os = "linux")]
+    pub fn scan_hidraw_devices(sysfs_root: &Path, dev_root: &Path, claimed: &[u16], known: &[String]) -> RazerResult<Vec<Self>> {
+        let mut located: Vec<Self> = Vec::new();
+        for phys in Self::find_hidraw_devices(sysfs_root, dev_root, claimed, known)? {
+            let device = DeviceType::from_id(phys.product_id);
+            let control = match phys.control_interface(&device.control_interface()) {
+                Some(c) => c.clone(),
+                None => continue
+            };
+            let transport = match HidrawTransport::open(Path::new(&control.path)) {
+                Ok(t) => t,
+                Err(e) => {
+                    eprintln!("Error opening {}! {:?}", control.path, e);
+                    continue
                 }
+            };
+            // sysfs and the node can disagree if a device was swapped between the two
+            match transport.raw_info() {
+                Ok((vid, pid)) if vid == RAZER_VENDOR_ID && pid == phys.product_id => {},
+                _ => continue
+            }
+            let mut dev = Self::new(device, Box::new(transport));
+            dev.interface = control.interface;
+            dev.path = control.path;
+            dev.bus_path = phys.bus_path;
+            dev.interfaces = phys.interfaces;
+            dev.get_serial_number();
+            located.push(dev)
+        }
+        Ok(located)
+    }
+
+    /// Finds devices bound to the openrazer kernel driver under `sysfs_root` (Normally /sys).
+    /// These are driven through the driver's sysfs attributes rather than raw reports.
+    /// Devices in `known` are left alone
+    #[cfg(target_os = "linux")]
+    pub fn scan_openrazer_devices(sysfs_root: &Path, known: &[String]) -> RazerResult<Vec<Self>> {
+        let mut located: Vec<Self> = Vec::new();
+        for node in find_openrazer_devices(sysfs_root, RAZER_VENDOR_ID)? {
+            if is_known(known, bus_path(&node.phys), &node.path.to_string_lossy()) {
+                continue
             }
+            let mut dev = Self::new(DeviceType::from_id(node.product_id), Box::new(OpenRazerTransport::new(&node.path)));
+            dev.path = node.path.to_string_lossy().into();
+            dev.bus_path = bus_path(&node.phys).into();
+            dev.get_serial_number();
+            located.push(dev)
         }
+        Ok(located)
+    }
 
+    /// Scans for devices using the given backend.
+    ///
+    /// On Linux, anything the openrazer driver has claimed goes through [RazerDevice::scan_openrazer_devices]
+    /// regardless of `kind`, as raw reports would fight with the driver.
+    ///
+    /// Devices whose path or bus path is in `known` are never opened, so rescanning on hotplug
+    /// leaves the ones we already drive alone
+    pub fn scan(kind: TransportKind, known: &[String]) -> RazerResult<Vec<Self>> {
+        #[cfg(target_os = "linux")]
+        let mut located = Self::scan_openrazer_devices(Path::new("/sys"), known)?;
+        #[cfg(not(target_os = "linux"))]
+        let mut located = Vec::new();
+
+        // Known openrazer devices are skipped above, but their product IDs still belong to the driver
+        #[cfg(target_os = "linux")]
+        let claimed: Vec<u16> = find_openrazer_devices(Path::new("/sys"), RAZER_VENDOR_ID)?
+            .iter().map(|n| n.product_id).collect();
+        #[cfg(not(target_os = "linux"))]
+        let claimed: Vec<u16> = Vec::new();
+        located.extend(match kind {
+            #[cfg(feature = "hidapi")]
+            TransportKind::HidApi => Self::scan_devices(&mut HidApi::new()?, &claimed, known)?,
+            #[cfg(feature = "usb")]
+            TransportKind::Usb => Self::scan_usb_devices(&Context::new()?, &claimed, known)?,
+            #[cfg(target_os = "linux")]
+            TransportKind::Hidraw => Self::scan_hidraw_devices(Path::new("/sys"), Path::new("/dev"), &claimed, known)?,
+        });
         Ok(located)
     }
 
     // Attempts to get Razers serial number and sets it to the device
     fn get_serial_number(&mut self) {
+        // Laptops get theirs from SMBIOS, see refine_model
         if self.device_type.is_laptop() {
-            // We do it differently
-            if let Ok(table) = table_load_from_device() {
-                match table.find_map(|sys_info: SMBiosSystemInformation| sys_info.serial_number()) {
-                    Some(uuid) => { self.serial = uuid },
-                    _ => {}
-                }
-            }
             return;
         }
 
-        let packet = RazerPacket::new(0x00, 0x82, &[0u8; 16]);
-        if let Ok(resp) = self.write_and_read_cmd(packet){
-            if let Ok(s) = String::from_utf8(Vec::from(&resp.args[0..resp.data_size as usize])) {
-                println!("Serial: {}", s);
-                self.serial = s;
-            }
+        if let Ok(s) = self.execute(&GetSerial) {
+            println!("Serial: {}", s);
+            self.serial = s;
         }  else {
             println!("Error getting Serial number for {}", self.device_type.get_name());
         }
     }
 
-    fn get_fw_version(&mut self) {
-        println!("{:?}", self.write_and_read_cmd(RazerPacket::new(0x00, 0x81, &[0u8; 2])))
+    /// Queries the device for its firmware version and bundles it with everything else we know about it
+    pub fn device_info(&mut self) -> RazerResult<DeviceInfo> {
+        let firmware = self.execute(&GetFirmware)?;
+        Ok(self.info_with_firmware(firmware))
+    }
+
+    /// Everything we know about the device, for when the firmware version is already known
+    pub fn info_with_firmware(&self, firmware: FirmwareVersion) -> DeviceInfo {
+        DeviceInfo {
+            product_id: self.device_type.get_id(),
+            name: self.device_type.get_name().into(),
+            serial: self.serial.clone(),
+            firmware,
+            interface: self.interface,
+            path: self.path.clone()
+        }
+    }
+
+    /// Builds a packet with this device's transaction ID
+    pub fn new_packet(&self, class: u8, cmd: u8, args: &[u8]) -> RazerPacket {
+        RazerPacket::new_with_id(self.transaction_id, class, cmd, args)
+    }
+
+    /// Replaces the clock used for retry timing
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Sends a command and parses the device's reply
+    pub fn execute<C: RazerCommand>(&mut self, cmd: &C) -> RazerResult<C::Response> {
+        let policy = C::RETRY_POLICY.unwrap_or(self.retry);
+        let resp = self.transact(cmd.build(self.transaction_id), cmd.target(), &policy)?;
+        cmd.parse(&resp)
+    }
+
+    /// Sends a command without waiting for a reply, for when speed matters more than knowing it worked
+    pub fn execute_no_reply<C: RazerCommand>(&mut self, cmd: &C) -> RazerResult<()> {
+        self.write_cmd(cmd.build(self.transaction_id))
+    }
+
+    /// Returns false if the device has already replied NotSupported to this command (for this target)
+    pub fn is_supported(&self, class: u8, cmd: u8, target: Option<u8>) -> bool {
+        !self.unsupported.contains(&UnsupportedCmd { class, cmd, target })
+    }
+
+    /// Like [RazerDevice::is_supported], for a typed command
+    pub fn supports<C: RazerCommand>(&self, cmd: &C) -> bool {
+        self.is_supported(C::CLASS, C::ID, cmd.target())
+    }
+
+    /// Every command the device has replied NotSupported to so far
+    pub fn unsupported_cmds(&self) -> Vec<UnsupportedCmd> {
+        let mut list: Vec<UnsupportedCmd> = self.unsupported.iter().copied().collect();
+        list.sort_unstable();
+        list
     }
 
     pub fn write_and_read_cmd(&mut self, packet: RazerPacket) -> RazerResult<RazerPacket> {
-        let mut buf: [u8; 91] = [0; 91];
-        buf.copy_from_slice(&packet.create_packet());
+        let policy = self.retry;
+        self.write_and_read_cmd_with(packet, &policy)
+    }
 
+    /// Sends a packet and waits for the reply, retrying as `policy` says
+    pub fn write_and_read_cmd_with(&mut self, packet: RazerPacket, policy: &RetryPolicy) -> RazerResult<RazerPacket> {
+        self.transact(packet, None, policy)
+    }
+
+    /// Does the work of [RazerDevice::write_and_read_cmd_with]. A NotSupported reply is
+    /// remembered for `target` only, so other LEDs or zones still get asked
+    fn transact(&mut self, mut packet: RazerPacket, target: Option<u8>, policy: &RetryPolicy) -> RazerResult<RazerPacket> {
+        let key = UnsupportedCmd { class: packet.cmd_class, cmd: packet.cmd_id, target };
+        if self.unsupported.contains(&key) {
+            // No point asking again, the answer won't change
+            return Err(RazerError::CmdNotSupported)
+        }
+        packet.id = self.transaction_id;
+        let out = packet.encode();
+
+        let start = self.clock.now();
+        let mut busy_count = 0;
         let mut err = RazerError::ECTimeout;
-        for _ in 0..3 { // Try sending packet 3 times
-            if let Err(e) = self.device.send_feature_report(&buf) {
-                err = e.into();
-                std::thread::sleep(std::time::Duration::from_micros(400));
+        for _ in 0..policy.attempts {
+            if let Some(deadline) = policy.deadline {
+                if self.clock.now().duration_since(start) >= deadline {
+                    break;
+                }
+            }
+            if let Err(e) = self.send_report(&out) {
+                err = e;
+                self.clock.sleep(policy.report_delay);
                 continue;
             }
-            std::thread::sleep(std::time::Duration::from_micros(400));
-            match self.device.get_feature_report(&mut buf) {
-                Ok(read_count) => {
-                    if read_count != 91 {
-                        continue; // Try again!
+            self.clock.sleep(policy.report_delay);
+            let mut buf = [0u8; RAZER_REPORT_LEN];
+            let new = match self.get_report(&mut buf) {
+                Ok(read_count) => match RazerPacket::decode(&buf[0..read_count]) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        // Garbage back from the device, try again!
+                        err = e;
+                        continue;
                     }
                 },
                 Err(e) => {
-                    err = e.into();
+                    err = e;
                     continue;
                 }
-            }
-            let new = RazerPacket::from_raw(&buf);
+            };
             if packet.is_same(&new) {
-                if new.status == RazerCmdStatus::Successful {
-                    return Ok(new)
-                } else {
-                    err = match new.status {
-                        RazerCmdStatus::Busy => {
-                            std::thread::sleep(std::time::Duration::from_micros(1000));
-                            RazerError::ECBusy
-                        },
-                        RazerCmdStatus::Failure => RazerError::ECFailure,
-                        RazerCmdStatus::Timeout => RazerError::ECTimeout,
-                        _ => panic!("Attempted to create Razer error from OK packet!?")
-                    }
+                err = match new.status {
+                    RazerCmdStatus::Successful => return Ok(new),
+                    RazerCmdStatus::NotSupported => {
+                        self.unsupported.insert(key);
+                        return Err(RazerError::CmdNotSupported)
+                    },
+                    RazerCmdStatus::Busy => {
+                        self.clock.sleep(policy.busy_wait(busy_count));
+                        busy_count += 1;
+                        RazerError::ECBusy
+                    },
+                    RazerCmdStatus::Failure => RazerError::ECFailure,
+                    // New means the device has not got round to our packet yet
+                    RazerCmdStatus::Timeout | RazerCmdStatus::New => RazerError::ECTimeout,
                 }
             } else {
                 err = RazerError::InvalidResponse
@@ -126,7 +512,219 @@ impl RazerDevice {
         Err(err)
     }
 
-    pub fn write_cmd(&mut self, packet: RazerPacket) -> RazerResult<()> {
-        Ok(self.device.send_feature_report(&packet.create_packet())?)
+    pub fn write_cmd(&mut self, mut packet: RazerPacket) -> RazerResult<()> {
+        packet.id = self.transaction_id;
+        self.send_report(&packet.encode())
+    }
+
+    /// Closes the underlying transport. The device is unusable afterwards
+    pub fn close(&mut self) -> RazerResult<()> {
+        self.device.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use crate::{chroma::{Led, LedStorage}, commands::{GetFanRpm, GetLedBrightness}, test_util::fake_device, transport::FakeResponse};
+    #[cfg(target_os = "linux")]
+    use crate::test_util::TempDir;
+
+    use super::*;
+
+    /// The reply to `request` with a raw status byte, which may not be a valid [RazerCmdStatus]
+    fn reply_with_status(request: &RazerPacket, status: u8) -> Vec<u8> {
+        let mut raw = request.encode();
+        raw[1] = status;
+        raw.to_vec()
+    }
+
+    #[test]
+    fn survives_every_status_byte() {
+        for status in 0..=u8::MAX {
+            let (mut dev, fake) = fake_device(0x0235);
+            let request = GetSerial.build(dev.transaction_id);
+            fake.respond_to(GetSerial::CLASS, GetSerial::ID, FakeResponse::Raw(reply_with_status(&request, status)));
+            let res = dev.execute(&GetSerial);
+            match RazerCmdStatus::try_from(status) {
+                Ok(RazerCmdStatus::Successful) => assert!(res.is_ok()),
+                Ok(RazerCmdStatus::NotSupported) => assert!(matches!(res, Err(RazerError::CmdNotSupported))),
+                Ok(RazerCmdStatus::Busy) => assert!(matches!(res, Err(RazerError::ECBusy))),
+                Ok(RazerCmdStatus::Failure) => assert!(matches!(res, Err(RazerError::ECFailure))),
+                Ok(RazerCmdStatus::Timeout) | Ok(RazerCmdStatus::New) => assert!(matches!(res, Err(RazerError::ECTimeout))),
+                Err(_) => assert!(matches!(res, Err(RazerError::UnknownStatus(s)) if s == status))
+            }
+            // Only NotSupported is a final answer, everything else is worth retrying
+            let expected_sends = match status {
+                2 | 5 => 1,
+                _ => RetryPolicy::DEFAULT.attempts as usize
+            };
+            assert_eq!(fake.sent().len(), expected_sends, "status {}", status);
+        }
+    }
+
+    #[test]
+    fn not_supported_is_remembered() {
+        let (mut dev, fake) = fake_device(0x0235);
+        fake.respond_to(GetSerial::CLASS, GetSerial::ID, FakeResponse::Status(RazerCmdStatus::NotSupported));
+        assert!(matches!(dev.execute(&GetSerial), Err(RazerError::CmdNotSupported)));
+        assert!(!dev.supports(&GetSerial));
+        // Answered from memory, without asking the device again
+        assert!(matches!(dev.execute(&GetSerial), Err(RazerError::CmdNotSupported)));
+        assert_eq!(fake.sent().len(), 1);
+        assert_eq!(dev.unsupported_cmds(), vec![UnsupportedCmd { class: 0x00, cmd: 0x82, target: None }]);
+        // Other commands are unaffected
+        assert!(dev.execute(&GetFirmware).is_ok());
+    }
+
+    #[test]
+    fn not_supported_is_per_target() {
+        let (mut dev, fake) = fake_device(0x0235);
+        fake.push_response(FakeResponse::Status(RazerCmdStatus::NotSupported));
+        assert!(matches!(dev.execute(&GetFanRpm { zone: 2 }), Err(RazerError::CmdNotSupported)));
+        fake.push_response(FakeResponse::Reply(vec![0x00, 0x01, 0x23]));
+        assert_eq!(dev.execute(&GetFanRpm { zone: 1 }).unwrap(), 3500);
+
+        let logo = GetLedBrightness { storage: LedStorage::VarStore, led: Led::Logo };
+        let backlight = GetLedBrightness { storage: LedStorage::VarStore, led: Led::Backlight };
+        fake.push_response(FakeResponse::Status(RazerCmdStatus::NotSupported));
+        assert!(matches!(dev.execute(&logo), Err(RazerError::CmdNotSupported)));
+        fake.push_response(FakeResponse::Reply(vec![0x01, 0x05, 0x80]));
+        assert_eq!(dev.execute(&backlight).unwrap(), 0x80);
+
+        assert!(!dev.supports(&GetFanRpm { zone: 2 }) && !dev.supports(&logo));
+        assert!(dev.supports(&GetFanRpm { zone: 1 }) && dev.supports(&backlight));
+        assert_eq!(dev.unsupported_cmds(), vec![
+            UnsupportedCmd { class: 0x03, cmd: 0x83, target: Some(Led::Logo as u8) },
+            UnsupportedCmd { class: 0x0D, cmd: 0x81, target: Some(2) }
+        ]);
+    }
+
+    #[test]
+    fn transport_errors() {
+        let (mut dev, fake) = fake_device(0x0235);
+        fake.respond_to(GetFirmware::CLASS, GetFirmware::ID, FakeResponse::ShortRead(10));
+        assert!(matches!(dev.execute(&GetFirmware), Err(RazerError::InvalidLength(10))));
+        assert!(dev.supports(&GetFirmware));
+
+        dev.close().unwrap();
+        assert!(fake.is_closed());
+        assert!(matches!(dev.execute(&GetSerial), Err(RazerError::TransportClosed)));
+    }
+
+    #[test]
+    fn laptop_serial_comes_from_smbios() {
+        let smbios = SmbiosInfo { serial: "BY2117M12345678".into(), ..Default::default() };
+        let (mut laptop, _) = fake_device(0x026D);
+        laptop.refine_model(&smbios);
+        assert_eq!(laptop.serial, "BY2117M12345678");
+        // Tables without one leave it alone
+        laptop.refine_model(&SmbiosInfo::default());
+        assert_eq!(laptop.serial, "BY2117M12345678");
+
+        let (mut keyboard, _) = fake_device(0x0235);
+        keyboard.refine_model(&smbios);
+        assert_eq!(keyboard.serial, "UNKNOWN SN");
+    }
+
+    #[test]
+    fn reply_for_another_command_is_rejected() {
+        let (mut dev, fake) = fake_device(0x0235);
+        let other = GetSerial.build(dev.transaction_id);
+        fake.respond_to(GetFirmware::CLASS, GetFirmware::ID, FakeResponse::Raw(reply_with_status(&other, 2)));
+        assert!(matches!(dev.execute(&GetFirmware), Err(RazerError::InvalidResponse)));
+    }
+
+    /// Adds a hidraw node to a fake sysfs tree, the way the kernel lays it out
+    #[cfg(target_os = "linux")]
+    fn add_hidraw(sysfs: &TempDir, node: &str, vid: u16, pid: u16, phys: &str) {
+        let uevent = format!("DRIVER=hid-generic\nHID_ID=0003:{:08X}:{:08X}\nHID_NAME=Razer\nHID_PHYS={}\n", vid, pid, phys);
+        sysfs.write(&format!("class/hidraw/{}/device/uevent", node), uevent);
+    }
+
+    #[cfg(target_os = "linux")]
+    fn fake_hidraw_tree() -> TempDir {
+        let sysfs = TempDir::new("hidraw-scan");
+        // Two of the same keyboard, neither with a readable serial
+        add_hidraw(&sysfs, "hidraw0", RAZER_VENDOR_ID, 0x0235, "usb-0000:00:14.0-1/input0");
+        add_hidraw(&sysfs, "hidraw1", RAZER_VENDOR_ID, 0x0235, "usb-0000:00:14.0-1/input2");
+        add_hidraw(&sysfs, "hidraw2", RAZER_VENDOR_ID, 0x0235, "usb-0000:00:14.0-2/input0");
+        add_hidraw(&sysfs, "hidraw3", RAZER_VENDOR_ID, 0x0235, "usb-0000:00:14.0-2/input2");
+        add_hidraw(&sysfs, "hidraw4", RAZER_VENDOR_ID, 0x026D, "usb-0000:00:14.0-8/input0");
+        add_hidraw(&sysfs, "hidraw7", RAZER_VENDOR_ID, 0x026D, "usb-0000:00:14.0-8/input1");
+        add_hidraw(&sysfs, "hidraw8", RAZER_VENDOR_ID, 0x026D, "usb-0000:00:14.0-8/input2");
+        // Laptop keyboard, mouse collection (control) and consumer keys
+        sysfs.write("class/hidraw/hidraw4/device/report_descriptor", [0x05, 0x01, 0x09, 0x06, 0xA1, 0x01]);
+        sysfs.write("class/hidraw/hidraw7/device/report_descriptor", [0x05, 0x01, 0x09, 0x02, 0xA1, 0x01]);
+        sysfs.write("class/hidraw/hidraw8/device/report_descriptor", [0x05, 0x0C, 0x09, 0x01, 0xA1, 0x01]);
+        add_hidraw(&sysfs, "hidraw5", 0x046D, 0xC52B, "usb-0000:00:14.0-3/input0");
+        // Half gone (unplugged while we looked)
+        std::fs::create_dir_all(sysfs.path().join("class/hidraw/hidraw6")).unwrap();
+        sysfs
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn finds_hidraw_nodes() {
+        let sysfs = fake_hidraw_tree();
+        let nodes = find_hidraw_nodes(sysfs.path(), Path::new("/dev"), RAZER_VENDOR_ID).unwrap();
+        let names: Vec<String> = nodes.iter().map(|n| n.node.to_string_lossy().into_owned()).collect();
+        assert_eq!(names, vec!["/dev/hidraw0", "/dev/hidraw1", "/dev/hidraw2", "/dev/hidraw3", "/dev/hidraw4", "/dev/hidraw7", "/dev/hidraw8"]);
+        assert_eq!((nodes[5].usage_page, nodes[5].usage), (0x01, 0x02));
+        // No report descriptor
+        assert_eq!((nodes[0].usage_page, nodes[0].usage), (0, 0));
+        assert_eq!(nodes[1].product_id, 0x0235);
+        assert_eq!(nodes[1].interface, Some(2));
+        assert_eq!(nodes[4].phys, "usb-0000:00:14.0-8/input0");
+        assert!(find_hidraw_nodes(&sysfs.path().join("missing"), Path::new("/dev"), RAZER_VENDOR_ID).is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn groups_hidraw_devices() {
+        let sysfs = fake_hidraw_tree();
+        let found = RazerDevice::find_hidraw_devices(sysfs.path(), Path::new("/dev"), &[], &[]).unwrap();
+        let buses: Vec<(u16, &str)> = found.iter().map(|p| (p.product_id, p.bus_path.as_str())).collect();
+        assert_eq!(buses, vec![(0x0235, "usb-0000:00:14.0-1"), (0x0235, "usb-0000:00:14.0-2"), (0x026D, "usb-0000:00:14.0-8")]);
+        // The database puts the Blackwidow Lite's control reports on interface 2
+        let keyboard = DeviceType::from_id(0x0235);
+        assert_eq!(found[0].control_interface(&keyboard.control_interface()).unwrap().path, "/dev/hidraw1");
+        assert_eq!(found[1].control_interface(&keyboard.control_interface()).unwrap().path, "/dev/hidraw3");
+        assert_eq!(found[0].input_interfaces(&keyboard.control_interface())[0].path, "/dev/hidraw0");
+        // The laptop has no interface in the database, so it goes by usage
+        let laptop = DeviceType::from_id(0x026D);
+        assert_eq!(found[2].interfaces.len(), 3);
+        assert_eq!(found[2].control_interface(&laptop.control_interface()).unwrap().path, "/dev/hidraw7");
+
+        let unclaimed = RazerDevice::find_hidraw_devices(sysfs.path(), Path::new("/dev"), &[0x0235], &[]).unwrap();
+        assert_eq!(unclaimed.len(), 1);
+        assert_eq!(unclaimed[0].product_id, 0x026D);
+
+        // Already driven, by bus path or by any of its nodes
+        let known = vec!["usb-0000:00:14.0-8".to_string(), "/dev/hidraw3".to_string()];
+        let new = RazerDevice::find_hidraw_devices(sysfs.path(), Path::new("/dev"), &[], &known).unwrap();
+        assert_eq!(new.len(), 1);
+        assert_eq!(new[0].bus_path, "usb-0000:00:14.0-1");
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn hidraw_scan_skips_nodes_it_cannot_open() {
+        let sysfs = fake_hidraw_tree();
+        let dev = TempDir::new("hidraw-dev");
+        // Plain files, so the ioctls fail
+        (0..5).for_each(|n| { dev.write(&format!("hidraw{}", n), ""); });
+        assert!(RazerDevice::scan_hidraw_devices(sysfs.path(), dev.path(), &[], &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn stamps_transaction_id() {
+        let (mut dev, fake) = fake_device(0x0235);
+        dev.transaction_id = 0x1F;
+        dev.execute(&GetFirmware).unwrap();
+        dev.execute_no_reply(&GetSerial).unwrap();
+        dev.write_and_read_cmd(RazerPacket::new_with_id(0x3F, 0x00, 0x81, &[0, 0])).unwrap();
+        assert!(fake.sent().iter().all(|raw| raw[2] == 0x1F));
     }
 }
@@ -0,0 +1,123 @@
+//! Laptop fan control through the Blade EC.
+//!
+//! The EC has a power mode per fan zone, with a flag for manual fan control.
+//! Manual speeds only stick while that flag is set, so switching mode keeps
+//! the zone's power mode and only flips the flag
+
+use common::{RazerLaptop, laptop::{FanMode, LaptopSettings}};
+
+use crate::{commands::{GetFanRpm, GetPowerMode, PowerState, SetFanRpm, SetPowerMode}, device::RazerDevice, razer::{RazerError, RazerResult}, store::DeviceStore};
+
+/// Laptop settings the user has saved, per device
+pub type LaptopStore = DeviceStore<LaptopSettings>;
+
+/// Keeps `mode` within what the model's fans can do
+pub fn clamp_fan_mode(laptop: &RazerLaptop, mode: FanMode) -> FanMode {
+    match mode {
+        FanMode::Manual(rpm) => FanMode::Manual(clamp_rpm(laptop, rpm)),
+        other => other
+    }
+}
+
+pub fn clamp_rpm(laptop: &RazerLaptop, rpm: u32) -> u32 {
+    rpm.clamp(laptop.min_fan_rpm, laptop.max_fan_rpm)
+}
+
+/// Current speed of every fan zone, zone 1 first
+pub fn read_fan_rpm(dev: &mut RazerDevice, laptop: &RazerLaptop) -> RazerResult<Vec<u32>> {
+    (1..=laptop.fan_zone_count).map(|zone| dev.execute(&GetFanRpm { zone })).collect()
+}
+
+/// Turns manual fan control on or off for every zone, keeping each zone's power mode
+pub fn set_manual_fan(dev: &mut RazerDevice, laptop: &RazerLaptop, manual_fan: bool) -> RazerResult<()> {
+    for zone in 1..=laptop.fan_zone_count {
+        let current = dev.execute(&GetPowerMode { zone })?;
+        if current.manual_fan != manual_fan {
+            dev.execute(&SetPowerMode { zone, state: PowerState { mode: current.mode, manual_fan } })?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets each zone's speed, zone 1 first, clamped to the model's range.
+/// Only sticks once manual fan control is on
+pub fn set_fan_rpm(dev: &mut RazerDevice, laptop: &RazerLaptop, rpm: &[u32]) -> RazerResult<()> {
+    for (zone, rpm) in (1..=laptop.fan_zone_count).zip(rpm) {
+        dev.execute(&SetFanRpm { zone, rpm: clamp_rpm(laptop, *rpm) })?;
+    }
+    Ok(())
+}
+
+/// Puts every fan zone into `mode`. Returns the mode as applied, after clamping.
+/// For [FanMode::Curve] this only hands the fans over to us, the speeds come
+/// from a [CurveTask](crate::curve::CurveTask)
+pub fn apply_fan_mode(dev: &mut RazerDevice, laptop: &RazerLaptop, mode: FanMode) -> RazerResult<FanMode> {
+    if laptop.fan_zone_count == 0 {
+        return Err(RazerError::CmdNotSupported)
+    }
+    let mode = clamp_fan_mode(laptop, mode);
+    match &mode {
+        FanMode::Auto => set_manual_fan(dev, laptop, false)?,
+        FanMode::Manual(rpm) => {
+            set_manual_fan(dev, laptop, true)?;
+            set_fan_rpm(dev, laptop, &vec![*rpm; laptop.fan_zone_count as usize])?;
+        },
+        FanMode::Curve(curve) => {
+            curve.validate(laptop.fan_zone_count).map_err(RazerError::InvalidConfig)?;
+            set_manual_fan(dev, laptop, true)?;
+        }
+    }
+    Ok(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{commands::{self, RazerCommand}, test_util::{fake_device, sent_args, test_laptop}, transport::{FakeResponse, FakeTransport}};
+
+    use super::*;
+
+    fn reply_power_mode(fake: &FakeTransport, mode: commands::PowerMode, manual_fan: bool) {
+        fake.respond_to(GetPowerMode::CLASS, GetPowerMode::ID, FakeResponse::Reply(vec![0x00, 0x01, mode as u8, manual_fan as u8]));
+    }
+
+    #[test]
+    fn manual_fan_keeps_the_power_mode() {
+        let (mut dev, fake) = fake_device(0x026D);
+        reply_power_mode(&fake, commands::PowerMode::Gaming, false);
+        set_manual_fan(&mut dev, &test_laptop(), true).unwrap();
+        assert_eq!(sent_args(&fake, SetPowerMode::CLASS, SetPowerMode::ID), vec![vec![0x00, 1, 1, 1], vec![0x00, 2, 1, 1]]);
+
+        // Already where it should be, so nothing is set
+        let (mut dev, fake) = fake_device(0x026D);
+        reply_power_mode(&fake, commands::PowerMode::Creator, true);
+        set_manual_fan(&mut dev, &test_laptop(), true).unwrap();
+        assert!(sent_args(&fake, SetPowerMode::CLASS, SetPowerMode::ID).is_empty());
+        set_manual_fan(&mut dev, &test_laptop(), false).unwrap();
+        assert_eq!(sent_args(&fake, SetPowerMode::CLASS, SetPowerMode::ID), vec![vec![0x00, 1, 2, 0], vec![0x00, 2, 2, 0]]);
+    }
+
+    #[test]
+    fn clamps_to_the_model() {
+        let laptop = test_laptop();
+        assert_eq!(clamp_rpm(&laptop, 1000), 3500);
+        assert_eq!(clamp_rpm(&laptop, 4200), 4200);
+        assert_eq!(clamp_rpm(&laptop, 9000), 5000);
+        assert_eq!(clamp_fan_mode(&laptop, FanMode::Manual(0)), FanMode::Manual(3500));
+        assert_eq!(clamp_fan_mode(&laptop, FanMode::Manual(u32::MAX)), FanMode::Manual(5000));
+        assert_eq!(clamp_fan_mode(&laptop, FanMode::Auto), FanMode::Auto);
+
+        let (mut dev, fake) = fake_device(0x026D);
+        reply_power_mode(&fake, commands::PowerMode::Balanced, false);
+        assert_eq!(apply_fan_mode(&mut dev, &laptop, FanMode::Manual(9000)).unwrap(), FanMode::Manual(5000));
+        assert_eq!(sent_args(&fake, SetFanRpm::CLASS, SetFanRpm::ID), vec![vec![0x00, 1, 50], vec![0x00, 2, 50]]);
+    }
+
+    #[test]
+    fn no_fans_is_not_supported() {
+        let (mut dev, fake) = fake_device(0x026D);
+        let laptop = RazerLaptop { fan_zone_count: 0, ..test_laptop() };
+        assert!(matches!(apply_fan_mode(&mut dev, &laptop, FanMode::Manual(4000)), Err(RazerError::CmdNotSupported)));
+        assert!(matches!(apply_fan_mode(&mut dev, &laptop, FanMode::Auto), Err(RazerError::CmdNotSupported)));
+        assert!(fake.sent().is_empty());
+    }
+}
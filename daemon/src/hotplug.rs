@@ -0,0 +1,376 @@
+//! Hotplug support.
+//!
+//! Listens to udev's netlink broadcasts and keeps the [DeviceRegistry](crate::registry::DeviceRegistry)
+//! in step with what is actually plugged in. [Hotplug::handle] works on parsed [Uevent]s,
+//! so events can be made up and fed to it without any hardware or netlink socket
+
+use std::{collections::HashMap, io, os::unix::io::RawFd, path::Path, sync::{Arc, Mutex}, thread::JoinHandle, time::Duration};
+
+use crate::{device::{RAZER_VENDOR_ID, RazerDevice}, ipc::{DaemonState, add_device}, razer::RazerResult, topology::bus_path, transport::parse_hid_name};
+
+/// Netlink group the kernel sends raw uevents to
+pub const KERNEL_GROUP: u32 = 1;
+/// Netlink group udev re-broadcasts events to, once it has set up the device node
+pub const UDEV_GROUP: u32 = 2;
+
+/// How long to wait for more events after one arrives. Plugging in a single keyboard
+/// sends a burst of them, and there is no point rescanning for each
+pub const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UeventAction {
+    Add,
+    Remove,
+    Change,
+    Bind,
+    Unbind,
+    Other(String)
+}
+
+impl From<&str> for UeventAction {
+    fn from(s: &str) -> Self {
+        match s {
+            "add" => Self::Add,
+            "remove" => Self::Remove,
+            "change" => Self::Change,
+            "bind" => Self::Bind,
+            "unbind" => Self::Unbind,
+            x => Self::Other(x.into())
+        }
+    }
+}
+
+/// A device event from the kernel or udev
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uevent {
+    pub action: UeventAction,
+    pub subsystem: String,
+    /// Path of the device under /sys
+    pub devpath: String,
+    /// Every KEY=VALUE that came with the event
+    pub vars: HashMap<String, String>
+}
+
+impl Uevent {
+    pub fn new(action: UeventAction, subsystem: &str, devpath: &str) -> Self {
+        Self { action, subsystem: subsystem.into(), devpath: devpath.into(), vars: HashMap::new() }
+    }
+
+    /// Adds a KEY=VALUE to the event
+    pub fn with_var(mut self, key: &str, value: &str) -> Self {
+        self.vars.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.vars.get(key).map(|x| x.as_str())
+    }
+
+    /// Parses a netlink message. Takes both the kernel's format (ACTION@DEVPATH, then
+    /// NUL separated KEY=VALUE) and udev's (libudev header, then the same KEY=VALUE list)
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        let props = if buf.starts_with(b"libudev\0") {
+            // prefix[8], magic, header_size, properties_off, properties_len, filter hashes...
+            if buf.len() < 24 {
+                return None
+            }
+            let off = u32::from_ne_bytes([buf[16], buf[17], buf[18], buf[19]]) as usize;
+            let len = u32::from_ne_bytes([buf[20], buf[21], buf[22], buf[23]]) as usize;
+            buf.get(off..off.checked_add(len)?)?
+        } else {
+            // Skip the ACTION@DEVPATH header, it is repeated in the vars
+            let header_end = buf.iter().position(|x| *x == 0)?;
+            if !buf[0..header_end].contains(&b'@') {
+                return None
+            }
+            &buf[header_end + 1..]
+        };
+        let mut vars = HashMap::new();
+        for raw in props.split(|x| *x == 0) {
+            let line = String::from_utf8_lossy(raw);
+            if let Some((k, v)) = line.split_once('=') {
+                vars.insert(k.to_string(), v.to_string());
+            }
+        }
+        Some(Self {
+            action: UeventAction::from(vars.get("ACTION")?.as_str()),
+            subsystem: vars.get("SUBSYSTEM").cloned().unwrap_or_default(),
+            devpath: vars.get("DEVPATH")?.clone(),
+            vars
+        })
+    }
+
+    /// (Vendor ID, product ID) of the HID device the event is about. hid events carry
+    /// HID_ID, hidraw events only have it in their DEVPATH (.../0003:1532:026D.0001/hidraw/hidraw3)
+    pub fn hid_id(&self) -> Option<(u16, u16)> {
+        if let Some(id) = self.get("HID_ID") {
+            let parts: Vec<&str> = id.split(':').collect();
+            if parts.len() == 3 {
+                return Some((u32::from_str_radix(parts[1], 16).ok()? as u16, u32::from_str_radix(parts[2], 16).ok()? as u16))
+            }
+        }
+        self.devpath.split('/').rev().find_map(parse_hid_name)
+    }
+}
+
+/// Socket receiving uevents over netlink
+pub struct UeventSocket {
+    fd: RawFd
+}
+
+impl UeventSocket {
+    /// Opens a socket listening to `group` ([UDEV_GROUP] or [KERNEL_GROUP])
+    pub fn open(group: u32) -> io::Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let socket = Self { fd };
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = group;
+        let res = unsafe {
+            libc::bind(fd, &addr as *const libc::sockaddr_nl as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(socket)
+    }
+
+    /// Waits for the next event. None if nothing arrived within `timeout`
+    /// (None waits forever) or the message could not be parsed
+    pub fn recv(&self, timeout: Option<Duration>) -> io::Result<Option<Uevent>> {
+        let mut pfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        let ms = timeout.map(|t| t.as_millis().min(i32::MAX as u128) as libc::c_int).unwrap_or(-1);
+        let ready = unsafe { libc::poll(&mut pfd, 1, ms) };
+        if ready < 0 {
+            return Err(io::Error::last_os_error())
+        } else if ready == 0 {
+            return Ok(None)
+        }
+        let mut buf = [0u8; 8192];
+        let len = unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(Uevent::parse(&buf[0..len as usize]))
+    }
+}
+
+impl Drop for UeventSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Finds every Razer device currently plugged in, apart from those whose path or bus path
+/// is in the list given (Devices the registry already has, which must not be reopened)
+pub type Scanner = Box<dyn FnMut(&[String]) -> RazerResult<Vec<RazerDevice>> + Send>;
+
+/// Adds and removes devices as they come and go
+pub struct Hotplug {
+    scan: Scanner
+}
+
+impl Hotplug {
+    pub fn new(scan: Scanner) -> Self {
+        Self { scan }
+    }
+
+    /// True if the event is a Razer HID device arriving or leaving
+    pub fn is_relevant(ev: &Uevent) -> bool {
+        let action = matches!(ev.action, UeventAction::Add | UeventAction::Remove | UeventAction::Bind | UeventAction::Unbind);
+        let subsystem = ev.subsystem == "hid" || ev.subsystem == "hidraw";
+        action && subsystem && ev.hid_id().map(|(vid, _)| vid == RAZER_VENDOR_ID).unwrap_or(false)
+    }
+
+    /// Applies a burst of events. Devices that left are dropped first, then if anything
+    /// arrived we rescan once for devices the registry does not have yet
+    pub fn handle(&mut self, events: &[Uevent], state: &Mutex<DaemonState>) {
+        let events: Vec<&Uevent> = events.iter().filter(|e| Self::is_relevant(e)).collect();
+        let removed: Vec<&str> = events.iter()
+            .filter(|e| matches!(e.action, UeventAction::Remove | UeventAction::Unbind))
+            .filter_map(|e| e.get("HID_PHYS"))
+            .map(bus_path)
+            .collect();
+        if events.iter().any(|e| matches!(e.action, UeventAction::Remove | UeventAction::Unbind)) {
+            let mut state = state.lock().unwrap();
+            let gone: Vec<_> = state.registry.iter()
+                .filter(|e| {
+                    let unplugged = !e.bus_path.is_empty() && removed.contains(&e.bus_path.as_str());
+                    // Node went away (hidraw, openrazer)
+                    let node_gone = e.info.path.starts_with('/') && !Path::new(&e.info.path).exists();
+                    unplugged || node_gone
+                })
+                .map(|e| e.id)
+                .collect();
+            gone.into_iter().for_each(|id| { state.remove_device(id); });
+        }
+
+        if events.iter().any(|e| matches!(e.action, UeventAction::Add | UeventAction::Bind)) {
+            // Scan without holding the lock, it talks to every new device
+            let known = state.lock().unwrap().registry.known_paths();
+            let found = match (self.scan)(&known) {
+                Ok(f) => f,
+                Err(e) => {
+                    eprintln!("Error rescanning for devices! {:?}", e);
+                    return
+                }
+            };
+            for mut dev in found {
+                // Could have been added by someone else while we were scanning
+                if state.lock().unwrap().registry.contains(&dev) {
+                    let _ = dev.close();
+                } else {
+                    add_device(state, dev);
+                }
+            }
+        }
+    }
+
+    /// Handles events from `socket` on a background thread, forever
+    pub fn spawn(mut self, socket: UeventSocket, state: Arc<Mutex<DaemonState>>) -> JoinHandle<()> {
+        std::thread::spawn(move || loop {
+            let first = match socket.recv(None) {
+                Ok(Some(ev)) if Self::is_relevant(&ev) => ev,
+                Ok(_) => continue,
+                Err(e) => {
+                    eprintln!("Error reading uevent! {}", e);
+                    std::thread::sleep(Duration::from_secs(1));
+                    continue
+                }
+            };
+            let mut batch = vec![first];
+            while let Ok(Some(ev)) = socket.recv(Some(SETTLE_TIME)) {
+                batch.push(ev);
+            }
+            self.handle(&batch, &state);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use crate::{test_util::fake_device, transport::FakeTransport};
+
+    use super::*;
+
+    const DEVPATH: &str = "/devices/pci0000:00/0000:00:14.0/usb3/3-8/3-8:1.2/0003:1532:0235.0004";
+
+    fn hid_event(action: UeventAction, vid: u16, port: u8) -> Uevent {
+        Uevent::new(action, "hid", DEVPATH)
+            .with_var("HID_ID", &format!("0003:{:08X}:00000235", vid))
+            .with_var("HID_PHYS", &format!("usb-0000:00:14.0-{}/input2", port))
+    }
+
+    fn device_on_port(port: u8) -> (RazerDevice, FakeTransport) {
+        let (mut dev, fake) = fake_device(0x0235);
+        dev.path = format!("usb:3-{}:2", port);
+        dev.bus_path = format!("usb-0000:00:14.0-{}", port);
+        (dev, fake)
+    }
+
+    /// Scanner handing out `found` one scan at a time, remembering the known list of each scan
+    fn scanner(found: Vec<Vec<RazerDevice>>) -> (Scanner, Arc<Mutex<Vec<Vec<String>>>>) {
+        let mut found: VecDeque<Vec<RazerDevice>> = found.into();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let seen = calls.clone();
+        let scan: Scanner = Box::new(move |known: &[String]| {
+            seen.lock().unwrap().push(known.to_vec());
+            Ok(found.pop_front().unwrap_or_default())
+        });
+        (scan, calls)
+    }
+
+    #[test]
+    fn adds_and_removes_devices() {
+        let (first, _) = device_on_port(8);
+        let (second, _) = device_on_port(9);
+        let (scan, calls) = scanner(vec![vec![first], vec![second]]);
+        let mut hotplug = Hotplug::new(scan);
+        let state = Mutex::new(DaemonState::default());
+
+        hotplug.handle(&[hid_event(UeventAction::Add, RAZER_VENDOR_ID, 8)], &state);
+        assert_eq!(state.lock().unwrap().registry.len(), 1);
+        // The second scan is told not to touch the first device
+        hotplug.handle(&[hid_event(UeventAction::Bind, RAZER_VENDOR_ID, 9)], &state);
+        assert_eq!(state.lock().unwrap().registry.len(), 2);
+        assert_eq!(*calls.lock().unwrap(), vec![vec![], vec!["usb:3-8:2".to_string(), "usb-0000:00:14.0-8".to_string()]]);
+
+        hotplug.handle(&[hid_event(UeventAction::Remove, RAZER_VENDOR_ID, 8)], &state);
+        let state = state.lock().unwrap();
+        let left: Vec<&str> = state.registry.iter().map(|e| e.bus_path.as_str()).collect();
+        assert_eq!(left, vec!["usb-0000:00:14.0-9"]);
+        // Nothing arrived, so no rescan
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn closes_devices_already_tracked() {
+        let (first, _) = device_on_port(8);
+        let (again, fake) = device_on_port(8);
+        let (scan, _) = scanner(vec![vec![first], vec![again]]);
+        let mut hotplug = Hotplug::new(scan);
+        let state = Mutex::new(DaemonState::default());
+
+        hotplug.handle(&[hid_event(UeventAction::Add, RAZER_VENDOR_ID, 8)], &state);
+        hotplug.handle(&[hid_event(UeventAction::Add, RAZER_VENDOR_ID, 8)], &state);
+        assert_eq!(state.lock().unwrap().registry.len(), 1);
+        assert!(fake.is_closed());
+        assert!(fake.sent().is_empty());
+    }
+
+    #[test]
+    fn ignores_other_devices() {
+        let (scan, calls) = scanner(Vec::new());
+        let mut hotplug = Hotplug::new(scan);
+        let state = Mutex::new(DaemonState::default());
+
+        let logitech = hid_event(UeventAction::Add, 0x046D, 8);
+        let change = hid_event(UeventAction::Change, RAZER_VENDOR_ID, 8);
+        let usb = Uevent::new(UeventAction::Add, "usb", "/devices/pci0000:00/0000:00:14.0/usb3/3-8");
+        assert!(!Hotplug::is_relevant(&logitech) && !Hotplug::is_relevant(&change) && !Hotplug::is_relevant(&usb));
+        hotplug.handle(&[logitech, change, usb], &state);
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn hid_id_from_devpath() {
+        let ev = Uevent::new(UeventAction::Add, "hidraw", &format!("{}/hidraw/hidraw3", DEVPATH));
+        assert_eq!(ev.hid_id(), Some((RAZER_VENDOR_ID, 0x0235)));
+        assert!(Hotplug::is_relevant(&ev));
+    }
+
+    const PROPS: &[u8] = b"ACTION=remove\0DEVPATH=/devices/x/0003:1532:0235.0004\0SUBSYSTEM=hid\0HID_PHYS=usb-0000:00:14.0-8/input2\0";
+
+    #[test]
+    fn parses_kernel_events() {
+        let mut buf = b"remove@/devices/x/0003:1532:0235.0004\0".to_vec();
+        buf.extend_from_slice(PROPS);
+        let ev = Uevent::parse(&buf).unwrap();
+        assert_eq!(ev.action, UeventAction::Remove);
+        assert_eq!(ev.subsystem, "hid");
+        assert_eq!(ev.devpath, "/devices/x/0003:1532:0235.0004");
+        assert_eq!(ev.get("HID_PHYS"), Some("usb-0000:00:14.0-8/input2"));
+        assert!(Uevent::parse(b"garbage\0ACTION=add\0").is_none());
+    }
+
+    #[test]
+    fn parses_udev_events() {
+        let mut buf = b"libudev\0".to_vec();
+        buf.extend_from_slice(&0xFEEDCAFEu32.to_be_bytes());
+        buf.extend_from_slice(&40u32.to_ne_bytes());
+        buf.extend_from_slice(&40u32.to_ne_bytes());
+        buf.extend_from_slice(&(PROPS.len() as u32).to_ne_bytes());
+        buf.resize(40, 0);
+        buf.extend_from_slice(PROPS);
+        let ev = Uevent::parse(&buf).unwrap();
+        assert_eq!(ev.action, UeventAction::Remove);
+        assert_eq!(ev.hid_id(), Some((RAZER_VENDOR_ID, 0x0235)));
+        // Properties past the end of the message
+        assert!(Uevent::parse(&buf[0..50]).is_none());
+    }
+}
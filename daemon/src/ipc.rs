@@ -0,0 +1,581 @@
+use std::{collections::HashMap, io::Write, path::PathBuf, sync::{Arc, Mutex}};
+#[cfg(unix)]
+use std::{io::{BufRead, BufReader}, os::unix::net::{UnixListener, UnixStream}, time::Duration};
+
+use common::{DeviceCapabilities, RazerDeviceKind, RazerLaptop, ipc::{DaemonEvent, DaemonRequest, DaemonResponse, DeviceId}, laptop::{BatteryStatus, FanMode, FanStatus}, lighting::{PowerProfile, PowerProfiles}};
+#[cfg(unix)]
+use common::ipc::SOCKET_PATH;
+
+use crate::{battery::{POWER_SUPPLY_ROOT, apply_battery_health, read_battery_health, read_power_supply}, chroma::{Led, LedController, LedStorage}, curve::{CurveController, CurveTask, HWMON_ROOT, find_temp_inputs, read_temp}, device::RazerDevice, fan::{LaptopStore, apply_fan_mode, read_fan_rpm}, lighting::{LightingStore, apply_lighting, apply_logo, read_logo}, power::{apply_power_mode, read_power_mode}, registry::{DeviceRegistry, ProbedDevice}, retry::{Clock, SystemClock}, worker::WorkerHandle};
+
+/// Everything the daemon shares between its clients, the hotplug monitor and main
+pub struct DaemonState {
+    pub registry: DeviceRegistry,
+    pub lighting: LightingStore,
+    pub laptop: LaptopStore,
+    /// Where fan curves look for their sensors
+    pub hwmon_root: PathBuf,
+    /// Where battery and AC state is read from
+    pub power_supply_root: PathBuf,
+    /// Clock fan curves run on
+    pub clock: Arc<dyn Clock>,
+    /// Lighting for the power source the machine is on
+    pub power_profile: PowerProfile,
+    /// Fan curves being run, per laptop
+    curves: HashMap<DeviceId, CurveTask>,
+    /// Connections that asked for [DaemonEvent]s
+    subscribers: Vec<Box<dyn Write + Send>>
+}
+
+impl Default for DaemonState {
+    fn default() -> Self {
+        Self {
+            registry: DeviceRegistry::default(),
+            lighting: LightingStore::default(),
+            laptop: LaptopStore::default(),
+            hwmon_root: HWMON_ROOT.into(),
+            power_supply_root: POWER_SUPPLY_ROOT.into(),
+            clock: Arc::new(SystemClock),
+            power_profile: PowerProfiles::default().ac,
+            curves: HashMap::new(),
+            subscribers: Vec::new()
+        }
+    }
+}
+
+impl DaemonState {
+    pub fn new(lighting: LightingStore, laptop: LaptopStore) -> Self {
+        Self { lighting, laptop, ..Self::default() }
+    }
+
+    /// Sends every future event to `out`, one JSON [DaemonResponse::Event] per line
+    pub fn subscribe(&mut self, out: Box<dyn Write + Send>) {
+        self.subscribers.push(out)
+    }
+
+    /// Sends `ev` to all subscribers, forgetting any that have gone away
+    pub fn broadcast(&mut self, ev: DaemonEvent) {
+        let line = match serde_json::to_string(&DaemonResponse::Event(ev)) {
+            Ok(l) => l,
+            Err(_) => return
+        };
+        self.subscribers.retain_mut(|out| writeln!(out, "{}", line).and_then(|_| out.flush()).is_ok());
+    }
+
+    /// Starts tracking a device, restores the user's saved lighting on it and tells subscribers.
+    /// See [crate::ipc::add_device] for getting from a [RazerDevice] to here without holding the state locked
+    pub fn add_device(&mut self, device: ProbedDevice) -> DeviceId {
+        let id = self.registry.add(device);
+        if let Some(entry) = self.registry.get(id) {
+            let info = entry.info.clone();
+            println!("{} - SN: {}, FW: {}, Interface: {:?} ({})", info.name, info.serial, info.firmware, info.interface, info.path);
+            self.restore(id);
+            self.broadcast(DaemonEvent::DeviceAdded(id, info));
+        }
+        id
+    }
+
+    /// Reapplies everything the user saved for a device (lighting, fans). Queued on
+    /// the device's worker, so this returns straight away
+    pub fn restore(&mut self, id: DeviceId) {
+        let entry = match self.registry.get(id) {
+            Some(e) => e,
+            None => return
+        };
+        let info = &entry.info;
+        let worker = entry.worker.handle();
+        if let Some(saved) = self.lighting.get(info.product_id, &info.serial).cloned() {
+            let name = info.name.clone();
+            let _ = worker.send(move |dev| {
+                if let Err(e) = apply_lighting(dev, &saved) {
+                    eprintln!("Error restoring lighting on {}! {:?}", name, e);
+                }
+            });
+        }
+        if self.power_profile.brightness.is_some() {
+            self.apply_power_brightness(id);
+        }
+        let saved = self.laptop.get(info.product_id, &info.serial).cloned().unwrap_or_default();
+        // Before the fans, whose manual flag sits alongside the power mode
+        if let (Some(laptop), Some(mode)) = (laptop_caps(&entry.caps), saved.power) {
+            let name = info.name.clone();
+            let _ = worker.send(move |dev| {
+                if let Err(e) = apply_power_mode(dev, &laptop, mode) {
+                    eprintln!("Error restoring power mode on {}! {:?}", name, e);
+                }
+            });
+        }
+        if let (Some(laptop), Some(health)) = (laptop_caps(&entry.caps), saved.battery_health) {
+            let name = info.name.clone();
+            let _ = worker.send(move |dev| {
+                if let Err(e) = apply_battery_health(dev, &laptop, health) {
+                    eprintln!("Error restoring battery health optimizer on {}! {:?}", name, e);
+                }
+            });
+        }
+        if let (Some(laptop), Some(mode)) = (laptop_caps(&entry.caps), saved.fan) {
+            let name = info.name.clone();
+            let to_apply = mode.clone();
+            let _ = worker.send(move |dev| {
+                if let Err(e) = apply_fan_mode(dev, &laptop, to_apply) {
+                    eprintln!("Error restoring fan mode on {}! {:?}", name, e);
+                }
+            });
+            self.run_fan_curve(id, &mode);
+        }
+    }
+
+    /// [DaemonState::restore] for every device. For after a suspend, when the EC and
+    /// some devices have forgotten their settings
+    pub fn restore_all(&mut self) {
+        let ids: Vec<DeviceId> = self.registry.iter().map(|e| e.id).collect();
+        ids.into_iter().for_each(|id| self.restore(id));
+    }
+
+    /// Switches to the lighting for a new power source. The capture loop picks up
+    /// the effect and frame rate, backlights are changed here
+    pub fn set_power_profile(&mut self, profile: PowerProfile) {
+        self.power_profile = profile;
+        let ids: Vec<DeviceId> = self.registry.iter().map(|e| e.id).collect();
+        ids.into_iter().for_each(|id| self.apply_power_brightness(id));
+    }
+
+    /// Sets a device's backlight to the power profile's brightness, or back to the user's own if it has none
+    fn apply_power_brightness(&self, id: DeviceId) {
+        let entry = match self.registry.get(id) {
+            Some(e) if has_backlight_brightness(&e.caps) => e,
+            _ => return
+        };
+        // Not stored, so the device comes back at the user's brightness if the daemon stops
+        let (led, brightness) = match self.power_profile.brightness {
+            Some(b) => (LedController::new(Led::Backlight).with_storage(LedStorage::NoStore), b),
+            None => match self.lighting.get(entry.info.product_id, &entry.info.serial).and_then(|s| s.brightness) {
+                Some(b) => (LedController::new(Led::Backlight), b),
+                None => return
+            }
+        };
+        let name = entry.info.name.clone();
+        let _ = entry.worker.handle().send(move |dev| {
+            if let Err(e) = led.set_brightness(dev, brightness) {
+                eprintln!("Error setting brightness on {}! {:?}", name, e);
+            }
+        });
+    }
+
+    /// Stops the laptop's fan curve, if one is running
+    pub fn stop_fan_curve(&mut self, id: DeviceId) {
+        self.curves.remove(&id);
+    }
+
+    /// Starts running the laptop's fan curve if `mode` is one, stopping any it already had
+    pub fn run_fan_curve(&mut self, id: DeviceId, mode: &FanMode) {
+        self.stop_fan_curve(id);
+        let (entry, curve) = match (self.registry.get(id), mode) {
+            (Some(e), FanMode::Curve(c)) => (e, c),
+            _ => return
+        };
+        if let Some(laptop) = laptop_caps(&entry.caps) {
+            let controller = CurveController::new(curve.clone(), laptop.clone(), &self.hwmon_root, self.clock.clone());
+            let task = CurveTask::spawn(controller, laptop, entry.worker.handle(), entry.info.name.clone());
+            self.curves.insert(id, task);
+        }
+    }
+
+    /// Stops tracking a device and tells subscribers
+    pub fn remove_device(&mut self, id: DeviceId) -> bool {
+        self.stop_fan_curve(id);
+        match self.registry.remove(id) {
+            Some(entry) => {
+                println!("{} removed", entry.info.name);
+                self.broadcast(DaemonEvent::DeviceRemoved(id));
+                true
+            },
+            None => false
+        }
+    }
+}
+
+/// Probes `device`, then adds it to `state`. The state is only locked to get at the
+/// capability cache and to add the device, not while talking to it
+pub fn add_device(state: &Mutex<DaemonState>, device: RazerDevice) -> DeviceId {
+    let cache = state.lock().unwrap().registry.cache();
+    let probed = ProbedDevice::probe(device, &cache);
+    state.lock().unwrap().add_device(probed)
+}
+
+fn laptop_caps(caps: &DeviceCapabilities) -> Option<RazerLaptop> {
+    match &caps.kind {
+        RazerDeviceKind::Laptop(l) => Some(l.clone()),
+        _ => None
+    }
+}
+
+fn has_backlight_brightness(caps: &DeviceCapabilities) -> bool {
+    match &caps.kind {
+        RazerDeviceKind::Laptop(l) => l.keyboard.has_brightness,
+        RazerDeviceKind::Keyboard(k) => k.has_brightness,
+        RazerDeviceKind::Keypad(k) => k.keyboard.has_brightness,
+        RazerDeviceKind::Mousepad(m) => m.has_brightness,
+        RazerDeviceKind::Dock(d) | RazerDeviceKind::MouseDock(d) => d.has_brightness,
+        _ => false
+    }
+}
+
+fn has_logo_control(state: &Mutex<DaemonState>, id: DeviceId) -> bool {
+    state.lock().unwrap().registry.get(id).and_then(|e| laptop_caps(&e.caps)).map(|l| l.has_logo_control).unwrap_or(false)
+}
+
+/// Worker and capabilities of a laptop, or the error to send back
+fn laptop_worker(state: &Mutex<DaemonState>, id: DeviceId) -> Result<(WorkerHandle, RazerLaptop, u16, String), DaemonResponse> {
+    let state = state.lock().unwrap();
+    let entry = state.registry.get(id).ok_or_else(|| DaemonResponse::Error(format!("No device with ID {}", id)))?;
+    let laptop = laptop_caps(&entry.caps).ok_or_else(|| DaemonResponse::Error(format!("Device {} is not a laptop", id)))?;
+    Ok((entry.worker.handle(), laptop, entry.info.product_id, entry.info.serial.clone()))
+}
+
+/// Answers a request. The state is only locked to look things up, anything that talks
+/// to a device happens on its worker with the lock released
+pub fn handle_request(state: &Mutex<DaemonState>, req: &DaemonRequest) -> DaemonResponse {
+    match req {
+        DaemonRequest::ListDevices => {
+            DaemonResponse::Devices(state.lock().unwrap().registry.iter().map(|e| (e.id, e.info.clone())).collect())
+        },
+        DaemonRequest::GetCapabilities(id) => {
+            let (worker, mut caps) = match state.lock().unwrap().registry.get(*id) {
+                Some(e) => (e.worker.handle(), e.caps.clone()),
+                None => return DaemonResponse::Error(format!("No device with ID {}", id))
+            };
+            // Add whatever the device has turned down since it was probed
+            if let Ok(live) = worker.call(|dev| dev.unsupported_cmds()) {
+                caps.unsupported.extend(live);
+                caps.unsupported.sort_unstable();
+                caps.unsupported.dedup();
+            }
+            DaemonResponse::Capabilities(caps)
+        },
+        DaemonRequest::SetLighting(id, lighting) => {
+            let (worker, info) = {
+                let state = state.lock().unwrap();
+                match state.registry.get(*id) {
+                    Some(e) => (e.worker.handle(), e.info.clone()),
+                    None => return DaemonResponse::Error(format!("No device with ID {}", id))
+                }
+            };
+            if lighting.logo.is_some() && !has_logo_control(state, *id) {
+                return DaemonResponse::Error(format!("Device {} has no logo control", id))
+            }
+            // The power profile has the backlight, the brightness is saved for when it lets go
+            let overridden = lighting.brightness.is_some() && state.lock().unwrap().power_profile.brightness.is_some();
+            let mut to_apply = lighting.clone();
+            if overridden {
+                to_apply.brightness = None;
+            }
+            let res = worker.call(move |dev| apply_lighting(dev, &to_apply))
+                .and_then(|res| res)
+                .and_then(|_| {
+                    // Only what was asked for changes, the rest of what was saved stays
+                    let mut state = state.lock().unwrap();
+                    let mut saved = state.lighting.get(info.product_id, &info.serial).cloned().unwrap_or_default();
+                    saved.merge(lighting);
+                    let res = state.lighting.set(info.product_id, &info.serial, saved);
+                    // The power source can change while the lighting is applied, whichever brightness is in charge now wins
+                    if lighting.brightness.is_some() && (overridden || state.power_profile.brightness.is_some()) {
+                        state.apply_power_brightness(*id);
+                    }
+                    res
+                });
+            match res {
+                Ok(_) => DaemonResponse::Ok,
+                Err(e) => DaemonResponse::Error(format!("Error setting lighting: {:?}", e))
+            }
+        },
+        DaemonRequest::GetLogo(id) => {
+            let (worker, _, _, _) = match laptop_worker(state, *id) {
+                Ok(x) => x,
+                Err(resp) => return resp
+            };
+            if !has_logo_control(state, *id) {
+                return DaemonResponse::Error(format!("Device {} has no logo control", id))
+            }
+            match worker.call(read_logo).and_then(|res| res) {
+                Ok(logo) => DaemonResponse::Logo(logo),
+                Err(e) => DaemonResponse::Error(format!("Error reading logo: {:?}", e))
+            }
+        },
+        DaemonRequest::SetLogo(id, logo) => {
+            let (worker, _, pid, serial) = match laptop_worker(state, *id) {
+                Ok(x) => x,
+                Err(resp) => return resp
+            };
+            if !has_logo_control(state, *id) {
+                return DaemonResponse::Error(format!("Device {} has no logo control", id))
+            }
+            let logo = *logo;
+            let res = worker.call(move |dev| apply_logo(dev, logo))
+                .and_then(|res| res)
+                .and_then(|_| {
+                    let mut state = state.lock().unwrap();
+                    let mut lighting = state.lighting.get(pid, &serial).cloned().unwrap_or_default();
+                    lighting.logo = Some(logo);
+                    state.lighting.set(pid, &serial, lighting)
+                });
+            match res {
+                Ok(_) => DaemonResponse::Ok,
+                Err(e) => DaemonResponse::Error(format!("Error setting logo: {:?}", e))
+            }
+        },
+        DaemonRequest::GetFans(id) => {
+            let (worker, laptop, pid, serial) = match laptop_worker(state, *id) {
+                Ok(x) => x,
+                Err(resp) => return resp
+            };
+            let mode = state.lock().unwrap().laptop.get(pid, &serial).and_then(|s| s.fan.clone()).unwrap_or(FanMode::Auto);
+            match worker.call(move |dev| read_fan_rpm(dev, &laptop)).and_then(|res| res) {
+                Ok(rpm) => DaemonResponse::Fans(FanStatus { mode, rpm }),
+                Err(e) => DaemonResponse::Error(format!("Error reading fans: {:?}", e))
+            }
+        },
+        DaemonRequest::SetFanMode(id, mode) => {
+            let (worker, laptop, pid, serial) = match laptop_worker(state, *id) {
+                Ok(x) => x,
+                Err(resp) => return resp
+            };
+            if let FanMode::Curve(curve) = mode {
+                if let Err(e) = curve.validate(laptop.fan_zone_count) {
+                    return DaemonResponse::Error(format!("Invalid fan curve: {}", e))
+                }
+            }
+            // A running curve would fight whatever gets set now
+            state.lock().unwrap().stop_fan_curve(*id);
+            let to_apply = mode.clone();
+            let res = worker.call(move |dev| apply_fan_mode(dev, &laptop, to_apply))
+                .and_then(|res| res)
+                .and_then(|applied| {
+                    let mut state = state.lock().unwrap();
+                    state.run_fan_curve(*id, &applied);
+                    let mut settings = state.laptop.get(pid, &serial).cloned().unwrap_or_default();
+                    settings.fan = Some(applied);
+                    state.laptop.set(pid, &serial, settings)
+                });
+            match res {
+                Ok(_) => DaemonResponse::Ok,
+                Err(e) => DaemonResponse::Error(format!("Error setting fan mode: {:?}", e))
+            }
+        },
+        DaemonRequest::ListTempSensors => {
+            let root = state.lock().unwrap().hwmon_root.clone();
+            DaemonResponse::TempSensors(find_temp_inputs(&root).iter()
+                .filter_map(|i| Some((i.sensor(), read_temp(i)?)))
+                .collect())
+        },
+        DaemonRequest::GetPowerMode(id) => {
+            let (worker, _, _, _) = match laptop_worker(state, *id) {
+                Ok(x) => x,
+                Err(resp) => return resp
+            };
+            match worker.call(read_power_mode).and_then(|res| res) {
+                Ok(mode) => DaemonResponse::PowerMode(mode),
+                Err(e) => DaemonResponse::Error(format!("Error reading power mode: {:?}", e))
+            }
+        },
+        DaemonRequest::SetPowerMode(id, mode) => {
+            let (worker, laptop, pid, serial) = match laptop_worker(state, *id) {
+                Ok(x) => x,
+                Err(resp) => return resp
+            };
+            if let Err(e) = mode.validate(&laptop) {
+                return DaemonResponse::Error(format!("Invalid power mode: {}", e))
+            }
+            let mode = *mode;
+            let res = worker.call(move |dev| apply_power_mode(dev, &laptop, mode))
+                .and_then(|res| res)
+                .and_then(|_| {
+                    let mut state = state.lock().unwrap();
+                    let mut settings = state.laptop.get(pid, &serial).cloned().unwrap_or_default();
+                    settings.power = Some(mode);
+                    state.laptop.set(pid, &serial, settings)
+                });
+            match res {
+                Ok(_) => DaemonResponse::Ok,
+                Err(e) => DaemonResponse::Error(format!("Error setting power mode: {:?}", e))
+            }
+        },
+        DaemonRequest::GetBattery(id) => {
+            let (worker, laptop, _, _) = match laptop_worker(state, *id) {
+                Ok(x) => x,
+                Err(resp) => return resp
+            };
+            let root = state.lock().unwrap().power_supply_root.clone();
+            let health = match laptop.has_battery_health {
+                true => match worker.call(move |dev| read_battery_health(dev, &laptop)).and_then(|res| res) {
+                    Ok(h) => Some(h),
+                    Err(e) => return DaemonResponse::Error(format!("Error reading battery health optimizer: {:?}", e))
+                },
+                false => None
+            };
+            DaemonResponse::Battery(BatteryStatus { health, supply: read_power_supply(&root) })
+        },
+        DaemonRequest::SetBatteryHealth(id, health) => {
+            let (worker, laptop, pid, serial) = match laptop_worker(state, *id) {
+                Ok(x) => x,
+                Err(resp) => return resp
+            };
+            if !laptop.has_battery_health {
+                return DaemonResponse::Error(format!("Device {} has no battery health optimizer", id))
+            }
+            if let Err(e) = health.validate() {
+                return DaemonResponse::Error(format!("Invalid battery health optimizer: {}", e))
+            }
+            let health = *health;
+            let res = worker.call(move |dev| apply_battery_health(dev, &laptop, health))
+                .and_then(|res| res)
+                .and_then(|_| {
+                    let mut state = state.lock().unwrap();
+                    let mut settings = state.laptop.get(pid, &serial).cloned().unwrap_or_default();
+                    settings.battery_health = Some(health);
+                    state.laptop.set(pid, &serial, settings)
+                });
+            match res {
+                Ok(_) => DaemonResponse::Ok,
+                Err(e) => DaemonResponse::Error(format!("Error setting battery health optimizer: {:?}", e))
+            }
+        },
+        // Needs the connection, so handle_client deals with it
+        DaemonRequest::Subscribe => DaemonResponse::Error("Subscribe is only valid on a client connection".into())
+    }
+}
+
+#[cfg(unix)]
+fn handle_client(stream: UnixStream, state: Arc<Mutex<DaemonState>>) -> std::io::Result<()> {
+    let mut out = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let resp = match serde_json::from_str::<DaemonRequest>(&line) {
+            Ok(DaemonRequest::Subscribe) => {
+                // Hold the lock so no event can get in ahead of the reply
+                let mut state = state.lock().unwrap();
+                writeln!(out, "{}", serde_json::to_string(&DaemonResponse::Subscribed)?)?;
+                // A client that stops reading must not stall everyone else
+                let events = out.try_clone()?;
+                events.set_write_timeout(Some(Duration::from_secs(1)))?;
+                state.subscribe(Box::new(events));
+                continue;
+            },
+            Ok(req) => handle_request(&state, &req),
+            Err(e) => DaemonResponse::Error(format!("Invalid request: {}", e))
+        };
+        writeln!(out, "{}", serde_json::to_string(&resp)?)?;
+    }
+    Ok(())
+}
+
+/// Starts listening for clients on [SOCKET_PATH] in the background
+#[cfg(unix)]
+pub fn start_server(state: Arc<Mutex<DaemonState>>) -> std::io::Result<()> {
+    // Left over from a previous run
+    let _ = std::fs::remove_file(SOCKET_PATH);
+    let listener = UnixListener::bind(SOCKET_PATH)?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let state = state.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = handle_client(stream, state) {
+                    eprintln!("Client error: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{laptop::{CpuBoost, GpuBoost, PowerMode}, lighting::{FrameEffect, LightingState, LogoMode, LogoState}};
+
+    use crate::{commands::{RazerCommand, SetBoost, SetLedBrightness}, test_util::{fake_device, sent_args}, transport::FakeTransport};
+
+    use super::*;
+
+    /// State with a single fake laptop in it, and nothing saved to disk
+    fn state_with_laptop() -> (Mutex<DaemonState>, DeviceId, FakeTransport) {
+        let (dev, fake) = fake_device(0x026D);
+        let state = Mutex::new(DaemonState::default());
+        let id = add_device(&state, dev);
+        (state, id, fake)
+    }
+
+    fn saved_lighting(state: &Mutex<DaemonState>, id: DeviceId) -> Option<LightingState> {
+        let state = state.lock().unwrap();
+        let info = &state.registry.get(id).unwrap().info;
+        state.lighting.get(info.product_id, &info.serial).cloned()
+    }
+
+    #[test]
+    fn missing_device_is_an_error() {
+        let (state, id, _fake) = state_with_laptop();
+        assert!(matches!(handle_request(&state, &DaemonRequest::GetCapabilities(99)), DaemonResponse::Error(_)));
+        assert!(matches!(handle_request(&state, &DaemonRequest::GetCapabilities(id)), DaemonResponse::Capabilities(_)));
+
+        // Unplugged while a client still has its ID
+        drop(state.lock().unwrap().registry.remove(id));
+        assert!(matches!(handle_request(&state, &DaemonRequest::GetCapabilities(id)), DaemonResponse::Error(_)));
+        assert!(matches!(handle_request(&state, &DaemonRequest::GetFans(id)), DaemonResponse::Error(_)));
+    }
+
+    #[test]
+    fn set_lighting_keeps_what_was_not_asked_for() {
+        let (state, id, _fake) = state_with_laptop();
+        let logo = LogoState { mode: LogoMode::Breathing, brightness: 90 };
+        let first = LightingState { on: Some(true), brightness: Some(200), logo: Some(logo) };
+        assert!(matches!(handle_request(&state, &DaemonRequest::SetLighting(id, first)), DaemonResponse::Ok));
+        let dimmer = LightingState { brightness: Some(60), ..Default::default() };
+        assert!(matches!(handle_request(&state, &DaemonRequest::SetLighting(id, dimmer)), DaemonResponse::Ok));
+        assert_eq!(saved_lighting(&state, id), Some(LightingState { on: Some(true), brightness: Some(60), logo: Some(logo) }));
+    }
+
+    #[test]
+    fn set_power_mode_is_saved() {
+        let (state, id, fake) = state_with_laptop();
+        let saved = |state: &Mutex<DaemonState>| {
+            let state = state.lock().unwrap();
+            let info = &state.registry.get(id).unwrap().info;
+            state.laptop.get(info.product_id, &info.serial).and_then(|s| s.power)
+        };
+        let mode = PowerMode::Custom { cpu: CpuBoost::Boost, gpu: GpuBoost::High };
+        assert!(matches!(handle_request(&state, &DaemonRequest::SetPowerMode(id, mode)), DaemonResponse::Ok));
+        assert_eq!(saved(&state), Some(mode));
+        assert_eq!(sent_args(&fake, SetBoost::CLASS, SetBoost::ID).len(), 2);
+
+        // A request that fails leaves what was saved
+        assert!(matches!(handle_request(&state, &DaemonRequest::SetPowerMode(99, PowerMode::Gaming)), DaemonResponse::Error(_)));
+        assert_eq!(saved(&state), Some(mode));
+    }
+
+    /// Backlight brightnesses sent to the device so far, waiting for anything queued on its worker
+    fn backlight_brightness(state: &Mutex<DaemonState>, id: DeviceId, fake: &FakeTransport) -> Vec<u8> {
+        let worker = state.lock().unwrap().registry.worker(id).unwrap();
+        worker.call(|_| ()).unwrap();
+        fake.sent().iter()
+            .filter(|raw| raw[7..9] == [SetLedBrightness::CLASS, SetLedBrightness::ID] && raw[10] == Led::Backlight as u8)
+            .map(|raw| raw[11])
+            .collect()
+    }
+
+    #[test]
+    fn set_lighting_leaves_the_power_profile_brightness() {
+        let (state, id, fake) = state_with_laptop();
+        let on_battery = PowerProfile { brightness: Some(32), effect: FrameEffect::Static([0xFF, 0xFF, 0xFF]), fps: 1 };
+        state.lock().unwrap().set_power_profile(on_battery);
+        assert_eq!(backlight_brightness(&state, id, &fake), vec![32]);
+
+        let brighter = LightingState { brightness: Some(200), ..Default::default() };
+        assert!(matches!(handle_request(&state, &DaemonRequest::SetLighting(id, brighter)), DaemonResponse::Ok));
+        // Saved, but the profile keeps the backlight
+        assert_eq!(saved_lighting(&state, id).unwrap().brightness, Some(200));
+        assert_eq!(backlight_brightness(&state, id, &fake), vec![32, 32]);
+
+        // Back on AC the user's brightness returns
+        state.lock().unwrap().set_power_profile(PowerProfiles::default().ac);
+        assert_eq!(backlight_brightness(&state, id, &fake), vec![32, 32, 200]);
+    }
+}
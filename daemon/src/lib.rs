@@ -0,0 +1,26 @@
+pub mod razer;
+pub mod device;
+pub mod topology;
+pub mod chroma;
+pub mod commands;
+pub mod transport;
+pub mod retry;
+pub mod trace;
+pub mod capabilities;
+pub mod ipc;
+pub mod registry;
+pub mod worker;
+pub mod store;
+pub mod lighting;
+pub mod fan;
+pub mod curve;
+pub mod power;
+pub mod battery;
+pub mod power_source;
+pub mod smbios;
+#[cfg(target_os = "linux")]
+pub mod hotplug;
+#[cfg(target_os = "linux")]
+pub mod resume;
+#[cfg(test)]
+mod test_util;
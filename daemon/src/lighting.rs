@@ -0,0 +1,48 @@
+use common::lighting::{LightingState, LogoMode, LogoState};
+
+use crate::{chroma::{Led, LedController, LedEffect}, device::RazerDevice, razer::RazerResult, store::DeviceStore};
+
+/// Lighting the user has saved, per device
+pub type LightingStore = DeviceStore<LightingState>;
+
+/// Sends `state` to the device's backlight, and logo if it has one
+pub fn apply_lighting(dev: &mut RazerDevice, state: &LightingState) -> RazerResult<()> {
+    let backlight = LedController::new(Led::Backlight);
+    if let Some(on) = state.on {
+        backlight.set_on(dev, on)?;
+    }
+    if let Some(brightness) = state.brightness {
+        backlight.set_brightness(dev, brightness)?;
+    }
+    if let Some(logo) = state.logo {
+        apply_logo(dev, logo)?;
+    }
+    Ok(())
+}
+
+/// Sets a laptop's lid logo. Only call on laptops with logo control
+pub fn apply_logo(dev: &mut RazerDevice, logo: LogoState) -> RazerResult<()> {
+    let led = LedController::new(Led::Logo);
+    let effect = match logo.mode {
+        LogoMode::Off => return led.set_on(dev, false),
+        LogoMode::Static => LedEffect::Static,
+        LogoMode::Breathing => LedEffect::Breathing
+    };
+    led.set_on(dev, true)?;
+    led.set_effect(dev, effect)?;
+    led.set_brightness(dev, logo.brightness)
+}
+
+pub fn read_logo(dev: &mut RazerDevice) -> RazerResult<LogoState> {
+    let led = LedController::new(Led::Logo);
+    let brightness = led.read_brightness(dev)?;
+    let mode = match led.is_on(dev)? {
+        false => LogoMode::Off,
+        // Blinking and spectrum have no LogoMode, they were set by something else
+        true => match led.read_effect(dev)? {
+            LedEffect::Breathing => LogoMode::Breathing,
+            _ => LogoMode::Static
+        }
+    };
+    Ok(LogoState { mode, brightness })
+}
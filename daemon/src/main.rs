@@ -1,51 +1,75 @@
-use core::time;
-use std::{borrow::BorrowMut, process::exit, thread, time::{Duration, Instant}};
-
-use chroma::set_keyboard_effect;
-use common::effects::{CaptureDisplayEffect, Effect, EffectLayer};
-use device::{RAZER_VENDOR_ID, RazerDevice};
-use hidapi::*;
-use rand::Rng;
-use rusb::*;
-
-use crate::razer::RazerPacket;
-mod razer;
-mod device;
-mod chroma;
-
+use std::{path::Path, process::exit, sync::{Arc, Mutex}, time::Instant};
+
+use common::{config::user_config_dir, devicedb::{self, DeviceDb}, effects::{CaptureDisplayEffect, Colour, Effect, EffectLayer}, hw::SmbiosInfo, lighting::FrameEffect};
+use serde::{Serialize, de::DeserializeOwned};
+use daemon::{chroma::FrameTarget, device::RazerDevice, ipc::{DaemonState, add_device}, power_source::{PowerProfilesFile, spawn_power_watcher}, razer::RazerResult, smbios::{read_smbios, read_smbios_dump}, store::DeviceStore, trace::TraceWriter, transport::TransportKind, worker::WorkerHandle};
+
+/// Loads one of the user's saved settings files. Starts empty if there is none (or it cannot be read)
+fn load_store<T: Serialize + DeserializeOwned>(file: &str) -> DeviceStore<T> {
+    match user_config_dir() {
+        Some(dir) => DeviceStore::load(dir.join(file)).unwrap_or_else(|e| {
+            eprintln!("Error loading saved settings from {}! {:?}", file, e);
+            DeviceStore::default()
+        }),
+        None => DeviceStore::default()
+    }
+}
 
-const TIMEOUT: Duration = Duration::from_secs(1);
+fn main() {
+    // Built in device database, then /etc and the user's overrides on top
+    let (db, errors) = DeviceDb::load_layers(&DeviceDb::default_paths());
+    for e in errors {
+        eprintln!("Error loading device database {}", e);
+    }
+    devicedb::install(db);
 
-struct HotPlugHandler;
+    // RAZER_TRANSPORT=hidapi|usb|hidraw picks how we talk to devices
+    let kind = match std::env::var("RAZER_TRANSPORT").map(|x| x.parse::<TransportKind>()) {
+        Ok(Ok(k)) => k,
+        Ok(Err(e)) => {
+            eprintln!("{}", e);
+            exit(1)
+        },
+        Err(_) => TransportKind::default()
+    };
 
-/*
-impl<T: UsbContext> rusb::Hotplug<T> for HotPlugHandler {
-    fn device_arrived(&mut self, device: Device<T>) {
-        println!("Device added! Scanning");
-        if let Ok(mut ctx) = Context::new() {
-            if let Ok(dev) = RazerDevice::scan_devices(ctx.borrow_mut()) {
-                
+    // RAZER_TRACE=<trace file> records every report to and from every device
+    let trace = match std::env::var("RAZER_TRACE") {
+        Ok(path) => match TraceWriter::create(&path) {
+            Ok(trace) => Some(trace),
+            Err(e) => {
+                eprintln!("Error creating packet trace {}! {:?}", path, e);
+                None
             }
-        }
-    }
+        },
+        Err(_) => None
+    };
 
-    fn device_left(&mut self, device: Device<T>) {
-        println!("Device removed! Scanning");
-        if let Ok(mut ctx) = Context::new() {
-            if let Ok(dev) = RazerDevice::scan_devices(ctx.borrow_mut()) {
-                
-            }
+    // RAZER_SMBIOS=<table dump> reads a captured SMBIOS table rather than this machine's
+    let smbios = match std::env::var("RAZER_SMBIOS") {
+        Ok(path) => read_smbios_dump(Path::new(&path)),
+        Err(_) => read_smbios()
+    }.unwrap_or_else(|e| {
+        eprintln!("Error reading SMBIOS, laptops will use their base model! {}", e);
+        SmbiosInfo::default()
+    });
+
+    // RAZER_REPLAY=<trace file> runs against a recorded trace rather than real hardware
+    let replay = std::env::var("RAZER_REPLAY").ok();
+    let hotplug = replay.is_none();
+    let scan = move |known: &[String]| -> RazerResult<Vec<RazerDevice>> {
+        let mut devices = match &replay {
+            Some(path) => RazerDevice::from_trace(path)?,
+            None => RazerDevice::scan(kind, known)?
+        };
+        devices.iter_mut().for_each(|d| d.refine_model(&smbios));
+        if let Some(trace) = &trace {
+            devices.iter_mut().for_each(|d| d.enable_trace(trace.clone()));
         }
-    }
-}
-*/
-
-fn main() {
-    //rusb::set_log_level(rusb::LogLevel::Debug);
-    //let mut context = Context::new().unwrap();
-    let mut context = HidApi::new().unwrap();
+        Ok(devices)
+    };
 
-    let mut devices = match RazerDevice::scan_devices(&mut context) {
+    let devices = match scan(&[]) {
         Ok(l) => l,
         Err(e) => {
             eprintln!("Error scanning for devices! {:?}", e);
@@ -53,42 +77,69 @@ fn main() {
         }
     };
 
-    /*
-    if rusb::has_hotplug() {
-        thread::spawn(move || {
-            if let Ok(mut reg) = context.register_callback(Some(RAZER_VENDOR_ID), None, None, Box::new(HotPlugHandler{})) {
-                loop {
-                    context.handle_events(None).unwrap();
-                    std::thread::sleep(std::time::Duration::from_secs(1));
-                }
-            } else {
-                println!("Error creating hotplug!");
-            }
-        });
-    } else {
-        println!("Device does not support hotplug :(");
+    let state = Arc::new(Mutex::new(DaemonState::new(load_store("lighting.json"), load_store("laptop.json"))));
+    for dev in devices {
+        add_device(&state, dev);
+    }
+
+    #[cfg(unix)]
+    if let Err(e) = daemon::ipc::start_server(state.clone()) {
+        eprintln!("Error starting IPC server! {}", e);
     }
-    */
 
-    for x in &devices {
-        println!("{:?} - SN: {}", x.device_type, x.serial);
+    #[cfg(target_os = "linux")]
+    if hotplug {
+        use daemon::hotplug::{Hotplug, UDEV_GROUP, UeventSocket};
+        match UeventSocket::open(UDEV_GROUP) {
+            Ok(socket) => { Hotplug::new(Box::new(scan)).spawn(socket, state.clone()); },
+            Err(e) => eprintln!("Error listening for hotplug events, devices plugged in later will be missed! {}", e)
+        }
     }
 
-    let mut laptop: &mut RazerDevice = devices[0].borrow_mut();
-    let mut rng = rand::thread_rng();
+    #[cfg(target_os = "linux")]
+    daemon::resume::spawn_resume_watcher(state.clone());
+
+    // Effect, frame rate and brightness follow AC and battery
+    spawn_power_watcher(state.clone(), PowerProfilesFile::new(user_config_dir().map(|d| d.join("power.json"))));
+
     let mut layer = EffectLayer::create_blank([[true; 15]; 6]);
-    if let Some(mut effect) = CaptureDisplayEffect::new(layer.get_width(), layer.get_height()) {
+    let mut capture = CaptureDisplayEffect::new(layer.get_width(), layer.get_height());
+    if let Some(effect) = capture.as_mut() {
         effect.init(&mut layer);
-        let effect_interval = Duration::from_millis(40);
-        loop {
-            let now = Instant::now();
-            effect.update(&mut layer);
-            set_keyboard_effect(laptop, &layer);
-
-            if let Some(remain) = effect_interval.checked_sub(now.elapsed()) {
-                std::thread::sleep(remain);
+    }
+    loop {
+        let now = Instant::now();
+        let profile = state.lock().unwrap().power_profile;
+        let drawn = match (profile.effect, capture.as_mut()) {
+            (FrameEffect::Capture, Some(effect)) => {
+                effect.update(&mut layer);
+                true
+            },
+            // Nothing to capture from
+            (FrameEffect::Capture, None) => false,
+            (FrameEffect::Static([r, g, b]), _) => {
+                layer.set_matrix_bg(Colour::new_colour(r, g, b));
+                true
+            }
+        };
+        if drawn {
+            // Every device that can show a custom frame gets one, each on its own worker
+            let targets: Vec<(WorkerHandle, FrameTarget)> = state.lock().unwrap().registry.iter()
+                .filter_map(|e| Some((e.worker.handle(), FrameTarget::for_device(e.spec.as_ref()?)?)))
+                .collect();
+            for (worker, target) in targets {
+                let frame = layer.clone();
+                let _ = worker.try_send(move |dev| {
+                    if let Err(e) = target.send(dev, &frame) {
+                        eprintln!("Error updating {}! {:?}", dev.device_type.get_name(), e);
+                    }
+                });
             }
         }
+
+        if let Some(remain) = profile.frame_interval().checked_sub(now.elapsed()) {
+            std::thread::sleep(remain);
+        }
     }
 
     //loop{std::thread::sleep(std::time::Duration::from_millis(100))}
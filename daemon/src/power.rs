@@ -0,0 +1,177 @@
+//! Laptop power modes through the Blade EC.
+//!
+//! The EC keeps the power mode per fan zone, next to the manual fan flag [crate::fan]
+//! uses. Switching mode keeps that flag, so a manual speed or fan curve stays in charge
+//! of the fans. CPU and GPU boost are only read and set in custom mode
+
+use common::{RazerLaptop, laptop::{CpuBoost, GpuBoost, PowerMode}};
+
+use crate::{commands::{self, BoostTarget, GetBoost, GetPowerMode, PowerState, SetBoost, SetPowerMode}, device::RazerDevice, razer::{RazerError, RazerResult}};
+
+fn cpu_boost(level: u8) -> RazerResult<CpuBoost> {
+    match level {
+        0 => Ok(CpuBoost::Low),
+        1 => Ok(CpuBoost::Medium),
+        2 => Ok(CpuBoost::High),
+        3 => Ok(CpuBoost::Boost),
+        _ => Err(RazerError::InvalidResponse)
+    }
+}
+
+fn gpu_boost(level: u8) -> RazerResult<GpuBoost> {
+    match level {
+        0 => Ok(GpuBoost::Low),
+        1 => Ok(GpuBoost::Medium),
+        2 => Ok(GpuBoost::High),
+        _ => Err(RazerError::InvalidResponse)
+    }
+}
+
+fn ec_mode(mode: PowerMode) -> commands::PowerMode {
+    match mode {
+        PowerMode::Balanced => commands::PowerMode::Balanced,
+        PowerMode::Gaming => commands::PowerMode::Gaming,
+        PowerMode::Creator => commands::PowerMode::Creator,
+        PowerMode::Custom { .. } => commands::PowerMode::Custom
+    }
+}
+
+/// Zones the EC keeps a power mode for. That is every fan zone, even when reading the fans
+/// failed while probing, so the database has the final say. Always at least zone 1
+fn power_zones(dev: &RazerDevice, laptop: &RazerLaptop) -> u8 {
+    let spec_zones = dev.spec.as_ref().map(|s| s.fan_zones).unwrap_or(0);
+    laptop.fan_zone_count.max(spec_zones).max(1)
+}
+
+/// The power mode the EC is in. Zones are always set together, so zone 1 speaks for all of them
+pub fn read_power_mode(dev: &mut RazerDevice) -> RazerResult<PowerMode> {
+    Ok(match dev.execute(&GetPowerMode { zone: 1 })?.mode {
+        commands::PowerMode::Balanced => PowerMode::Balanced,
+        commands::PowerMode::Gaming => PowerMode::Gaming,
+        commands::PowerMode::Creator => PowerMode::Creator,
+        commands::PowerMode::Custom => PowerMode::Custom {
+            cpu: cpu_boost(dev.execute(&GetBoost { target: BoostTarget::Cpu })?)?,
+            gpu: gpu_boost(dev.execute(&GetBoost { target: BoostTarget::Gpu })?)?
+        }
+    })
+}
+
+/// Puts every zone into `mode`, keeping each zone's manual fan flag.
+/// Fails with [RazerError::InvalidConfig] if the laptop does not have the mode
+pub fn apply_power_mode(dev: &mut RazerDevice, laptop: &RazerLaptop, mode: PowerMode) -> RazerResult<()> {
+    mode.validate(laptop).map_err(RazerError::InvalidConfig)?;
+    let target = ec_mode(mode);
+    for zone in 1..=power_zones(dev, laptop) {
+        let current = dev.execute(&GetPowerMode { zone })?;
+        if current.mode != target {
+            dev.execute(&SetPowerMode { zone, state: PowerState { mode: target, manual_fan: current.manual_fan } })?;
+        }
+    }
+    if let PowerMode::Custom { cpu, gpu } = mode {
+        dev.execute(&SetBoost { target: BoostTarget::Cpu, level: cpu as u8 })?;
+        dev.execute(&SetBoost { target: BoostTarget::Gpu, level: gpu as u8 })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use common::laptop::BoostLimits;
+
+    use crate::{commands::RazerCommand, test_util::{fake_device, sent_args, test_laptop}, transport::{FakeResponse, FakeTransport}};
+
+    use super::*;
+
+    /// What probing finds on a laptop whose fan speeds could not be read
+    fn without_fans() -> RazerLaptop {
+        RazerLaptop { fan_zone_count: 0, ..test_laptop() }
+    }
+
+    /// Zones SetPowerMode was sent to
+    fn zones_set(fake: &FakeTransport) -> Vec<u8> {
+        sent_args(fake, SetPowerMode::CLASS, SetPowerMode::ID).iter().map(|args| args[1]).collect()
+    }
+
+    fn reply_power_mode(fake: &FakeTransport, mode: commands::PowerMode, manual_fan: bool) {
+        fake.respond_to(GetPowerMode::CLASS, GetPowerMode::ID, FakeResponse::Reply(vec![0x00, 0x01, mode as u8, manual_fan as u8]));
+    }
+
+    fn custom(cpu: CpuBoost, gpu: GpuBoost) -> PowerMode {
+        PowerMode::Custom { cpu, gpu }
+    }
+
+    #[test]
+    fn validates_against_the_model() {
+        let basic = RazerLaptop { has_gaming_mode: false, has_creator_mode: false, max_boost: None, ..test_laptop() };
+        assert!(PowerMode::Balanced.validate(&basic).is_ok());
+        assert!(PowerMode::Gaming.validate(&basic).unwrap_err().contains("gaming"));
+        assert!(PowerMode::Creator.validate(&basic).unwrap_err().contains("creator"));
+        assert!(custom(CpuBoost::Low, GpuBoost::Low).validate(&basic).unwrap_err().contains("custom"));
+
+        let boosted = RazerLaptop { max_boost: Some(BoostLimits { cpu: CpuBoost::High, gpu: GpuBoost::Medium }), ..test_laptop() };
+        assert!(PowerMode::Gaming.validate(&boosted).is_ok() && PowerMode::Creator.validate(&boosted).is_ok());
+        assert!(custom(CpuBoost::High, GpuBoost::Medium).validate(&boosted).is_ok());
+        assert!(custom(CpuBoost::Low, GpuBoost::Low).validate(&boosted).is_ok());
+        assert!(custom(CpuBoost::Boost, GpuBoost::Medium).validate(&boosted).unwrap_err().starts_with("CPU boost Boost"));
+        assert!(custom(CpuBoost::High, GpuBoost::High).validate(&boosted).unwrap_err().starts_with("GPU boost High"));
+    }
+
+    #[test]
+    fn rejected_mode_sends_nothing() {
+        let basic = RazerLaptop { has_gaming_mode: false, max_boost: None, ..test_laptop() };
+        let (mut dev, fake) = fake_device(0x026D);
+        assert!(matches!(apply_power_mode(&mut dev, &basic, PowerMode::Gaming), Err(RazerError::InvalidConfig(_))));
+        assert!(matches!(apply_power_mode(&mut dev, &basic, custom(CpuBoost::Low, GpuBoost::Low)), Err(RazerError::InvalidConfig(_))));
+        assert!(fake.sent().is_empty());
+    }
+
+    #[test]
+    fn custom_mode_sets_boost() {
+        let laptop = RazerLaptop { max_boost: Some(BoostLimits { cpu: CpuBoost::Boost, gpu: GpuBoost::High }), ..test_laptop() };
+        let (mut dev, fake) = fake_device(0x026D);
+        apply_power_mode(&mut dev, &laptop, custom(CpuBoost::Boost, GpuBoost::Low)).unwrap();
+        assert_eq!(sent_args(&fake, SetPowerMode::CLASS, SetPowerMode::ID), vec![vec![0x00, 1, 4, 0], vec![0x00, 2, 4, 0]]);
+        assert_eq!(sent_args(&fake, SetBoost::CLASS, SetBoost::ID), vec![vec![0x00, BoostTarget::Cpu as u8, 3], vec![0x00, BoostTarget::Gpu as u8, 0]]);
+
+        // Other modes leave boost to the EC
+        let (mut dev, fake) = fake_device(0x026D);
+        apply_power_mode(&mut dev, &laptop, PowerMode::Gaming).unwrap();
+        assert!(sent_args(&fake, SetBoost::CLASS, SetBoost::ID).is_empty());
+    }
+
+    #[test]
+    fn mode_change_keeps_manual_fan() {
+        let (mut dev, fake) = fake_device(0x026D);
+        reply_power_mode(&fake, commands::PowerMode::Balanced, true);
+        apply_power_mode(&mut dev, &test_laptop(), PowerMode::Creator).unwrap();
+        assert_eq!(sent_args(&fake, SetPowerMode::CLASS, SetPowerMode::ID), vec![vec![0x00, 1, 2, 1], vec![0x00, 2, 2, 1]]);
+
+        // Already in the mode, so nothing is set
+        let (mut dev, fake) = fake_device(0x026D);
+        reply_power_mode(&fake, commands::PowerMode::Creator, true);
+        apply_power_mode(&mut dev, &test_laptop(), PowerMode::Creator).unwrap();
+        assert!(zones_set(&fake).is_empty());
+    }
+
+    #[test]
+    fn sets_every_zone_in_the_database() {
+        // The Blade 15 has 2 fan zones
+        let (mut dev, fake) = fake_device(0x026D);
+        apply_power_mode(&mut dev, &without_fans(), PowerMode::Gaming).unwrap();
+        assert_eq!(zones_set(&fake), vec![1, 2]);
+    }
+
+    #[test]
+    fn sets_zone_1_without_a_database_entry() {
+        let (mut dev, fake) = fake_device(0xFFFF);
+        apply_power_mode(&mut dev, &without_fans(), PowerMode::Creator).unwrap();
+        assert_eq!(zones_set(&fake), vec![1]);
+    }
+
+    #[test]
+    fn reads_mode_without_fans() {
+        let (mut dev, fake) = fake_device(0x026D);
+        reply_power_mode(&fake, commands::PowerMode::Gaming, false);
+        assert_eq!(read_power_mode(&mut dev).unwrap(), PowerMode::Gaming);
+    }
+}
@@ -0,0 +1,231 @@
+//! Switches lighting between AC, battery and low battery profiles.
+//!
+//! [PowerSourceWatcher] polls the power_supply tree ([crate::battery]) and says when the
+//! [PowerSource] changes, [PowerProfilesFile] says when the user's `power.json` does.
+//! The tree's root comes from [DaemonState::power_supply_root], so a temporary tree can
+//! stand in for sysfs
+
+use std::{path::PathBuf, sync::{Arc, Mutex}, thread::JoinHandle, time::Duration};
+
+use common::{laptop::{ChargeState, PowerSource, PowerSupply}, lighting::PowerProfiles};
+
+use crate::{battery::read_power_supply, ipc::DaemonState};
+
+/// How often [spawn_power_watcher] reads the power supplies
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Works out the power source. Machines without a battery are always on AC
+pub fn power_source(supply: &PowerSupply, low_battery_threshold: u8) -> PowerSource {
+    let on_ac = match supply.ac_online {
+        Some(online) => online,
+        // No mains supply listed, go by whether the batteries are draining
+        None => !supply.batteries.iter().any(|b| b.state == ChargeState::Discharging)
+    };
+    if on_ac || supply.batteries.is_empty() {
+        return PowerSource::Ac
+    }
+    let capacity = supply.batteries.iter().filter_map(|b| b.capacity).min();
+    match capacity {
+        Some(c) if c <= low_battery_threshold => PowerSource::LowBattery,
+        _ => PowerSource::Battery
+    }
+}
+
+pub struct PowerSourceWatcher {
+    root: PathBuf,
+    low_battery_threshold: u8,
+    current: Option<PowerSource>
+}
+
+impl PowerSourceWatcher {
+    pub fn new<P: Into<PathBuf>>(root: P, low_battery_threshold: u8) -> Self {
+        Self { root: root.into(), low_battery_threshold, current: None }
+    }
+
+    /// Battery % at or below which the source is [PowerSource::LowBattery]. Takes effect on the next poll
+    pub fn set_low_battery_threshold(&mut self, threshold: u8) {
+        self.low_battery_threshold = threshold;
+    }
+
+    /// The power source as of the last poll, None before the first
+    pub fn current(&self) -> Option<PowerSource> {
+        self.current
+    }
+
+    /// The power source if it changed since the last poll. The first poll always returns it
+    pub fn poll(&mut self) -> Option<PowerSource> {
+        let source = power_source(&read_power_supply(&self.root), self.low_battery_threshold);
+        if self.current == Some(source) {
+            return None
+        }
+        self.current = Some(source);
+        Some(source)
+    }
+}
+
+/// Parses and validates the contents of a power.json
+pub fn parse_power_profiles(json: &str) -> Result<PowerProfiles, String> {
+    let profiles: PowerProfiles = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    profiles.validate()?;
+    Ok(profiles)
+}
+
+/// The user's `power.json`, read again whenever it changes so edits apply without a restart
+pub struct PowerProfilesFile {
+    path: Option<PathBuf>,
+    /// Set once the file has been polled
+    read: bool,
+    /// What the file held when it was last read. None if it was missing
+    contents: Option<String>
+}
+
+impl PowerProfilesFile {
+    /// None for `path` means there is no file, and the defaults are used throughout
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { path, read: false, contents: None }
+    }
+
+    /// The profiles if the file changed since the last poll. The first poll always returns them.
+    /// A missing file means the defaults. A file that does not load is reported and ignored,
+    /// keeping whatever was in use (The defaults, if it is the first poll)
+    pub fn poll(&mut self) -> Option<PowerProfiles> {
+        let contents = match self.path.as_ref().map(std::fs::read_to_string) {
+            Some(Ok(json)) => Some(json),
+            Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => {
+                eprintln!("Error reading {}! {}", self.path.as_ref()?.display(), e);
+                return None
+            },
+            _ => None
+        };
+        if self.read && contents == self.contents {
+            return None
+        }
+        let first = !self.read;
+        self.read = true;
+        self.contents = contents;
+        let json = match &self.contents {
+            Some(json) => json,
+            None => return Some(PowerProfiles::default())
+        };
+        match parse_power_profiles(json) {
+            Ok(profiles) => Some(profiles),
+            Err(e) => {
+                eprintln!("Error loading {}, keeping the power profiles in use! {}", self.path.as_ref()?.display(), e);
+                first.then(PowerProfiles::default)
+            }
+        }
+    }
+}
+
+/// Applies the profile for the current power source, then keeps it up to date on a background thread.
+/// `profiles` is the user's power.json, picked up again whenever it changes
+pub fn spawn_power_watcher(state: Arc<Mutex<DaemonState>>, mut profiles: PowerProfilesFile) -> JoinHandle<()> {
+    let root = state.lock().unwrap().power_supply_root.clone();
+    let mut in_use = PowerProfiles::default();
+    let mut watcher = PowerSourceWatcher::new(root, in_use.low_battery_threshold);
+    std::thread::spawn(move || loop {
+        let reloaded = match profiles.poll() {
+            Some(p) => {
+                in_use = p;
+                watcher.set_low_battery_threshold(p.low_battery_threshold);
+                true
+            },
+            None => false
+        };
+        let changed = watcher.poll();
+        if let Some(source) = changed {
+            println!("Power source is now {:?}", source);
+        }
+        if let (Some(source), true) = (watcher.current(), reloaded || changed.is_some()) {
+            state.lock().unwrap().set_power_profile(in_use.profile(source));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::test_util::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn watches_the_power_source() {
+        let sysfs = TempDir::new("power-source");
+        sysfs.write("AC0/type", "Mains\n");
+        sysfs.write("AC0/online", "1\n");
+        sysfs.write("BAT0/type", "Battery\n");
+        sysfs.write("BAT0/capacity", "50\n");
+        sysfs.write("BAT0/status", "Charging\n");
+
+        let mut watcher = PowerSourceWatcher::new(sysfs.path(), 20);
+        assert_eq!(watcher.current(), None);
+        assert_eq!(watcher.poll(), Some(PowerSource::Ac));
+        assert_eq!(watcher.poll(), None);
+
+        sysfs.write("AC0/online", "0\n");
+        sysfs.write("BAT0/status", "Discharging\n");
+        assert_eq!(watcher.poll(), Some(PowerSource::Battery));
+        assert_eq!(watcher.current(), Some(PowerSource::Battery));
+
+        sysfs.write("BAT0/capacity", "20\n");
+        assert_eq!(watcher.poll(), Some(PowerSource::LowBattery));
+        watcher.set_low_battery_threshold(10);
+        assert_eq!(watcher.poll(), Some(PowerSource::Battery));
+        watcher.set_low_battery_threshold(0);
+        sysfs.write("BAT0/capacity", "0\n");
+        assert_eq!(watcher.poll(), Some(PowerSource::LowBattery));
+
+        sysfs.write("AC0/online", "1\n");
+        assert_eq!(watcher.poll(), Some(PowerSource::Ac));
+    }
+
+    #[test]
+    fn no_battery_is_ac() {
+        let sysfs = TempDir::new("power-source-desktop");
+        sysfs.write("AC0/type", "Mains\n");
+        sysfs.write("AC0/online", "0\n");
+        assert_eq!(PowerSourceWatcher::new(sysfs.path(), 20).poll(), Some(PowerSource::Ac));
+        assert_eq!(PowerSourceWatcher::new(sysfs.path().join("missing"), 20).poll(), Some(PowerSource::Ac));
+    }
+
+    #[test]
+    fn reloads_power_profiles() {
+        let config = TempDir::new("power-profiles");
+        let path = config.path().join("power.json");
+        let mut file = PowerProfilesFile::new(Some(path.clone()));
+        // Missing to begin with
+        assert_eq!(file.poll(), Some(PowerProfiles::default()));
+        assert_eq!(file.poll(), None);
+
+        config.write("power.json", r#"{"low_battery_threshold": 10}"#);
+        let expected = PowerProfiles { low_battery_threshold: 10, ..Default::default() };
+        assert_eq!(file.poll(), Some(expected));
+        assert_eq!(file.poll(), None);
+
+        // Broken files are ignored, whatever was loaded last stays
+        config.write("power.json", r#"{"ac": {"brightness": null, "effect": "Capture", "fps": 0}}"#);
+        assert_eq!(file.poll(), None);
+        config.write("power.json", "{");
+        assert_eq!(file.poll(), None);
+
+        config.write("power.json", r#"{"low_battery_threshold": 5}"#);
+        assert_eq!(file.poll(), Some(PowerProfiles { low_battery_threshold: 5, ..Default::default() }));
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file.poll(), Some(PowerProfiles::default()));
+    }
+
+    #[test]
+    fn broken_power_profiles_start_with_the_defaults() {
+        let config = TempDir::new("power-profiles-broken");
+        let path = config.write("power.json", r#"{"low_battery_threshold": 100}"#);
+        let mut file = PowerProfilesFile::new(Some(path));
+        assert_eq!(file.poll(), Some(PowerProfiles::default()));
+        assert_eq!(file.poll(), None);
+
+        let mut file = PowerProfilesFile::new(None);
+        assert_eq!(file.poll(), Some(PowerProfiles::default()));
+        assert_eq!(file.poll(), None);
+    }
+}
@@ -1,32 +1,80 @@
-use std::{cmp::min, fs::read};
+use std::{cmp::min, convert::TryFrom};
+
+use common::{hw::DEFAULT_TRANSACTION_ID, ipc::DeviceId};
 
 
 pub type RazerResult<T> = std::result::Result<T, RazerError>;
 
+/// Size of a Razer feature report, including the leading report index
+pub const RAZER_REPORT_LEN: usize = 91;
+/// Max number of argument bytes a single report can carry
+pub const RAZER_MAX_ARGS: usize = 80;
+
+// Byte offsets within a report
+const STATUS_IDX: usize = 1;
+const ID_IDX: usize = 2;
+const REMAINING_IDX: usize = 3;
+const PROTOCOL_IDX: usize = 5;
+const DATA_SIZE_IDX: usize = 6;
+const CLASS_IDX: usize = 7;
+const CMD_IDX: usize = 8;
+const ARGS_IDX: usize = 9;
+const CRC_IDX: usize = ARGS_IDX + RAZER_MAX_ARGS;
+const RES_IDX: usize = CRC_IDX + 1;
+
 
 #[derive(Debug)]
 pub enum RazerError {
+    #[cfg(feature = "usb")]
     UsbError(rusb::Error),
+    #[cfg(feature = "hidapi")]
     HidError(hidapi::HidError),
+    IoError(std::io::Error),
+    /// Transport failed in a way that only has a description (e.g. a replayed failure)
+    TransportError(String),
     CmdNotSupported,
     ECBusy,
     ECTimeout,
     InvalidResponse,
-    ECFailure
+    ECFailure,
+    /// The transport was closed, or the device went away
+    TransportClosed,
+    /// Report was not [RAZER_REPORT_LEN] bytes long
+    InvalidLength(usize),
+    /// Status byte is not one we know about
+    UnknownStatus(u8),
+    /// data_size claims more than [RAZER_MAX_ARGS] bytes of args
+    InvalidDataSize(u8),
+    /// CRC in the report does not match its contents
+    BadCrc { expected: u8, actual: u8 },
+    /// A packet trace file could not be parsed
+    InvalidTrace(String),
+    /// A settings file could not be parsed
+    InvalidConfig(String),
+    /// No device has this ID (It was never there, or has been unplugged)
+    NoSuchDevice(DeviceId)
 }
 
+#[cfg(feature = "usb")]
 impl From<rusb::Error> for RazerError {
     fn from(x: rusb::Error) -> Self {
         Self::UsbError(x)
     }
 }
 
+#[cfg(feature = "hidapi")]
 impl From<hidapi::HidError> for RazerError {
     fn from(x: hidapi::HidError) -> Self {
         Self::HidError(x)
     }
 }
 
+impl From<std::io::Error> for RazerError {
+    fn from(x: std::io::Error) -> Self {
+        Self::IoError(x)
+    }
+}
+
 
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -39,8 +87,23 @@ pub enum RazerCmdStatus {
     NotSupported = 5
 }
 
+impl TryFrom<u8> for RazerCmdStatus {
+    type Error = RazerError;
+
+    fn try_from(x: u8) -> RazerResult<Self> {
+        match x {
+            0 => Ok(Self::New),
+            1 => Ok(Self::Busy),
+            2 => Ok(Self::Successful),
+            3 => Ok(Self::Failure),
+            4 => Ok(Self::Timeout),
+            5 => Ok(Self::NotSupported),
+            x => Err(RazerError::UnknownStatus(x))
+        }
+    }
+}
+
 
-#[repr(C, packed)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct RazerPacket {
     report_index: u8,
@@ -51,37 +114,46 @@ pub struct RazerPacket {
     pub data_size: u8,
     pub cmd_class: u8,
     pub cmd_id: u8,
-    pub args: [u8; 80],
+    pub args: [u8; RAZER_MAX_ARGS],
     pub crc: u8,
     pub _res: u8
 }
 
 impl std::fmt::Display for RazerPacket {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("Razer packet {{ cmd: {:02X}, class: {:02X}, args: {:02X?}, status: {:?}}}", self.cmd_id, self.cmd_class, &self.args[0..self.data_size as usize], self.status))
+        let size = min(RAZER_MAX_ARGS, self.data_size as usize);
+        f.write_fmt(format_args!("Razer packet {{ cmd: {:02X}, class: {:02X}, args: {:02X?}, status: {:?}}}", self.cmd_id, self.cmd_class, &self.args[0..size], self.status))
     }
 }
 
 impl RazerPacket {
 
+    /// XOR of everything between the transaction ID and the CRC (remaining..args)
+    fn calc_crc(raw: &[u8; RAZER_REPORT_LEN]) -> u8 {
+        raw[REMAINING_IDX..CRC_IDX].iter().fold(0, |crc, x| crc ^ x)
+    }
+
     pub fn set_crc(&mut self) {
-        let raw = unsafe { ::std::slice::from_raw_parts((self as *const  Self) as *const u8, ::std::mem::size_of::<Self>()) };
-        self.crc = 0;
-        raw[2..88].iter().for_each(|x| self.crc ^= *x);
+        self.crc = Self::calc_crc(&self.encode());
     }
 
     pub fn new(class: u8, cmd: u8, args: &[u8]) -> Self {
-        let max = min(80, args.len());
+        Self::new_with_id(DEFAULT_TRANSACTION_ID, class, cmd, args)
+    }
+
+    /// Like [RazerPacket::new], but for devices that want a specific transaction ID
+    pub fn new_with_id(id: u8, class: u8, cmd: u8, args: &[u8]) -> Self {
+        let max = min(RAZER_MAX_ARGS, args.len());
         let mut tmp = Self {
             report_index: 0,
             status: RazerCmdStatus::New,
-            id: 0xFF,
+            id,
             remaining: 0x00,
             protocol_type: 0x00,
             data_size: max as u8,
             cmd_class: class,
             cmd_id: cmd,
-            args: [0; 80],
+            args: [0; RAZER_MAX_ARGS],
             crc: 0,
             _res: 0x00
         };
@@ -90,22 +162,181 @@ impl RazerPacket {
         tmp
     }
 
+    /// Checks if `other` is the reply to this packet. Devices echo back the transaction ID,
+    /// so a reply for another ID is not ours
     pub const fn is_same(&self, other: &Self) -> bool {
-        self.remaining == other.remaining && self.cmd_class == other.cmd_class && self.id == other.id
+        self.remaining == other.remaining && self.cmd_class == other.cmd_class && self.cmd_id == other.cmd_id && self.id == other.id
     }
 
     pub fn set_args(&mut self, args: &[u8]) {
-        let max = min(80, args.len());
+        let max = min(RAZER_MAX_ARGS, args.len());
         self.args[0..max].copy_from_slice(&args[0..max]);
         self.data_size = max as u8;
         self.set_crc();
     }
 
-    pub fn create_packet(&self) -> &[u8] {
-        unsafe { ::std::slice::from_raw_parts((self as *const Self) as *const u8, ::std::mem::size_of::<Self>()) }
+    /// Serializes the packet into a feature report, exactly as it goes on the wire
+    pub fn encode(&self) -> [u8; RAZER_REPORT_LEN] {
+        let mut raw = [0u8; RAZER_REPORT_LEN];
+        raw[0] = self.report_index;
+        raw[STATUS_IDX] = self.status as u8;
+        raw[ID_IDX] = self.id;
+        raw[REMAINING_IDX..PROTOCOL_IDX].copy_from_slice(&self.remaining.to_be_bytes());
+        raw[PROTOCOL_IDX] = self.protocol_type;
+        raw[DATA_SIZE_IDX] = self.data_size;
+        raw[CLASS_IDX] = self.cmd_class;
+        raw[CMD_IDX] = self.cmd_id;
+        raw[ARGS_IDX..CRC_IDX].copy_from_slice(&self.args);
+        raw[CRC_IDX] = self.crc;
+        raw[RES_IDX] = self._res;
+        raw
+    }
+
+    /// Parses a feature report read back from a device.
+    ///
+    /// Anything that is not a well formed report (wrong length, unknown status,
+    /// oversized data_size or bad CRC) is rejected rather than trusted
+    pub fn decode(buf: &[u8]) -> RazerResult<Self> {
+        if buf.len() != RAZER_REPORT_LEN {
+            return Err(RazerError::InvalidLength(buf.len()))
+        }
+        let mut raw = [0u8; RAZER_REPORT_LEN];
+        raw.copy_from_slice(buf);
+
+        let status = RazerCmdStatus::try_from(raw[STATUS_IDX])?;
+        let data_size = raw[DATA_SIZE_IDX];
+        if data_size as usize > RAZER_MAX_ARGS {
+            return Err(RazerError::InvalidDataSize(data_size))
+        }
+        let expected = Self::calc_crc(&raw);
+        if raw[CRC_IDX] != expected {
+            return Err(RazerError::BadCrc { expected, actual: raw[CRC_IDX] })
+        }
+
+        let mut args = [0u8; RAZER_MAX_ARGS];
+        args.copy_from_slice(&raw[ARGS_IDX..CRC_IDX]);
+        Ok(Self {
+            report_index: raw[0],
+            status,
+            id: raw[ID_IDX],
+            remaining: u16::from_be_bytes([raw[REMAINING_IDX], raw[REMAINING_IDX+1]]),
+            protocol_type: raw[PROTOCOL_IDX],
+            data_size,
+            cmd_class: raw[CLASS_IDX],
+            cmd_id: raw[CMD_IDX],
+            args,
+            crc: raw[CRC_IDX],
+            _res: raw[RES_IDX]
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    fn random_packet(rng: &mut StdRng) -> RazerPacket {
+        let len = rng.gen_range(0..=RAZER_MAX_ARGS);
+        let args: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+        let mut packet = RazerPacket::new_with_id(rng.gen(), rng.gen(), rng.gen(), &args);
+        packet.status = RazerCmdStatus::try_from(rng.gen_range(0..=5)).unwrap();
+        packet.remaining = rng.gen();
+        packet.protocol_type = rng.gen();
+        packet.set_crc();
+        packet
+    }
+
+    /// A valid report with `raw[idx] = value` and the CRC fixed up afterwards
+    fn with_byte(idx: usize, value: u8) -> [u8; RAZER_REPORT_LEN] {
+        let mut raw = RazerPacket::new(0x00, 0x82, &[]).encode();
+        raw[idx] = value;
+        raw[CRC_IDX] = RazerPacket::calc_crc(&raw);
+        raw
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = StdRng::seed_from_u64(0x1532);
+        for _ in 0..1000 {
+            let packet = random_packet(&mut rng);
+            let raw = packet.encode();
+            assert_eq!(raw.len(), RAZER_REPORT_LEN);
+            let decoded = RazerPacket::decode(&raw).unwrap();
+            assert_eq!(decoded, packet);
+            assert_eq!(decoded.encode(), raw);
+        }
+    }
+
+    #[test]
+    fn encode_layout() {
+        let mut packet = RazerPacket::new_with_id(0x3F, 0x03, 0x0B, &[0xFF, 0x02, 0x00, 0x0F]);
+        packet.remaining = 0x0102;
+        packet.set_crc();
+        let raw = packet.encode();
+        assert_eq!(&raw[..ARGS_IDX + 4], &[0x00, 0x00, 0x3F, 0x01, 0x02, 0x00, 0x04, 0x03, 0x0B, 0xFF, 0x02, 0x00, 0x0F]);
+        assert!(raw[ARGS_IDX + 4..CRC_IDX].iter().all(|&b| b == 0));
+        assert_eq!(raw[CRC_IDX], 0x01 ^ 0x02 ^ 0x04 ^ 0x03 ^ 0x0B ^ 0xFF ^ 0x02 ^ 0x0F);
+        assert_eq!(raw[RES_IDX], 0);
     }
 
-    pub fn from_raw(buf: &[u8; 91]) -> Self {
-        unsafe { std::ptr::read(buf.as_ptr() as *const Self) }
+    #[test]
+    fn set_args_truncates() {
+        let packet = RazerPacket::new(0x0F, 0x03, &[0xAA; RAZER_MAX_ARGS + 10]);
+        assert_eq!(packet.data_size as usize, RAZER_MAX_ARGS);
+        assert!(RazerPacket::decode(&packet.encode()).is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rejects_bad_crc() {
+        let mut rng = StdRng::seed_from_u64(91);
+        for _ in 0..100 {
+            let mut raw = random_packet(&mut rng).encode();
+            let expected = raw[CRC_IDX];
+            raw[CRC_IDX] ^= rng.gen_range(1..=255u8);
+            match RazerPacket::decode(&raw) {
+                Err(RazerError::BadCrc { expected: e, actual }) => {
+                    assert_eq!(e, expected);
+                    assert_eq!(actual, raw[CRC_IDX]);
+                },
+                other => panic!("expected BadCrc, got {:?}", other)
+            }
+        }
+        // A flipped arg bit breaks the CRC just the same
+        let mut raw = RazerPacket::new(0x00, 0x82, &[0x01]).encode();
+        raw[ARGS_IDX + 10] ^= 0x40;
+        assert!(matches!(RazerPacket::decode(&raw), Err(RazerError::BadCrc { .. })));
+    }
+
+    #[test]
+    fn rejects_unknown_status() {
+        for status in 0..=u8::MAX {
+            let res = RazerPacket::decode(&with_byte(STATUS_IDX, status));
+            if status <= RazerCmdStatus::NotSupported as u8 {
+                assert_eq!(res.unwrap().status as u8, status);
+            } else {
+                assert!(matches!(res, Err(RazerError::UnknownStatus(s)) if s == status), "status {}", status);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_data_size() {
+        assert!(RazerPacket::decode(&with_byte(DATA_SIZE_IDX, RAZER_MAX_ARGS as u8)).is_ok());
+        for size in RAZER_MAX_ARGS as u8 + 1..=u8::MAX {
+            let res = RazerPacket::decode(&with_byte(DATA_SIZE_IDX, size));
+            assert!(matches!(res, Err(RazerError::InvalidDataSize(s)) if s == size), "data_size {}", size);
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let raw = RazerPacket::new(0x00, 0x82, &[]).encode();
+        let mut long = raw.to_vec();
+        long.push(0);
+        for buf in [&raw[..0], &raw[..1], &raw[..RAZER_REPORT_LEN - 1], &long[..], &[0u8; 90][..]] {
+            assert!(matches!(RazerPacket::decode(buf), Err(RazerError::InvalidLength(l)) if l == buf.len()));
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use common::{DeviceCapabilities, devicedb::DeviceSpec, hw::{DeviceInfo, FirmwareVersion}, ipc::DeviceId};
+
+use crate::{capabilities::CapabilityCache, commands::GetFirmware, device::RazerDevice, razer::{RazerError, RazerResult}, worker::{DeviceWorker, WorkerHandle}};
+
+/// A device whose info and capabilities have been read, ready for [DeviceRegistry::add].
+///
+/// That takes a handful of round trips, so it is done before locking anything the
+/// registry sits behind
+pub struct ProbedDevice {
+    device: RazerDevice,
+    info: DeviceInfo,
+    caps: DeviceCapabilities
+}
+
+impl ProbedDevice {
+    /// Reads the device's firmware, then its capabilities through `cache`
+    pub fn probe(mut device: RazerDevice, cache: &CapabilityCache) -> Self {
+        // Devices that cannot tell us their firmware are still usable, they all get lumped in as v0.0
+        let firmware = device.execute(&GetFirmware).unwrap_or(FirmwareVersion { major: 0, minor: 0 });
+        let info = device.info_with_firmware(firmware);
+        let caps = cache.get_or_probe(&mut device, firmware);
+        Self { device, info, caps }
+    }
+}
+
+/// A connected device and what the daemon knows about it
+pub struct DeviceEntry {
+    pub id: DeviceId,
+    pub info: DeviceInfo,
+    pub caps: DeviceCapabilities,
+    /// Device database entry as it was when the device was added, None for unknown devices
+    pub spec: Option<DeviceSpec>,
+    /// Copied from the device before it moved onto its worker
+    pub bus_path: String,
+    pub worker: DeviceWorker
+}
+
+/// Every device the daemon is currently driving.
+///
+/// IDs are handed out in order and never reused, so a client holding the ID
+/// of a device that went away cannot end up talking to a different one
+#[derive(Default)]
+pub struct DeviceRegistry {
+    next_id: DeviceId,
+    entries: Vec<DeviceEntry>,
+    cache: CapabilityCache
+}
+
+impl DeviceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cache to probe new devices with. Shared with the registry, so it can be used without holding it
+    pub fn cache(&self) -> CapabilityCache {
+        self.cache.clone()
+    }
+
+    /// Starts tracking a device, handing it to its own worker thread
+    pub fn add(&mut self, probed: ProbedDevice) -> DeviceId {
+        let ProbedDevice { device, info, caps } = probed;
+        let id = self.next_id;
+        self.next_id += 1;
+        let spec = device.spec.clone();
+        let bus_path = device.bus_path.clone();
+        self.entries.push(DeviceEntry { id, info, caps, spec, bus_path, worker: DeviceWorker::spawn(device) });
+        id
+    }
+
+    /// Stops tracking a device. Its worker stops (and closes the device) once the entry is dropped
+    pub fn remove(&mut self, id: DeviceId) -> Option<DeviceEntry> {
+        let idx = self.entries.iter().position(|e| e.id == id)?;
+        Some(self.entries.remove(idx))
+    }
+
+    pub fn get(&self, id: DeviceId) -> Option<&DeviceEntry> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+
+    /// Handle to a device's worker, or [RazerError::NoSuchDevice]
+    pub fn worker(&self, id: DeviceId) -> RazerResult<WorkerHandle> {
+        self.get(id).map(|e| e.worker.handle()).ok_or(RazerError::NoSuchDevice(id))
+    }
+
+    /// True if a device with the same path (or product ID and serial, for devices without one) is tracked
+    pub fn contains(&self, device: &RazerDevice) -> bool {
+        self.entries.iter().any(|e| {
+            if device.path.is_empty() {
+                e.info.product_id == device.device_type.get_id() && e.info.serial == device.serial
+            } else {
+                e.info.path == device.path
+            }
+        })
+    }
+
+    /// Paths and bus paths of every tracked device, for scans to leave alone
+    pub fn known_paths(&self) -> Vec<String> {
+        self.entries.iter()
+            .flat_map(|e| [e.info.path.clone(), e.bus_path.clone()])
+            .filter(|p| !p.is_empty())
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceEntry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{commands::RazerCommand, razer::RazerCmdStatus, test_util::fake_device, transport::{FakeResponse, FakeTransport}};
+
+    use super::*;
+
+    fn firmware_requests(fake: &FakeTransport) -> usize {
+        fake.sent().iter().filter(|raw| raw[7..9] == [GetFirmware::CLASS, GetFirmware::ID]).count()
+    }
+
+    #[test]
+    fn asks_for_firmware_once() {
+        let (dev, fake) = fake_device(0x026D);
+        fake.respond_to(GetFirmware::CLASS, GetFirmware::ID, FakeResponse::Reply(vec![1, 2]));
+        let mut registry = DeviceRegistry::new();
+        let id = registry.add(ProbedDevice::probe(dev, &registry.cache()));
+        let entry = registry.get(id).unwrap();
+        assert_eq!(entry.info.firmware, FirmwareVersion { major: 1, minor: 2 });
+        assert_eq!(entry.caps.common.firmware, entry.info.firmware);
+        assert_eq!(firmware_requests(&fake), 1);
+    }
+
+    #[test]
+    fn adds_devices_without_firmware() {
+        let (dev, fake) = fake_device(0x026D);
+        fake.respond_to(GetFirmware::CLASS, GetFirmware::ID, FakeResponse::Status(RazerCmdStatus::NotSupported));
+        let mut registry = DeviceRegistry::new();
+        let id = registry.add(ProbedDevice::probe(dev, &registry.cache()));
+        assert_eq!(registry.get(id).unwrap().info.firmware, FirmwareVersion { major: 0, minor: 0 });
+        assert_eq!(firmware_requests(&fake), 1);
+    }
+
+    #[test]
+    fn missing_devices_fail_cleanly() {
+        let (dev, fake) = fake_device(0x026D);
+        let mut registry = DeviceRegistry::new();
+        let id = registry.add(ProbedDevice::probe(dev, &registry.cache()));
+        assert!(matches!(registry.worker(id + 1), Err(RazerError::NoSuchDevice(x)) if x == id + 1));
+
+        // A handle taken before the device went away stops working, and the device is closed
+        let handle = registry.worker(id).unwrap();
+        drop(registry.remove(id));
+        assert!(fake.is_closed());
+        assert!(matches!(registry.worker(id), Err(RazerError::NoSuchDevice(_))));
+        assert!(matches!(handle.call(|_| ()), Err(RazerError::TransportClosed)));
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,138 @@
+//! Notices the machine coming back from suspend.
+//!
+//! CLOCK_BOOTTIME keeps counting while the machine is suspended and CLOCK_MONOTONIC does not,
+//! so the gap between them grows by however long it slept. Polling that gap needs no
+//! logind or D-Bus, and [ResumeDetector] takes a [SleepClock] so a suspend can be faked
+
+use std::{sync::{Arc, Mutex}, thread::JoinHandle, time::Duration};
+
+use crate::ipc::DaemonState;
+
+/// How often [spawn_resume_watcher] checks the clocks
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Shortest gap that counts as a suspend. The clocks drift apart a little on their own
+pub const MIN_SLEEP: Duration = Duration::from_secs(1);
+
+pub trait SleepClock: Send {
+    /// Time since boot, not counting suspend
+    fn monotonic(&self) -> Duration;
+    /// Time since boot, suspend included
+    fn boottime(&self) -> Duration;
+}
+
+/// The kernel's clocks
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemSleepClock;
+
+fn clock(id: libc::clockid_t) -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(id, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+impl SleepClock for SystemSleepClock {
+    fn monotonic(&self) -> Duration {
+        clock(libc::CLOCK_MONOTONIC)
+    }
+
+    fn boottime(&self) -> Duration {
+        clock(libc::CLOCK_BOOTTIME)
+    }
+}
+
+pub struct ResumeDetector<C: SleepClock> {
+    clock: C,
+    /// Suspended time as of the last poll
+    asleep: Duration
+}
+
+impl<C: SleepClock> ResumeDetector<C> {
+    pub fn new(clock: C) -> Self {
+        let asleep = clock.boottime().saturating_sub(clock.monotonic());
+        Self { clock, asleep }
+    }
+
+    /// How long the machine was suspended since the last poll, None if it was not
+    pub fn poll(&mut self) -> Option<Duration> {
+        let asleep = self.clock.boottime().saturating_sub(self.clock.monotonic());
+        let slept = asleep.saturating_sub(self.asleep);
+        self.asleep = asleep;
+        if slept >= MIN_SLEEP { Some(slept) } else { None }
+    }
+}
+
+/// Reapplies every device's saved settings after each suspend, on a background thread
+pub fn spawn_resume_watcher(state: Arc<Mutex<DaemonState>>) -> JoinHandle<()> {
+    let mut detector = ResumeDetector::new(SystemSleepClock);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(POLL_INTERVAL);
+        if let Some(slept) = detector.poll() {
+            println!("Resumed after {}s asleep, restoring device settings", slept.as_secs());
+            state.lock().unwrap().restore_all();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (monotonic, boottime), shared with the detector
+    #[derive(Clone, Default)]
+    struct FakeSleepClock(Arc<Mutex<(Duration, Duration)>>);
+
+    impl FakeSleepClock {
+        fn run(&self, d: Duration) {
+            let mut t = self.0.lock().unwrap();
+            t.0 += d;
+            t.1 += d;
+        }
+
+        fn sleep(&self, d: Duration) {
+            self.0.lock().unwrap().1 += d;
+        }
+    }
+
+    impl SleepClock for FakeSleepClock {
+        fn monotonic(&self) -> Duration {
+            self.0.lock().unwrap().0
+        }
+
+        fn boottime(&self) -> Duration {
+            self.0.lock().unwrap().1
+        }
+    }
+
+    #[test]
+    fn notices_suspend() {
+        let clock = FakeSleepClock::default();
+        // Booted a while ago, and slept before the daemon started
+        clock.run(Duration::from_secs(600));
+        clock.sleep(Duration::from_secs(60));
+        let mut detector = ResumeDetector::new(clock.clone());
+        assert_eq!(detector.poll(), None);
+
+        clock.run(POLL_INTERVAL);
+        assert_eq!(detector.poll(), None);
+        clock.sleep(Duration::from_secs(3600));
+        clock.run(POLL_INTERVAL);
+        assert_eq!(detector.poll(), Some(Duration::from_secs(3600)));
+        assert_eq!(detector.poll(), None);
+
+        clock.sleep(MIN_SLEEP);
+        assert_eq!(detector.poll(), Some(MIN_SLEEP));
+    }
+
+    #[test]
+    fn drift_is_not_suspend() {
+        let clock = FakeSleepClock::default();
+        let mut detector = ResumeDetector::new(clock.clone());
+        // Each poll only sees the drift since the one before, so it never adds up to a suspend
+        for _ in 0..10 {
+            clock.run(POLL_INTERVAL);
+            clock.sleep(Duration::from_millis(600));
+            assert_eq!(detector.poll(), None);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use common::devicedb::{DeviceSpec, RetryProfile};
+
+/// Where [RazerDevice](crate::device::RazerDevice) gets the time from, and how it waits.
+/// Swap in a [FakeClock] to run retry logic without actually sleeping
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, d: Duration);
+}
+
+/// The real clock
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, d: Duration) {
+        std::thread::sleep(d)
+    }
+}
+
+/// Clock that only moves when something sleeps on it. Clones share the same time
+#[derive(Debug, Clone)]
+pub struct FakeClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>
+}
+
+impl FakeClock {
+    pub fn new() -> Self {
+        Self { start: Instant::now(), elapsed: Arc::new(Mutex::new(Duration::from_secs(0))) }
+    }
+
+    /// Total time slept (or advanced) so far
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+
+    pub fn advance(&self, d: Duration) {
+        *self.elapsed.lock().unwrap() += d;
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(&self, d: Duration) {
+        self.advance(d)
+    }
+}
+
+/// How hard [RazerDevice](crate::device::RazerDevice) tries to get a command through
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times the packet gets sent before giving up
+    pub attempts: u32,
+    /// Wait between sending a report and reading the reply
+    pub report_delay: Duration,
+    /// Wait after the first busy reply
+    pub busy_backoff: Duration,
+    /// Every further busy reply multiplies the wait by this
+    pub busy_backoff_factor: u32,
+    /// Longest a single busy wait can get
+    pub max_busy_backoff: Duration,
+    /// Give up once this much time has passed, even with attempts left
+    pub deadline: Option<Duration>
+}
+
+impl RetryPolicy {
+    /// Good enough for wired keyboards and laptops
+    pub const DEFAULT: Self = Self {
+        attempts: 3,
+        report_delay: Duration::from_micros(400),
+        busy_backoff: Duration::from_micros(1000),
+        busy_backoff_factor: 1,
+        max_busy_backoff: Duration::from_micros(1000),
+        deadline: None
+    };
+
+    /// Wireless dongles have to relay everything to the device, so give them a lot longer
+    pub const WIRELESS: Self = Self {
+        attempts: 10,
+        report_delay: Duration::from_millis(2),
+        busy_backoff: Duration::from_millis(5),
+        busy_backoff_factor: 2,
+        max_busy_backoff: Duration::from_millis(50),
+        deadline: Some(Duration::from_millis(500))
+    };
+
+    /// For things sent many times a second (Custom frames), where a dropped
+    /// frame is better than holding up the next one
+    pub const FAST: Self = Self {
+        attempts: 1,
+        report_delay: Duration::from_micros(400),
+        busy_backoff: Duration::from_micros(0),
+        busy_backoff_factor: 1,
+        max_busy_backoff: Duration::from_micros(0),
+        deadline: None
+    };
+
+    /// Policy to use for a model unless a command asks for something else.
+    /// Devices missing from the database get [RetryPolicy::DEFAULT]
+    pub fn for_spec(spec: Option<&DeviceSpec>) -> Self {
+        match spec.map(|s| s.retry) {
+            Some(RetryProfile::Wireless) => Self::WIRELESS,
+            Some(RetryProfile::Wired) | None => Self::DEFAULT
+        }
+    }
+
+    /// How long to wait after the `busy_count`th busy reply (Starting at 0)
+    pub fn busy_wait(&self, busy_count: u32) -> Duration {
+        let factor = self.busy_backoff_factor.checked_pow(busy_count).unwrap_or(u32::MAX);
+        self.busy_backoff.checked_mul(factor).unwrap_or(self.max_busy_backoff).min(self.max_busy_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::devicedb::DeviceDb;
+
+    use crate::{commands::{GetFirmware, RazerCommand, SetCustomFrameRow}, device::RazerDevice, razer::{RazerCmdStatus, RazerError}, test_util::fake_device, transport::{FakeResponse, FakeTransport}};
+
+    use super::*;
+
+    fn device_with(policy: RetryPolicy) -> (RazerDevice, FakeTransport, FakeClock) {
+        let (mut dev, fake) = fake_device(0xFFFF);
+        let clock = FakeClock::new();
+        dev.retry = policy;
+        dev.set_clock(Arc::new(clock.clone()));
+        (dev, fake, clock)
+    }
+
+    fn ms(x: u64) -> Duration {
+        Duration::from_millis(x)
+    }
+
+    #[test]
+    fn busy_wait_curve() {
+        let waits: Vec<Duration> = (0..6).map(|n| RetryPolicy::WIRELESS.busy_wait(n)).collect();
+        assert_eq!(waits, vec![ms(5), ms(10), ms(20), ms(40), ms(50), ms(50)]);
+        assert!((0..6).all(|n| RetryPolicy::DEFAULT.busy_wait(n) == ms(1)));
+        // No overflow however many busy replies come back
+        assert_eq!(RetryPolicy::WIRELESS.busy_wait(u32::MAX), ms(50));
+    }
+
+    #[test]
+    fn retries_until_success() {
+        let (mut dev, fake, clock) = device_with(RetryPolicy::DEFAULT);
+        fake.push_response(FakeResponse::Status(RazerCmdStatus::Timeout));
+        fake.push_response(FakeResponse::ShortRead(4));
+        assert!(dev.execute(&GetFirmware).is_ok());
+        assert_eq!(fake.sent().len(), 3);
+        assert_eq!(clock.elapsed(), RetryPolicy::DEFAULT.report_delay * 3);
+    }
+
+    #[test]
+    fn gives_up_after_attempts() {
+        let (mut dev, fake, _) = device_with(RetryPolicy::DEFAULT);
+        fake.respond_to(GetFirmware::CLASS, GetFirmware::ID, FakeResponse::Status(RazerCmdStatus::Failure));
+        assert!(matches!(dev.execute(&GetFirmware), Err(RazerError::ECFailure)));
+        assert_eq!(fake.sent().len(), RetryPolicy::DEFAULT.attempts as usize);
+    }
+
+    #[test]
+    fn busy_backoff() {
+        let (mut dev, fake, clock) = device_with(RetryPolicy::WIRELESS);
+        (0..3).for_each(|_| fake.push_response(FakeResponse::Status(RazerCmdStatus::Busy)));
+        assert!(dev.execute(&GetFirmware).is_ok());
+        assert_eq!(fake.sent().len(), 4);
+        // 4 report delays, then 5 + 10 + 20ms backing off
+        assert_eq!(clock.elapsed(), ms(2) * 4 + ms(35));
+    }
+
+    #[test]
+    fn deadline() {
+        let policy = RetryPolicy { attempts: 100, deadline: Some(ms(100)), ..RetryPolicy::WIRELESS };
+        let (mut dev, fake, clock) = device_with(policy);
+        fake.respond_to(GetFirmware::CLASS, GetFirmware::ID, FakeResponse::Status(RazerCmdStatus::Busy));
+        assert!(matches!(dev.execute(&GetFirmware), Err(RazerError::ECBusy)));
+        // Attempts end at 7, 19, 41, 83 and 135ms. The last one starts before the deadline
+        assert_eq!(fake.sent().len(), 5);
+        assert_eq!(clock.elapsed(), ms(135));
+    }
+
+    #[test]
+    fn command_policy_wins() {
+        let (mut dev, fake, _) = device_with(RetryPolicy::WIRELESS);
+        fake.respond_to(SetCustomFrameRow::CLASS, SetCustomFrameRow::ID, FakeResponse::Status(RazerCmdStatus::Busy));
+        let row = SetCustomFrameRow { row: 0, start_col: 0, colours: vec![[0xFF; 3]; 16] };
+        assert!(matches!(dev.execute(&row), Err(RazerError::ECBusy)));
+        assert_eq!(fake.sent().len(), RetryPolicy::FAST.attempts as usize);
+    }
+
+    #[test]
+    fn policy_from_database() {
+        let db = DeviceDb::parse(r#"{"devices": [
+            {"product_id": "0x0001", "name": "Wired", "class": "mouse"},
+            {"product_id": "0x0002", "name": "Wireless", "class": "mouse", "retry": "wireless"}
+        ]}"#, "test").unwrap();
+        assert_eq!(RetryPolicy::for_spec(db.get(0x0001)), RetryPolicy::DEFAULT);
+        assert_eq!(RetryPolicy::for_spec(db.get(0x0002)), RetryPolicy::WIRELESS);
+        assert_eq!(RetryPolicy::for_spec(None), RetryPolicy::DEFAULT);
+        assert!(DeviceDb::parse(r#"{"devices": [{"product_id": 1, "name": "X", "class": "mouse", "retry": "bluetooth"}]}"#, "test").is_err());
+    }
+}
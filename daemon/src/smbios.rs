@@ -0,0 +1,111 @@
+//! Reads what the laptop says it is from its SMBIOS (DMI) tables.
+//!
+//! Used to pick between Blade variants sharing a product ID, see [DeviceSpec::refine](common::devicedb::DeviceSpec::refine),
+//! and for the laptop's serial number.
+//! [read_smbios_dump] takes a captured table (e.g. a copy of /sys/firmware/dmi/tables/DMI),
+//! so variant detection can be checked without the laptop in question
+
+use std::{io, path::Path};
+
+use common::hw::SmbiosInfo;
+use smbioslib::{SMBiosBaseboardInformation, SMBiosData, SMBiosSystemInformation, load_smbios_data_from_file, table_load_from_device};
+
+/// Pulls the strings we care about out of a table
+pub fn parse_smbios(data: &SMBiosData) -> SmbiosInfo {
+    let system = data.first::<SMBiosSystemInformation>();
+    let board = data.first::<SMBiosBaseboardInformation>();
+    SmbiosInfo {
+        product_name: system.as_ref().and_then(|s| s.product_name()).unwrap_or_default(),
+        sku: system.as_ref().and_then(|s| s.sku_number()).unwrap_or_default(),
+        board_version: board.as_ref().and_then(|b| b.version()).unwrap_or_default(),
+        serial: system.as_ref().and_then(|s| s.serial_number()).unwrap_or_default()
+    }
+}
+
+/// Reads this machine's tables. Needs root on Linux
+pub fn read_smbios() -> io::Result<SmbiosInfo> {
+    table_load_from_device().map(|data| parse_smbios(&data))
+}
+
+/// Reads a raw table dump
+pub fn read_smbios_dump(path: &Path) -> io::Result<SmbiosInfo> {
+    load_smbios_data_from_file(path).map(|data| parse_smbios(&data))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use common::devicedb::DeviceDb;
+
+    use crate::test_util::TempDir;
+
+    use super::*;
+
+    /// A structure: header, formatted area and string set
+    fn structure(kind: u8, handle: u16, formatted: &[u8], strings: &[&str]) -> Vec<u8> {
+        let mut raw = vec![kind, (4 + formatted.len()) as u8];
+        raw.extend_from_slice(&handle.to_le_bytes());
+        raw.extend_from_slice(formatted);
+        for s in strings {
+            raw.extend_from_slice(s.as_bytes());
+            raw.push(0);
+        }
+        if strings.is_empty() {
+            raw.push(0);
+        }
+        raw.push(0);
+        raw
+    }
+
+    /// Table as the Blade 15 Advanced (Early 2021) has it, minus the structures we do not read
+    fn blade_table() -> Vec<u8> {
+        // Manufacturer, product name, version, serial, UUID, wake up type, SKU, family
+        let mut system = vec![1, 2, 3, 4];
+        system.extend_from_slice(&[0x5A; 16]);
+        system.extend_from_slice(&[6, 5, 6]);
+        // Manufacturer, product, version, serial, asset tag, feature flags, location, chassis handle, board type, handles
+        let board = [1, 2, 3, 4, 0, 0x09, 0, 0x03, 0x00, 0x0A, 0];
+        let mut table = structure(1, 0x0001, &system, &[
+            "Razer", "Blade 15 Advanced Model (Early 2021) - RZ09-0367", "8.04", "BY2117M12345678", "RZ09-0367BEA3"
+        ]);
+        table.extend(structure(2, 0x0002, &board, &["Razer", "CH580", "4", "BY2117M12345678"]));
+        table.extend(structure(127, 0x0003, &[], &[]));
+        table
+    }
+
+    #[test]
+    fn reads_a_dump() {
+        let dir = TempDir::new("smbios-dump");
+        let path = dir.write("DMI", blade_table());
+        let info = read_smbios_dump(&path).unwrap();
+        assert_eq!(info, SmbiosInfo {
+            product_name: "Blade 15 Advanced Model (Early 2021) - RZ09-0367".into(),
+            sku: "RZ09-0367BEA3".into(),
+            board_version: "4".into(),
+            serial: "BY2117M12345678".into()
+        });
+        assert!(read_smbios_dump(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn dump_picks_the_variant() {
+        let db = DeviceDb::parse(r#"{ "devices": [{
+            "product_id": "0x026D", "name": "Razer blade 15 early 2021 (Adv)", "class": "laptop", "fan_zones": 2,
+            "variants": [
+                { "match": { "sku": "RZ09-0367A" }, "name": "Razer blade 15 early 2021 (Adv, 2 fans)" },
+                { "match": { "sku": "RZ09-0367B", "board_version": "4" }, "name": "Razer blade 15 early 2021 (Adv, 1 fan)", "fan_zones": 1 }
+            ]
+        }] }"#, "test.json").unwrap();
+        let spec = db.get(0x026D).unwrap();
+
+        let dir = TempDir::new("smbios-refine");
+        let info = read_smbios_dump(&dir.write("DMI", blade_table())).unwrap();
+        let refined = spec.refine(&info);
+        assert_eq!(refined.name, "Razer blade 15 early 2021 (Adv, 1 fan)");
+        assert_eq!(refined.fan_zones, 1);
+
+        // A table without the strings keeps the base model
+        let empty = read_smbios_dump(&dir.write("empty", structure(127, 0x0001, &[], &[]))).unwrap();
+        assert_eq!(empty, SmbiosInfo::default());
+        assert_eq!(spec.refine(&empty).name, spec.name);
+    }
+}
@@ -0,0 +1,57 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::razer::{RazerError, RazerResult};
+
+/// Settings the user has saved, per device, in a JSON file. Keyed by product ID and serial,
+/// with entries for just the product ID acting as the default for every device of that model
+#[derive(Debug, Clone)]
+pub struct DeviceStore<T> {
+    path: Option<PathBuf>,
+    devices: HashMap<String, T>
+}
+
+impl<T> Default for DeviceStore<T> {
+    fn default() -> Self {
+        Self { path: None, devices: HashMap::new() }
+    }
+}
+
+fn key(product_id: u16, serial: Option<&str>) -> String {
+    match serial {
+        Some(s) => format!("{:04X}:{}", product_id, s),
+        None => format!("{:04X}", product_id)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> DeviceStore<T> {
+    /// Loads the store from `path`. A missing file is an empty store, which gets created on the first save
+    pub fn load<P: AsRef<Path>>(path: P) -> RazerResult<Self> {
+        let devices = match std::fs::read_to_string(path.as_ref()) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| RazerError::InvalidConfig(format!("{}: {}", path.as_ref().display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into())
+        };
+        Ok(Self { path: Some(path.as_ref().into()), devices })
+    }
+
+    /// Saved settings for a device, falling back to the model wide entry
+    pub fn get(&self, product_id: u16, serial: &str) -> Option<&T> {
+        self.devices.get(&key(product_id, Some(serial)))
+            .or_else(|| self.devices.get(&key(product_id, None)))
+    }
+
+    /// Remembers settings for a device and writes the store back out (if it was loaded from a file)
+    pub fn set(&mut self, product_id: u16, serial: &str, value: T) -> RazerResult<()> {
+        self.devices.insert(key(product_id, Some(serial)), value);
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let json = serde_json::to_string_pretty(&self.devices).map_err(|e| RazerError::InvalidConfig(e.to_string()))?;
+            std::fs::write(path, json)?;
+        }
+        Ok(())
+    }
+}
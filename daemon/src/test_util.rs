@@ -0,0 +1,79 @@
+//! Helpers shared between tests: fake devices, and fake sysfs (or any other) trees on disk
+
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use common::{RGBControl, RazerKeyboard, RazerLaptop, hw::DeviceType};
+
+use crate::{device::RazerDevice, retry::{FakeClock, RetryPolicy}, transport::FakeTransport};
+
+/// Device `product_id` on a [FakeTransport], retrying like most devices on a clock that never sleeps.
+/// Keep the returned clone to script replies and see what was sent
+pub fn fake_device(product_id: u16) -> (RazerDevice, FakeTransport) {
+    let fake = FakeTransport::new();
+    let mut dev = RazerDevice::new(DeviceType::from_id(product_id), Box::new(fake.clone()));
+    dev.retry = RetryPolicy::DEFAULT;
+    dev.set_clock(Arc::new(FakeClock::new()));
+    (dev, fake)
+}
+
+/// Args of every report sent to `fake` for (class, id), oldest first
+pub fn sent_args(fake: &FakeTransport, class: u8, id: u8) -> Vec<Vec<u8>> {
+    fake.sent().iter()
+        .filter(|raw| raw[7..9] == [class, id])
+        .map(|raw| raw[9..9 + raw[6] as usize].to_vec())
+        .collect()
+}
+
+/// Laptop with two fan zones, gaming and creator modes, and no boost or battery health.
+/// Tests that need something else change it with `RazerLaptop { .., ..test_laptop() }`
+pub fn test_laptop() -> RazerLaptop {
+    RazerLaptop {
+        has_logo_control: true,
+        fan_zone_count: 2,
+        min_fan_rpm: 3500,
+        max_fan_rpm: 5000,
+        has_gaming_mode: true,
+        has_creator_mode: true,
+        max_boost: None,
+        has_battery_health: false,
+        keyboard: RazerKeyboard { matrix_type: RGBControl::MultiColourMultiZone, has_brightness: true }
+    }
+}
+
+/// Directory under the system temp dir, removed again on drop
+pub struct TempDir {
+    path: PathBuf
+}
+
+impl TempDir {
+    /// `name` only has to be unique among tests, the process ID keeps parallel runs apart
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("razer-daemon-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `contents` to `rel` under the dir, creating any parent dirs
+    pub fn write<C: AsRef<[u8]>>(&self, rel: &str, contents: C) -> PathBuf {
+        let path = self.path.join(rel);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Contents of `rel` under the dir
+    pub fn read(&self, rel: &str) -> Vec<u8> {
+        std::fs::read(self.path.join(rel)).unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,168 @@
+//! Groups HID interfaces into the physical devices they belong to.
+//!
+//! A single keyboard shows up as several HID interfaces (keyboard, consumer keys, the
+//! collection that takes feature reports, ...). Only one of them is the control interface,
+//! the rest carry input, so discovery works on whole devices and picks from their interfaces.
+
+use common::hw::ControlInterface;
+
+/// One HID interface of a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HidInterface {
+    /// Path the backend opens the interface with (e.g. /dev/hidraw2)
+    pub path: String,
+    /// USB interface number, if known
+    pub interface: Option<i32>,
+    /// Usage page and usage of the top level collection. 0 if the backend could not tell
+    pub usage_page: u16,
+    pub usage: u16
+}
+
+impl HidInterface {
+    fn matches_interface(&self, spec: &ControlInterface) -> bool {
+        spec.interface.is_some() && self.interface == spec.interface
+    }
+
+    fn matches_usage(&self, spec: &ControlInterface) -> bool {
+        self.usage_page != 0 && self.usage_page == spec.usage_page && self.usage == spec.usage
+    }
+}
+
+/// A device on the bus, with all of its HID interfaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhysicalDevice {
+    pub product_id: u16,
+    /// Where the device sits on the bus, without the interface (e.g. usb-0000:00:14.0-8)
+    pub bus_path: String,
+    /// Every HID interface of the device, ordered by interface number
+    pub interfaces: Vec<HidInterface>
+}
+
+impl PhysicalDevice {
+    /// Picks the control interface. Interface number and usage both matching wins, then either
+    /// one on its own, then the lowest numbered interface
+    pub fn control_interface(&self, spec: &ControlInterface) -> Option<&HidInterface> {
+        self.interfaces.iter().find(|i| i.matches_interface(spec) && i.matches_usage(spec))
+            .or_else(|| self.interfaces.iter().find(|i| i.matches_interface(spec)))
+            .or_else(|| self.interfaces.iter().find(|i| i.matches_usage(spec)))
+            .or_else(|| self.interfaces.first())
+    }
+
+    /// Every interface apart from the control one. These carry key presses and such
+    pub fn input_interfaces(&self, spec: &ControlInterface) -> Vec<&HidInterface> {
+        let control = self.control_interface(spec);
+        self.interfaces.iter().filter(|i| Some(*i) != control).collect()
+    }
+}
+
+/// Strips the interface off a path, leaving where the device is on the bus.
+///
+/// Understands the kernel's HID_PHYS (usb-0000:00:14.0-8/input2) and
+/// hidapi's libusb paths (0001:0004:02). Anything else is returned as is
+pub fn bus_path(path: &str) -> &str {
+    if let Some((bus, intf)) = path.rsplit_once('/') {
+        if intf.starts_with("input") {
+            return bus
+        }
+    }
+    if path.matches(':').count() == 2 {
+        if let Some((bus, intf)) = path.rsplit_once(':') {
+            if !intf.is_empty() && intf.chars().all(|c| c.is_ascii_hexdigit()) {
+                return bus
+            }
+        }
+    }
+    path
+}
+
+/// Groups (product ID, bus path, interface) into physical devices, keeping the order
+/// devices were first seen in
+pub fn group_interfaces<I: IntoIterator<Item = (u16, String, HidInterface)>>(found: I) -> Vec<PhysicalDevice> {
+    let mut devices: Vec<PhysicalDevice> = Vec::new();
+    for (product_id, bus_path, intf) in found {
+        match devices.iter_mut().find(|d| d.product_id == product_id && d.bus_path == bus_path) {
+            Some(dev) => dev.interfaces.push(intf),
+            None => devices.push(PhysicalDevice { product_id, bus_path, interfaces: vec![intf] })
+        }
+    }
+    // Unknown interface numbers go last
+    devices.iter_mut().for_each(|d| d.interfaces.sort_by_key(|i| i.interface.unwrap_or(i32::MAX)));
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intf(interface: Option<i32>, usage_page: u16, usage: u16) -> HidInterface {
+        let path = match interface {
+            Some(n) => format!("/dev/hidraw{}", n),
+            None => "/dev/hidraw9".into()
+        };
+        HidInterface { path, interface, usage_page, usage }
+    }
+
+    fn device(interfaces: Vec<HidInterface>) -> PhysicalDevice {
+        PhysicalDevice { product_id: 0x0235, bus_path: "usb-0000:00:14.0-8".into(), interfaces }
+    }
+
+    #[test]
+    fn strips_the_interface() {
+        assert_eq!(bus_path("usb-0000:00:14.0-8/input2"), "usb-0000:00:14.0-8");
+        assert_eq!(bus_path("usb-0000:00:14.0-8.1/input0"), "usb-0000:00:14.0-8.1");
+        assert_eq!(bus_path("0001:0004:02"), "0001:0004");
+        assert_eq!(bus_path("0001:0004:0a"), "0001:0004");
+        // Not hidapi's form, so nothing to strip
+        assert_eq!(bus_path("0001:0004:input"), "0001:0004:input");
+        assert_eq!(bus_path("0001:0004:"), "0001:0004:");
+        assert_eq!(bus_path("0001:0004:02:01"), "0001:0004:02:01");
+        assert_eq!(bus_path("/dev/hidraw2"), "/dev/hidraw2");
+        // The libusb transport's own paths have the same shape
+        assert_eq!(bus_path("usb:3-8:2"), "usb:3-8");
+    }
+
+    #[test]
+    fn picks_the_control_interface() {
+        let spec = ControlInterface { interface: Some(2), usage_page: 0x01, usage: 0x02 };
+        let both = intf(Some(2), 0x01, 0x02);
+        let number = intf(Some(2), 0x01, 0x06);
+        let usage = intf(Some(1), 0x01, 0x02);
+        let first = intf(Some(0), 0x01, 0x06);
+
+        assert_eq!(device(vec![first.clone(), usage.clone(), number.clone(), both.clone()]).control_interface(&spec), Some(&both));
+        assert_eq!(device(vec![first.clone(), usage.clone(), number.clone()]).control_interface(&spec), Some(&number));
+        assert_eq!(device(vec![first.clone(), usage.clone()]).control_interface(&spec), Some(&usage));
+        assert_eq!(device(vec![first.clone()]).control_interface(&spec), Some(&first));
+        assert_eq!(device(vec![]).control_interface(&spec), None);
+
+        // No interface number in the spec only goes by usage, and a backend that could not read
+        // the usage never matches on it
+        let by_usage = ControlInterface::DEFAULT;
+        assert_eq!(device(vec![first.clone(), usage.clone()]).control_interface(&by_usage), Some(&usage));
+        let unread = intf(Some(1), 0, 0);
+        let spec = ControlInterface { interface: None, usage_page: 0, usage: 0 };
+        assert_eq!(device(vec![first.clone(), unread]).control_interface(&spec), Some(&first));
+
+        let dev = device(vec![first.clone(), usage.clone(), both.clone()]);
+        let spec = ControlInterface { interface: Some(2), usage_page: 0x01, usage: 0x02 };
+        assert_eq!(dev.input_interfaces(&spec), vec![&first, &usage]);
+    }
+
+    #[test]
+    fn groups_by_device() {
+        let other = "usb-0000:00:14.0-9".to_string();
+        let bus = "usb-0000:00:14.0-8".to_string();
+        let grouped = group_interfaces(vec![
+            (0x0235, bus.clone(), intf(Some(2), 0x01, 0x02)),
+            (0x0235, other.clone(), intf(Some(0), 0x01, 0x06)),
+            (0x0235, bus.clone(), intf(None, 0x01, 0x02)),
+            (0x0235, bus.clone(), intf(Some(0), 0x01, 0x06)),
+            // Same port, different product (a receiver swapped for a cable)
+            (0x0084, bus.clone(), intf(Some(1), 0x01, 0x02))
+        ]);
+        assert_eq!(grouped.iter().map(|d| (d.product_id, d.bus_path.as_str())).collect::<Vec<_>>(),
+            vec![(0x0235, bus.as_str()), (0x0235, other.as_str()), (0x0084, bus.as_str())]);
+        // Ordered by interface number, unknown numbers last
+        assert_eq!(grouped[0].interfaces.iter().map(|i| i.interface).collect::<Vec<_>>(), vec![Some(0), Some(2), None]);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{fs::File, io::{BufRead, BufReader, LineWriter, Write}, path::Path, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::razer::{RazerError, RazerPacket, RazerResult};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceDirection {
+    /// Host -> device
+    Out,
+    /// Device -> host
+    In
+}
+
+/// One feature report going to or from a device. Traces are stored as one of these per line (JSON lines)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceEntry {
+    /// Microseconds since the unix epoch
+    pub time_us: u64,
+    pub product_id: u16,
+    pub device: String,
+    pub serial: String,
+    pub dir: TraceDirection,
+    /// Raw report as hex. Empty if the transport failed
+    pub data: String,
+    /// What the report decodes to, or why it did not
+    pub decoded: String,
+    /// Set if the transport itself failed
+    pub error: Option<String>
+}
+
+impl TraceEntry {
+    pub fn bytes(&self) -> RazerResult<Vec<u8>> {
+        from_hex(&self.data)
+    }
+}
+
+/// Writes trace entries to a file. Clones share the same file, so one trace can cover every device
+#[derive(Clone)]
+pub struct TraceWriter {
+    out: Arc<Mutex<LineWriter<File>>>
+}
+
+impl TraceWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> RazerResult<Self> {
+        Ok(Self { out: Arc::new(Mutex::new(LineWriter::new(File::create(path)?))) })
+    }
+
+    /// Records a report. `result` is the bytes that went over the wire, or the transport's error
+    pub fn record(&self, product_id: u16, device: &str, serial: &str, dir: TraceDirection, result: Result<&[u8], &RazerError>) {
+        let time_us = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0);
+        let (data, decoded, error) = match result {
+            Ok(buf) => {
+                let decoded = match RazerPacket::decode(buf) {
+                    Ok(p) => format!("{}", p),
+                    Err(e) => format!("{:?}", e)
+                };
+                (to_hex(buf), decoded, None)
+            },
+            Err(e) => (String::new(), String::new(), Some(format!("{:?}", e)))
+        };
+        let entry = TraceEntry {
+            time_us,
+            product_id,
+            device: device.into(),
+            serial: serial.into(),
+            dir,
+            data,
+            decoded,
+            error
+        };
+        if let Ok(line) = serde_json::to_string(&entry) {
+            let mut out = self.out.lock().unwrap();
+            if let Err(e) = writeln!(out, "{}", line) {
+                eprintln!("Error writing packet trace! {}", e);
+            }
+        }
+    }
+}
+
+/// Loads a trace written by [TraceWriter]
+pub fn read_trace<P: AsRef<Path>>(path: P) -> RazerResult<Vec<TraceEntry>> {
+    let mut entries = Vec::new();
+    for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).map_err(|e| RazerError::InvalidTrace(format!("line {}: {}", idx + 1, e)))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+pub(crate) fn to_hex(buf: &[u8]) -> String {
+    buf.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+// is_multiple_of is newer than our MSRV
+#[allow(clippy::manual_is_multiple_of)]
+fn from_hex(s: &str) -> RazerResult<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return Err(RazerError::InvalidTrace(format!("odd length hex '{}'", s)))
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i+2], 16).map_err(|_| RazerError::InvalidTrace(format!("bad hex '{}'", s))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use common::hw::{DeviceType, FirmwareVersion};
+
+    use crate::{commands::{GetFirmware, RazerCommand}, device::RazerDevice, retry::FakeClock, test_util::{TempDir, fake_device}, transport::{FakeResponse, ReplayTransport}};
+
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(to_hex(&[0x00, 0x0f, 0xa0, 0xff]), "000fa0ff");
+        assert_eq!(from_hex("000fa0ff").unwrap(), vec![0x00, 0x0f, 0xa0, 0xff]);
+        assert_eq!(from_hex("000FA0FF").unwrap(), vec![0x00, 0x0f, 0xa0, 0xff]);
+        assert_eq!(from_hex("").unwrap(), Vec::<u8>::new());
+        assert!(matches!(from_hex("0a1"), Err(RazerError::InvalidTrace(e)) if e.contains("odd length")));
+        assert!(matches!(from_hex("0g"), Err(RazerError::InvalidTrace(e)) if e.contains("bad hex")));
+    }
+
+    #[test]
+    fn bad_line_says_where() {
+        let dir = TempDir::new("trace-bad-line");
+        let good = r#"{"time_us":1,"product_id":565,"device":"Test","serial":"SN","dir":"out","data":"00","decoded":"","error":null}"#;
+        let path = dir.write("trace.jsonl", format!("{}\n\n{}\n", good, r#"{"time_us":2}"#));
+        assert!(matches!(read_trace(&path), Err(RazerError::InvalidTrace(e)) if e.starts_with("line 3:")));
+
+        dir.write("trace.jsonl", format!("{}\n\n", good));
+        let entries = read_trace(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].dir, TraceDirection::Out);
+        assert_eq!(entries[0].bytes().unwrap(), vec![0x00]);
+    }
+
+    #[test]
+    fn recorded_trace_replays() {
+        let dir = TempDir::new("trace-round-trip");
+        let path = dir.path().join("trace.jsonl");
+        let (mut dev, fake) = fake_device(0x0235);
+        fake.respond_to(GetFirmware::CLASS, GetFirmware::ID, FakeResponse::Reply(vec![0x01, 0x02]));
+        dev.enable_trace(TraceWriter::create(&path).unwrap());
+        assert_eq!(dev.execute(&GetFirmware).unwrap(), FirmwareVersion { major: 1, minor: 2 });
+        drop(dev);
+
+        let entries = read_trace(&path).unwrap();
+        assert_eq!(entries.iter().map(|e| e.dir).collect::<Vec<_>>(), vec![TraceDirection::Out, TraceDirection::In]);
+        assert_eq!(entries[0].bytes().unwrap(), fake.sent()[0]);
+        assert!(entries.iter().all(|e| e.product_id == 0x0235 && e.error.is_none()));
+
+        let mut replay = RazerDevice::new(DeviceType::from_id(0x0235), Box::new(ReplayTransport::new(entries)));
+        replay.set_clock(Arc::new(FakeClock::new()));
+        assert_eq!(replay.execute(&GetFirmware).unwrap(), FirmwareVersion { major: 1, minor: 2 });
+        // The trace is used up
+        assert!(matches!(replay.execute(&GetFirmware), Err(RazerError::TransportClosed)));
+    }
+}
@@ -0,0 +1,191 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}};
+
+use crate::razer::{RazerCmdStatus, RazerError, RazerPacket, RazerResult};
+
+use super::RazerTransport;
+
+/// How the fake answers the next feature report read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FakeResponse {
+    /// Echo the request back as successful (What most set commands get)
+    Echo,
+    /// Echo the request back as successful, with these args
+    Reply(Vec<u8>),
+    /// Echo the request back with this status
+    Status(RazerCmdStatus),
+    /// Echo the request back, but only return the first N bytes
+    ShortRead(usize),
+    /// Return exactly these bytes, no matter what was sent
+    Raw(Vec<u8>),
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    closed: bool,
+    sent: Vec<Vec<u8>>,
+    queued: VecDeque<FakeResponse>,
+    canned: HashMap<(u8, u8), FakeResponse>,
+}
+
+/// In memory stand in for a Razer device.
+///
+/// Clones share the same state, so keep a clone around to script responses and
+/// inspect what was sent after handing the transport to a [RazerDevice](crate::device::RazerDevice).
+///
+/// Responses are picked in this order: anything queued with [FakeTransport::push_response],
+/// then anything canned for the command with [FakeTransport::respond_to], otherwise [FakeResponse::Echo]
+#[derive(Debug, Clone, Default)]
+pub struct FakeTransport {
+    state: Arc<Mutex<FakeState>>
+}
+
+impl FakeTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a one off response for the next read
+    pub fn push_response(&self, resp: FakeResponse) {
+        self.state.lock().unwrap().queued.push_back(resp)
+    }
+
+    /// Always answers (class, cmd) with `resp`, unless something is queued
+    pub fn respond_to(&self, class: u8, cmd: u8, resp: FakeResponse) {
+        self.state.lock().unwrap().canned.insert((class, cmd), resp);
+    }
+
+    /// Every report that has been sent to the fake, oldest first
+    pub fn sent(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+}
+
+impl RazerTransport for FakeTransport {
+    fn send_feature_report(&mut self, buf: &[u8]) -> RazerResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(RazerError::TransportClosed)
+        }
+        state.sent.push(buf.to_vec());
+        Ok(())
+    }
+
+    fn get_feature_report(&mut self, buf: &mut [u8]) -> RazerResult<usize> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(RazerError::TransportClosed)
+        }
+        let request = match state.sent.last().map(|l| RazerPacket::decode(l)) {
+            Some(Ok(p)) => p,
+            _ => {
+                // Nothing sensible was sent, so the device just hands back zeros
+                buf.iter_mut().for_each(|x| *x = 0);
+                return Ok(buf.len())
+            }
+        };
+
+        let key = (request.cmd_class, request.cmd_id);
+        let resp = match state.queued.pop_front() {
+            Some(r) => r,
+            None => state.canned.get(&key).cloned().unwrap_or(FakeResponse::Echo)
+        };
+
+        let mut reply = request;
+        let bytes = match resp {
+            FakeResponse::Raw(raw) => raw,
+            FakeResponse::Echo => {
+                reply.status = RazerCmdStatus::Successful;
+                reply.set_crc();
+                reply.encode().to_vec()
+            },
+            FakeResponse::Reply(args) => {
+                reply.status = RazerCmdStatus::Successful;
+                reply.set_args(&args);
+                reply.encode().to_vec()
+            },
+            FakeResponse::Status(status) => {
+                reply.status = status;
+                reply.set_crc();
+                reply.encode().to_vec()
+            },
+            FakeResponse::ShortRead(n) => {
+                reply.status = RazerCmdStatus::Successful;
+                reply.set_crc();
+                let full = reply.encode();
+                full[0..std::cmp::min(n, full.len())].to_vec()
+            }
+        };
+        let count = std::cmp::min(bytes.len(), buf.len());
+        buf[0..count].copy_from_slice(&bytes[0..count]);
+        Ok(count)
+    }
+
+    fn close(&mut self) -> RazerResult<()> {
+        self.state.lock().unwrap().closed = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::razer::RAZER_REPORT_LEN;
+
+    use super::*;
+
+    /// Sends a request for (class, cmd) and decodes whatever comes back
+    fn round_trip(fake: &mut FakeTransport, class: u8, cmd: u8) -> RazerResult<RazerPacket> {
+        fake.send_feature_report(&RazerPacket::new(class, cmd, &[0x01]).encode())?;
+        let mut buf = [0; RAZER_REPORT_LEN];
+        let n = fake.get_feature_report(&mut buf)?;
+        RazerPacket::decode(&buf[..n])
+    }
+
+    #[test]
+    fn picks_queued_then_canned_then_echo() {
+        let mut fake = FakeTransport::new();
+        fake.respond_to(0x00, 0x81, FakeResponse::Reply(vec![0x01, 0x02]));
+        fake.push_response(FakeResponse::Status(RazerCmdStatus::Busy));
+
+        let reply = round_trip(&mut fake, 0x00, 0x81).unwrap();
+        assert_eq!(reply.status, RazerCmdStatus::Busy);
+        assert_eq!(reply.args[0], 0x01);
+        let reply = round_trip(&mut fake, 0x00, 0x81).unwrap();
+        assert_eq!(reply.status, RazerCmdStatus::Successful);
+        assert_eq!(reply.args[0..2], [0x01, 0x02]);
+        // Nothing canned for this one
+        let reply = round_trip(&mut fake, 0x03, 0x03).unwrap();
+        assert_eq!((reply.status, reply.cmd_class, reply.cmd_id), (RazerCmdStatus::Successful, 0x03, 0x03));
+        assert_eq!(fake.sent().len(), 3);
+    }
+
+    #[test]
+    fn scripts_broken_reads() {
+        let mut fake = FakeTransport::new();
+        fake.push_response(FakeResponse::ShortRead(10));
+        assert!(matches!(round_trip(&mut fake, 0x00, 0x81), Err(RazerError::InvalidLength(10))));
+        fake.push_response(FakeResponse::Raw(vec![0xFF; RAZER_REPORT_LEN]));
+        assert!(round_trip(&mut fake, 0x00, 0x81).is_err());
+
+        // Reading before anything was sent gets zeros
+        let mut buf = [0xAA; RAZER_REPORT_LEN];
+        assert_eq!(FakeTransport::new().get_feature_report(&mut buf).unwrap(), RAZER_REPORT_LEN);
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn clones_share_state() {
+        let fake = FakeTransport::new();
+        let mut transport = fake.clone();
+        transport.send_feature_report(&[0x00; RAZER_REPORT_LEN]).unwrap();
+        assert_eq!(fake.sent(), vec![vec![0x00; RAZER_REPORT_LEN]]);
+
+        transport.close().unwrap();
+        assert!(fake.is_closed());
+        assert!(matches!(transport.send_feature_report(&[0x00; RAZER_REPORT_LEN]), Err(RazerError::TransportClosed)));
+        assert!(matches!(transport.get_feature_report(&mut [0x00; RAZER_REPORT_LEN]), Err(RazerError::TransportClosed)));
+    }
+}
@@ -0,0 +1,36 @@
+use hidapi::HidDevice;
+
+use crate::razer::{RazerError, RazerResult};
+
+use super::RazerTransport;
+
+/// Transport backed by a hidapi device handle
+pub struct HidTransport {
+    device: Option<HidDevice>
+}
+
+impl HidTransport {
+    pub fn new(device: HidDevice) -> Self {
+        Self { device: Some(device) }
+    }
+
+    fn handle(&self) -> RazerResult<&HidDevice> {
+        self.device.as_ref().ok_or(RazerError::TransportClosed)
+    }
+}
+
+impl RazerTransport for HidTransport {
+    fn send_feature_report(&mut self, buf: &[u8]) -> RazerResult<()> {
+        Ok(self.handle()?.send_feature_report(buf)?)
+    }
+
+    fn get_feature_report(&mut self, buf: &mut [u8]) -> RazerResult<usize> {
+        Ok(self.handle()?.get_feature_report(buf)?)
+    }
+
+    fn close(&mut self) -> RazerResult<()> {
+        // hidapi closes the handle when it is dropped
+        self.device.take();
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+use std::{fs::{File, OpenOptions}, os::unix::io::AsRawFd, path::{Path, PathBuf}};
+
+use crate::razer::{RazerError, RazerResult};
+
+use super::RazerTransport;
+
+// From linux/hidraw.h
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct HidrawDevinfo {
+    bustype: u32,
+    vendor: i16,
+    product: i16
+}
+
+const IOC_READ: libc::c_ulong = 2;
+const IOC_WRITE: libc::c_ulong = 1;
+
+const fn ioc(dir: libc::c_ulong, nr: libc::c_ulong, size: usize) -> libc::c_ulong {
+    (dir << 30) | ((size as libc::c_ulong) << 16) | ((b'H' as libc::c_ulong) << 8) | nr
+}
+
+const HIDIOCGRAWINFO: libc::c_ulong = ioc(IOC_READ, 0x03, std::mem::size_of::<HidrawDevinfo>());
+
+const fn hidiocsfeature(len: usize) -> libc::c_ulong {
+    ioc(IOC_WRITE | IOC_READ, 0x06, len)
+}
+
+const fn hidiocgfeature(len: usize) -> libc::c_ulong {
+    ioc(IOC_WRITE | IOC_READ, 0x07, len)
+}
+
+/// A hidraw node found in sysfs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HidrawNode {
+    /// Device node to open, e.g. /dev/hidraw0
+    pub node: PathBuf,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// USB interface number, if the device is on USB
+    pub interface: Option<i32>,
+    /// Physical path from the kernel (HID_PHYS), e.g. usb-0000:00:14.0-8/input0
+    pub phys: String,
+    /// Usage page and usage of the top level collection, 0 if the report descriptor could not be read
+    pub usage_page: u16,
+    pub usage: u16,
+}
+
+/// Parses the uevent file of a HID device (`/sys/class/hidraw/hidrawN/device/uevent`)
+fn parse_uevent(uevent: &str) -> Option<(u16, u16, String)> {
+    let mut ids = None;
+    let mut phys = String::new();
+    for line in uevent.lines() {
+        if let Some(id) = line.strip_prefix("HID_ID=") {
+            // bus:vendor:product, all hex
+            let parts: Vec<&str> = id.split(':').collect();
+            if parts.len() == 3 {
+                let vid = u32::from_str_radix(parts[1], 16).ok()?;
+                let pid = u32::from_str_radix(parts[2], 16).ok()?;
+                ids = Some((vid as u16, pid as u16));
+            }
+        } else if let Some(p) = line.strip_prefix("HID_PHYS=") {
+            phys = p.into();
+        }
+    }
+    ids.map(|(vid, pid)| (vid, pid, phys))
+}
+
+/// First Usage Page and Usage in a HID report descriptor, which is the top level collection's.
+/// Either is 0 if the descriptor does not have it (HID 1.11, section 6.2.2)
+pub fn parse_report_descriptor(desc: &[u8]) -> (u16, u16) {
+    let (mut usage_page, mut usage) = (None, None);
+    let mut i = 0;
+    while i < desc.len() && (usage_page.is_none() || usage.is_none()) {
+        let prefix = desc[i];
+        if prefix == 0xFE {
+            // Long item: size, tag, then data. Nothing we want is ever one
+            let size = desc.get(i + 1).copied().unwrap_or(0) as usize;
+            i += 3 + size;
+            continue;
+        }
+        let size = match prefix & 0x03 {
+            3 => 4,
+            x => x as usize
+        };
+        let data = match desc.get(i + 1..i + 1 + size) {
+            Some(d) => d.iter().rev().fold(0u32, |acc, x| (acc << 8) | *x as u32),
+            None => break
+        };
+        match prefix & 0xFC {
+            // Usage Page (global)
+            0x04 if usage_page.is_none() => usage_page = Some(data as u16),
+            // Usage (local). A 4 byte usage carries its own page in the top half
+            0x08 if usage.is_none() => {
+                if size == 4 && usage_page.is_none() {
+                    usage_page = Some((data >> 16) as u16);
+                }
+                usage = Some(data as u16)
+            },
+            _ => {}
+        }
+        i += 1 + size;
+    }
+    (usage_page.unwrap_or(0), usage.unwrap_or(0))
+}
+
+/// Pulls the interface number out of HID_PHYS (Ends with /inputN on USB)
+fn phys_interface(phys: &str) -> Option<i32> {
+    phys.rsplit('/').next()?.strip_prefix("input")?.parse().ok()
+}
+
+/// Walks `<sysfs_root>/class/hidraw`, returning every node belonging to `vendor_id`.
+/// Nodes are resolved against `dev_root` (Normally /dev)
+pub fn find_hidraw_nodes(sysfs_root: &Path, dev_root: &Path, vendor_id: u16) -> RazerResult<Vec<HidrawNode>> {
+    let mut nodes = Vec::new();
+    for entry in std::fs::read_dir(sysfs_root.join("class/hidraw"))? {
+        let entry = entry?;
+        let uevent = match std::fs::read_to_string(entry.path().join("device/uevent")) {
+            Ok(u) => u,
+            Err(_) => continue
+        };
+        if let Some((vid, pid, phys)) = parse_uevent(&uevent) {
+            if vid == vendor_id {
+                let (usage_page, usage) = std::fs::read(entry.path().join("device/report_descriptor"))
+                    .map(|d| parse_report_descriptor(&d))
+                    .unwrap_or((0, 0));
+                nodes.push(HidrawNode {
+                    node: dev_root.join(entry.file_name()),
+                    vendor_id: vid,
+                    product_id: pid,
+                    interface: phys_interface(&phys),
+                    phys,
+                    usage_page,
+                    usage
+                })
+            }
+        }
+    }
+    // read_dir order is arbitrary, keep hidraw0 before hidraw1
+    nodes.sort_by(|a, b| a.node.cmp(&b.node));
+    Ok(nodes)
+}
+
+/// Transport that talks to /dev/hidrawN directly with the hidraw feature report ioctls.
+/// No hidapi or libusb needed
+pub struct HidrawTransport {
+    file: Option<File>
+}
+
+impl HidrawTransport {
+    pub fn open(node: &Path) -> RazerResult<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(node)?;
+        Ok(Self { file: Some(file) })
+    }
+
+    fn fd(&self) -> RazerResult<libc::c_int> {
+        self.file.as_ref().map(|f| f.as_raw_fd()).ok_or(RazerError::TransportClosed)
+    }
+
+    /// Asks the kernel which device is behind the node, returning (vendor ID, product ID)
+    pub fn raw_info(&self) -> RazerResult<(u16, u16)> {
+        let mut info = HidrawDevinfo::default();
+        let res = unsafe { libc::ioctl(self.fd()?, HIDIOCGRAWINFO as _, &mut info as *mut HidrawDevinfo) };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error().into())
+        }
+        Ok((info.vendor as u16, info.product as u16))
+    }
+}
+
+impl RazerTransport for HidrawTransport {
+    fn send_feature_report(&mut self, buf: &[u8]) -> RazerResult<()> {
+        let res = unsafe { libc::ioctl(self.fd()?, hidiocsfeature(buf.len()) as _, buf.as_ptr()) };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error().into())
+        }
+        Ok(())
+    }
+
+    fn get_feature_report(&mut self, buf: &mut [u8]) -> RazerResult<usize> {
+        let res = unsafe { libc::ioctl(self.fd()?, hidiocgfeature(buf.len()) as _, buf.as_mut_ptr()) };
+        if res < 0 {
+            return Err(std::io::Error::last_os_error().into())
+        }
+        Ok(res as usize)
+    }
+
+    fn close(&mut self) -> RazerResult<()> {
+        self.file.take();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_descriptor_usage() {
+        // Start of a Razer keyboard's boot interface
+        let keyboard = [0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x05, 0x07, 0x19, 0xE0];
+        assert_eq!(parse_report_descriptor(&keyboard), (0x01, 0x06));
+        // Mouse collection with the feature reports
+        let mouse = [0x05, 0x01, 0x09, 0x02, 0xA1, 0x01, 0x09, 0x01, 0xA1, 0x00];
+        assert_eq!(parse_report_descriptor(&mouse), (0x01, 0x02));
+        // Vendor page written as a 2 byte item, and a 4 byte usage with its own page
+        assert_eq!(parse_report_descriptor(&[0x06, 0x00, 0xFF, 0x09, 0x01, 0xA1, 0x01]), (0xFF00, 0x01));
+        assert_eq!(parse_report_descriptor(&[0x0B, 0x80, 0x00, 0x0C, 0x00, 0xA1, 0x01]), (0x0C, 0x80));
+        // Long items are skipped over
+        assert_eq!(parse_report_descriptor(&[0xFE, 0x02, 0x10, 0xAA, 0xBB, 0x05, 0x0C, 0x09, 0x01]), (0x0C, 0x01));
+        // Truncated or empty
+        assert_eq!(parse_report_descriptor(&[0x05, 0x01, 0x0A, 0x01]), (0x01, 0));
+        assert_eq!(parse_report_descriptor(&[]), (0, 0));
+    }
+}
@@ -0,0 +1,85 @@
+use std::str::FromStr;
+
+use crate::razer::RazerResult;
+
+#[cfg(feature = "hidapi")]
+mod hid;
+mod fake;
+mod replay;
+#[cfg(feature = "usb")]
+mod usb;
+#[cfg(target_os = "linux")]
+mod hidraw;
+#[cfg(target_os = "linux")]
+mod openrazer;
+
+#[cfg(feature = "hidapi")]
+pub use hid::HidTransport;
+pub use fake::{FakeResponse, FakeTransport};
+pub use replay::ReplayTransport;
+#[cfg(feature = "usb")]
+pub use usb::{KEYBOARD_USAGE, UsbTransport, hid_interfaces, usb_bus_path};
+#[cfg(target_os = "linux")]
+pub use hidraw::{HidrawNode, HidrawTransport, find_hidraw_nodes};
+#[cfg(target_os = "linux")]
+pub use openrazer::{OPENRAZER_DRIVERS, OpenRazerNode, OpenRazerTransport, find_openrazer_devices};
+#[cfg(target_os = "linux")]
+pub(crate) use openrazer::parse_hid_name;
+
+/// A way of getting Razer feature reports to and from a device.
+///
+/// [RazerDevice](crate::device::RazerDevice) only ever talks to hardware through this,
+/// so anything that can answer a feature report (hidapi, a fake, ...) can sit behind it
+pub trait RazerTransport: Send {
+    /// Sends a feature report. `buf` includes the report index as its first byte
+    fn send_feature_report(&mut self, buf: &[u8]) -> RazerResult<()>;
+
+    /// Reads a feature report into `buf`, returning how many bytes were read.
+    /// `buf[0]` must hold the report index to read
+    fn get_feature_report(&mut self, buf: &mut [u8]) -> RazerResult<usize>;
+
+    /// Releases the underlying device. Any further reports will fail
+    fn close(&mut self) -> RazerResult<()>;
+}
+
+/// Which backend to find and talk to devices with
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransportKind {
+    #[cfg(feature = "hidapi")]
+    HidApi,
+    /// Raw USB control transfers through libusb
+    #[cfg(feature = "usb")]
+    Usb,
+    /// /dev/hidraw* directly, no hidapi or libusb needed
+    #[cfg(target_os = "linux")]
+    Hidraw,
+}
+
+impl Default for TransportKind {
+    // hidapi if we have it, hidraw otherwise
+    #[cfg(feature = "hidapi")]
+    fn default() -> Self {
+        Self::HidApi
+    }
+
+    #[cfg(all(not(feature = "hidapi"), target_os = "linux"))]
+    fn default() -> Self {
+        Self::Hidraw
+    }
+}
+
+impl FromStr for TransportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            #[cfg(feature = "hidapi")]
+            "hidapi" => Ok(Self::HidApi),
+            #[cfg(feature = "usb")]
+            "usb" => Ok(Self::Usb),
+            #[cfg(target_os = "linux")]
+            "hidraw" => Ok(Self::Hidraw),
+            _ => Err(format!("Unknown (or not compiled in) transport '{}'", s))
+        }
+    }
+}
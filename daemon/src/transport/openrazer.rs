@@ -0,0 +1,464 @@
+use std::{collections::HashMap, convert::TryFrom, path::{Path, PathBuf}, str::FromStr};
+
+use crate::{chroma::{Led, LedEffect}, commands::{GetFirmware, GetLedBrightness, GetLedEffect, GetLedRgb, GetLedState, GetSerial, RazerCommand, SetCustomFrameRow, SetExtCustomFrameRow, SetLedBrightness, SetLedEffect, SetLedRgb, SetLedState, ShowCustomFrame, ShowExtCustomFrame}, razer::{RAZER_MAX_ARGS, RAZER_REPORT_LEN, RazerCmdStatus, RazerError, RazerPacket, RazerResult}};
+
+use super::RazerTransport;
+
+/// HID drivers from the openrazer kernel module
+pub const OPENRAZER_DRIVERS: &[&str] = &["razerkbd", "razermouse", "razeraccessory", "razerkraken"];
+
+/// A device bound to one of the [OPENRAZER_DRIVERS]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenRazerNode {
+    /// Device directory holding the driver's attributes,
+    /// e.g. /sys/bus/hid/drivers/razerkbd/0003:1532:026D.0001
+    pub path: PathBuf,
+    pub driver: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Physical path from the kernel (HID_PHYS), empty if the uevent did not have one
+    pub phys: String,
+}
+
+/// Parses a HID device name (bus:vendor:product.instance, all hex)
+pub(crate) fn parse_hid_name(name: &str) -> Option<(u16, u16)> {
+    let ids = name.split('.').next()?;
+    let parts: Vec<&str> = ids.split(':').collect();
+    if parts.len() != 3 {
+        return None
+    }
+    Some((u16::from_str_radix(parts[1], 16).ok()?, u16::from_str_radix(parts[2], 16).ok()?))
+}
+
+/// Walks `<sysfs_root>/bus/hid/drivers` for devices bound to an openrazer driver.
+///
+/// openrazer binds every interface of a device but only creates its attributes on one,
+/// so only interfaces that have a `device_type` attribute are returned
+pub fn find_openrazer_devices(sysfs_root: &Path, vendor_id: u16) -> RazerResult<Vec<OpenRazerNode>> {
+    let mut nodes = Vec::new();
+    for driver in OPENRAZER_DRIVERS {
+        let dir = sysfs_root.join("bus/hid/drivers").join(driver);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(e) => e,
+            Err(_) => continue // Driver not loaded
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some((vid, pid)) = parse_hid_name(&name) {
+                if vid == vendor_id && entry.path().join("device_type").exists() {
+                    let phys = std::fs::read_to_string(entry.path().join("uevent")).unwrap_or_default()
+                        .lines()
+                        .find_map(|l| l.strip_prefix("HID_PHYS="))
+                        .unwrap_or_default()
+                        .to_string();
+                    nodes.push(OpenRazerNode {
+                        path: entry.path(),
+                        driver: driver.to_string(),
+                        vendor_id: vid,
+                        product_id: pid,
+                        phys
+                    })
+                }
+            }
+        }
+    }
+    nodes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(nodes)
+}
+
+/// Transport for devices the openrazer kernel driver owns.
+///
+/// Sending raw reports behind the driver's back upsets it, so instead this decodes each
+/// packet and carries it out through the driver's sysfs attributes, then answers the
+/// following read with a reply as the device would have. Commands with no matching
+/// attribute are answered with [RazerCmdStatus::NotSupported].
+///
+/// LEDs are driven through their `<led>_led_*` attributes where the driver has them.
+/// Newer devices only have `*matrix_effect_*` ones, which take the colour along with the
+/// effect, so the colour and effect last set are kept here to fill those in
+pub struct OpenRazerTransport {
+    dir: PathBuf,
+    reply: Option<[u8; RAZER_REPORT_LEN]>,
+    /// Colour and effect last set per LED
+    leds: HashMap<u8, ([u8; 3], LedEffect)>,
+    closed: bool
+}
+
+/// Colour matrix effects start with, until something sets one
+const DEFAULT_COLOUR: [u8; 3] = [0xFF, 0xFF, 0xFF];
+
+impl OpenRazerTransport {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into(), reply: None, leds: HashMap::new(), closed: false }
+    }
+
+    /// Directory holding the driver's attributes
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn read_attr(&self, name: &str) -> RazerResult<String> {
+        Ok(std::fs::read_to_string(self.dir.join(name))?.trim().to_string())
+    }
+
+    fn read_num<T: FromStr>(&self, name: &str) -> RazerResult<T> {
+        self.read_attr(name)?.parse().map_err(|_| RazerError::InvalidResponse)
+    }
+
+    fn write_attr(&self, name: &str, data: &[u8]) -> RazerResult<()> {
+        Ok(std::fs::write(self.dir.join(name), data)?)
+    }
+
+    /// Brightness attribute for an LED, if the driver has one
+    fn brightness_attr(led: u8) -> Option<&'static str> {
+        match led {
+            x if x == Led::Backlight as u8 => Some("matrix_brightness"),
+            x if x == Led::Logo as u8 => Some("logo_led_brightness"),
+            x if x == Led::ScrollWheel as u8 => Some("scroll_led_brightness"),
+            _ => None
+        }
+    }
+
+    /// Prefix of an LED's attributes (`<prefix>_led_state` and such)
+    fn led_prefix(led: u8) -> Option<&'static str> {
+        match led {
+            x if x == Led::Backlight as u8 => Some("backlight"),
+            x if x == Led::Logo as u8 => Some("logo"),
+            x if x == Led::ScrollWheel as u8 => Some("scroll"),
+            _ => None
+        }
+    }
+
+    /// `<prefix>_led_<name>` for an LED, if the driver has it
+    fn led_attr(&self, led: u8, name: &str) -> Option<String> {
+        Self::led_prefix(led).map(|p| format!("{}_led_{}", p, name)).filter(|a| self.dir.join(a).exists())
+    }
+
+    /// The matrix effect attribute for an LED (`matrix_effect_<name>` for the backlight,
+    /// `<prefix>_matrix_effect_<name>` otherwise), if the driver has it
+    fn matrix_effect_attr(&self, led: u8, name: &str) -> Option<String> {
+        let attr = match Self::led_prefix(led)? {
+            "backlight" => format!("matrix_effect_{}", name),
+            prefix => format!("{}_matrix_effect_{}", prefix, name)
+        };
+        Some(attr).filter(|a| self.dir.join(a).exists())
+    }
+
+    fn remembered(&self, led: u8) -> ([u8; 3], LedEffect) {
+        self.leds.get(&led).copied().unwrap_or((DEFAULT_COLOUR, LedEffect::Static))
+    }
+
+    /// Starts `effect` on an LED through its matrix effect attributes. False if the driver has no way to
+    fn write_matrix_effect(&self, led: u8, effect: LedEffect, rgb: [u8; 3]) -> RazerResult<bool> {
+        let (name, data) = match effect {
+            LedEffect::Static => ("static", rgb.to_vec()),
+            LedEffect::Breathing => ("breath", rgb.to_vec()),
+            LedEffect::Spectrum => ("spectrum", b"1".to_vec()),
+            LedEffect::Blinking => return Ok(false)
+        };
+        match self.matrix_effect_attr(led, name) {
+            Some(attr) => self.write_attr(&attr, &data).map(|_| true),
+            None => Ok(false)
+        }
+    }
+
+    /// Carries out `req`, returning the args to reply with. None means there is no attribute for it
+    fn handle(&mut self, req: &RazerPacket) -> RazerResult<Option<Vec<u8>>> {
+        let args = &req.args[0..(req.data_size as usize).min(RAZER_MAX_ARGS)];
+        let attr_exists = |name: &str| self.dir.join(name).exists();
+        match (req.cmd_class, req.cmd_id) {
+            (GetSerial::CLASS, GetSerial::ID) if attr_exists("device_serial") => {
+                let mut reply = self.read_attr("device_serial")?.into_bytes();
+                reply.resize(0x16, 0);
+                Ok(Some(reply))
+            },
+            (GetFirmware::CLASS, GetFirmware::ID) if attr_exists("firmware_version") => {
+                // Driver formats it as vX.Y
+                let fw = self.read_attr("firmware_version")?;
+                let mut parts = fw.trim_start_matches('v').split('.').map(|x| x.parse::<u8>());
+                match (parts.next(), parts.next()) {
+                    (Some(Ok(major)), Some(Ok(minor))) => Ok(Some(vec![major, minor])),
+                    _ => Err(RazerError::InvalidResponse)
+                }
+            },
+            (SetLedBrightness::CLASS, SetLedBrightness::ID) if args.len() >= 3 => {
+                match Self::brightness_attr(args[1]).filter(|a| attr_exists(a)) {
+                    Some(attr) => {
+                        self.write_attr(attr, args[2].to_string().as_bytes())?;
+                        Ok(Some(args.to_vec()))
+                    },
+                    None => Ok(None)
+                }
+            },
+            (GetLedBrightness::CLASS, GetLedBrightness::ID) if args.len() >= 3 => {
+                match Self::brightness_attr(args[1]).filter(|a| attr_exists(a)) {
+                    Some(attr) => Ok(Some(vec![args[0], args[1], self.read_num(attr)?])),
+                    None => Ok(None)
+                }
+            },
+            (SetLedState::CLASS, SetLedState::ID) if args.len() >= 3 => {
+                let (led, on) = (args[1], args[2] != 0);
+                if let Some(attr) = self.led_attr(led, "state") {
+                    self.write_attr(&attr, if on { b"1" } else { b"0" })?;
+                    return Ok(Some(args.to_vec()))
+                }
+                // No on/off switch, so off is the none effect and on brings the last effect back
+                let (rgb, effect) = self.remembered(led);
+                let done = match (on, self.matrix_effect_attr(led, "none")) {
+                    (false, Some(attr)) => self.write_attr(&attr, b"1").map(|_| true)?,
+                    (false, None) => false,
+                    (true, _) => self.write_matrix_effect(led, effect, rgb)?
+                };
+                Ok(Some(args.to_vec()).filter(|_| done))
+            },
+            (GetLedState::CLASS, GetLedState::ID) if args.len() >= 3 => {
+                match self.led_attr(args[1], "state") {
+                    Some(attr) => Ok(Some(vec![args[0], args[1], (self.read_num::<u8>(&attr)? != 0) as u8])),
+                    None => Ok(None)
+                }
+            },
+            (SetLedEffect::CLASS, SetLedEffect::ID) if args.len() >= 3 => {
+                let (led, effect) = (args[1], LedEffect::try_from(args[2])?);
+                let (rgb, _) = self.remembered(led);
+                let done = match self.led_attr(led, "effect") {
+                    Some(attr) => self.write_attr(&attr, (effect as u8).to_string().as_bytes()).map(|_| true)?,
+                    None => self.write_matrix_effect(led, effect, rgb)?
+                };
+                if !done {
+                    return Ok(None)
+                }
+                self.leds.insert(led, (rgb, effect));
+                Ok(Some(args.to_vec()))
+            },
+            (GetLedEffect::CLASS, GetLedEffect::ID) if args.len() >= 3 => {
+                let led = args[1];
+                let effect = match self.led_attr(led, "effect") {
+                    Some(attr) => self.read_num::<u8>(&attr)?,
+                    // Matrix effects can't be read back, go by what we set
+                    None if self.leds.contains_key(&led) => self.remembered(led).1 as u8,
+                    None => return Ok(None)
+                };
+                Ok(Some(vec![args[0], led, effect]))
+            },
+            (SetLedRgb::CLASS, SetLedRgb::ID) if args.len() >= 5 => {
+                let (led, rgb) = (args[1], [args[2], args[3], args[4]]);
+                let (_, effect) = self.remembered(led);
+                let done = match self.led_attr(led, "rgb") {
+                    Some(attr) => self.write_attr(&attr, &rgb).map(|_| true)?,
+                    // Only a colour change for the effects that have one
+                    None => match effect {
+                        LedEffect::Static | LedEffect::Breathing => self.write_matrix_effect(led, effect, rgb)?,
+                        _ => self.matrix_effect_attr(led, "static").is_some()
+                    }
+                };
+                if !done {
+                    return Ok(None)
+                }
+                self.leds.insert(led, (rgb, effect));
+                Ok(Some(args.to_vec()))
+            },
+            (GetLedRgb::CLASS, GetLedRgb::ID) if args.len() >= 5 => {
+                let led = args[1];
+                let rgb = match self.led_attr(led, "rgb") {
+                    Some(attr) => {
+                        let raw = std::fs::read(self.dir.join(attr))?;
+                        if raw.len() < 3 {
+                            return Err(RazerError::InvalidResponse)
+                        }
+                        [raw[0], raw[1], raw[2]]
+                    },
+                    None if self.leds.contains_key(&led) => self.remembered(led).0,
+                    None => return Ok(None)
+                };
+                Ok(Some(vec![args[0], led, rgb[0], rgb[1], rgb[2]]))
+            },
+            (SetCustomFrameRow::CLASS, SetCustomFrameRow::ID) if args.len() >= 4 && attr_exists("matrix_custom_frame") => {
+                // Driver takes row, start, stop then the colours, without our leading 0xFF
+                let stop = args[3].max(args[2]);
+                let len = 4 + (stop as usize - args[2] as usize + 1) * 3;
+                self.write_attr("matrix_custom_frame", &args[1..len.min(args.len())])?;
+                Ok(Some(args.to_vec()))
+            },
+            (SetExtCustomFrameRow::CLASS, SetExtCustomFrameRow::ID) if args.len() >= 5 && attr_exists("matrix_custom_frame") => {
+                // Same again, after the two leading zeros
+                let stop = args[4].max(args[3]);
+                let len = 5 + (stop as usize - args[3] as usize + 1) * 3;
+                self.write_attr("matrix_custom_frame", &args[2..len.min(args.len())])?;
+                Ok(Some(args.to_vec()))
+            },
+            (ShowCustomFrame::CLASS, ShowCustomFrame::ID) | (ShowExtCustomFrame::CLASS, ShowExtCustomFrame::ID) if attr_exists("matrix_effect_custom") => {
+                self.write_attr("matrix_effect_custom", b"1")?;
+                Ok(Some(args.to_vec()))
+            },
+            _ => Ok(None)
+        }
+    }
+}
+
+impl RazerTransport for OpenRazerTransport {
+    fn send_feature_report(&mut self, buf: &[u8]) -> RazerResult<()> {
+        if self.closed {
+            return Err(RazerError::TransportClosed)
+        }
+        let req = RazerPacket::decode(buf)?;
+        let mut reply = req;
+        match self.handle(&req) {
+            Ok(Some(args)) => {
+                reply.set_args(&args);
+                reply.status = RazerCmdStatus::Successful;
+            },
+            Ok(None) => reply.status = RazerCmdStatus::NotSupported,
+            Err(e) => {
+                eprintln!("openrazer: error running {} through {}: {:?}", req, self.dir.display(), e);
+                reply.status = RazerCmdStatus::Failure;
+            }
+        }
+        reply.set_crc();
+        self.reply = Some(reply.encode());
+        Ok(())
+    }
+
+    fn get_feature_report(&mut self, buf: &mut [u8]) -> RazerResult<usize> {
+        if self.closed {
+            return Err(RazerError::TransportClosed)
+        }
+        // Nothing sent yet, so nothing to answer. Same as a device that is still working on it
+        let reply = self.reply.unwrap_or_else(|| {
+            let mut tmp = [0u8; RAZER_REPORT_LEN];
+            tmp[1] = RazerCmdStatus::Busy as u8;
+            tmp
+        });
+        let len = buf.len().min(RAZER_REPORT_LEN);
+        buf[0..len].copy_from_slice(&reply[0..len]);
+        Ok(len)
+    }
+
+    fn close(&mut self) -> RazerResult<()> {
+        self.closed = true;
+        self.reply.take();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{hw::{DeviceType, FirmwareVersion}, lighting::{LogoMode, LogoState}};
+
+    use crate::{chroma::LedController, commands::GetDpi, chroma::LedStorage, device::RazerDevice, lighting::{apply_logo, read_logo}, test_util::TempDir};
+
+    use super::*;
+
+    const DEV: &str = "bus/hid/drivers/razerkbd/0003:1532:026D.0001";
+
+    /// A laptop as razerkbd lays it out. `attrs` are created empty on top of the basics
+    fn fake_openrazer(name: &str, attrs: &[&str]) -> TempDir {
+        let sysfs = TempDir::new(name);
+        sysfs.write(&format!("{}/device_type", DEV), "Razer Blade 15 Advanced (Early 2021)\n");
+        sysfs.write(&format!("{}/uevent", DEV), "DRIVER=razerkbd\nHID_ID=0003:00001532:0000026D\nHID_PHYS=usb-0000:00:14.0-8/input0\n");
+        sysfs.write(&format!("{}/device_serial", DEV), "PM1234567890\n");
+        sysfs.write(&format!("{}/firmware_version", DEV), "v1.2\n");
+        sysfs.write(&format!("{}/matrix_brightness", DEV), "255\n");
+        attrs.iter().for_each(|a| { sysfs.write(&format!("{}/{}", DEV, a), ""); });
+        sysfs
+    }
+
+    fn device(sysfs: &TempDir) -> RazerDevice {
+        RazerDevice::new(DeviceType::from_id(0x026D), Box::new(OpenRazerTransport::new(sysfs.path().join(DEV))))
+    }
+
+    fn attr(sysfs: &TempDir, name: &str) -> Vec<u8> {
+        sysfs.read(&format!("{}/{}", DEV, name))
+    }
+
+    #[test]
+    fn finds_bound_devices() {
+        let sysfs = fake_openrazer("openrazer-find", &[]);
+        // Other interfaces of the same device have no attributes
+        sysfs.write("bus/hid/drivers/razerkbd/0003:1532:026D.0002/uevent", "");
+        sysfs.write("bus/hid/drivers/razermouse/0003:046D:C52B.0003/device_type", "Not Razer");
+        let nodes = find_openrazer_devices(sysfs.path(), 0x1532).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].product_id, 0x026D);
+        assert_eq!(nodes[0].driver, "razerkbd");
+        assert_eq!(nodes[0].phys, "usb-0000:00:14.0-8/input0");
+        assert!(find_openrazer_devices(&sysfs.path().join("missing"), 0x1532).unwrap().is_empty());
+    }
+
+    #[test]
+    fn device_info_and_brightness() {
+        let sysfs = fake_openrazer("openrazer-info", &[]);
+        let mut dev = device(&sysfs);
+        assert_eq!(dev.execute(&GetSerial).unwrap(), "PM1234567890");
+        assert_eq!(dev.execute(&GetFirmware).unwrap(), FirmwareVersion { major: 1, minor: 2 });
+        let backlight = LedController::new(Led::Backlight);
+        assert_eq!(backlight.read_brightness(&mut dev).unwrap(), 255);
+        backlight.set_brightness(&mut dev, 100).unwrap();
+        assert_eq!(attr(&sysfs, "matrix_brightness"), b"100");
+        // Nothing to map DPI onto
+        assert!(matches!(dev.execute(&GetDpi { storage: LedStorage::NoStore }), Err(RazerError::CmdNotSupported)));
+    }
+
+    #[test]
+    fn logo_through_led_attributes() {
+        let sysfs = fake_openrazer("openrazer-logo", &["logo_led_state", "logo_led_effect", "logo_led_brightness"]);
+        let mut dev = device(&sysfs);
+        let logo = LogoState { mode: LogoMode::Breathing, brightness: 200 };
+        apply_logo(&mut dev, logo).unwrap();
+        assert_eq!(attr(&sysfs, "logo_led_state"), b"1");
+        assert_eq!(attr(&sysfs, "logo_led_effect"), b"2");
+        assert_eq!(attr(&sysfs, "logo_led_brightness"), b"200");
+        assert_eq!(read_logo(&mut dev).unwrap(), logo);
+
+        apply_logo(&mut dev, LogoState { mode: LogoMode::Off, brightness: 200 }).unwrap();
+        assert_eq!(attr(&sysfs, "logo_led_state"), b"0");
+        assert_eq!(read_logo(&mut dev).unwrap().mode, LogoMode::Off);
+    }
+
+    #[test]
+    fn logo_through_matrix_effects() {
+        let effects = ["logo_matrix_effect_none", "logo_matrix_effect_static", "logo_matrix_effect_breath", "logo_matrix_effect_spectrum"];
+        let sysfs = fake_openrazer("openrazer-logo-matrix", &effects);
+        let mut dev = device(&sysfs);
+        let logo = LedController::new(Led::Logo);
+        logo.set_colour(&mut dev, [0x10, 0x20, 0x30]).unwrap();
+        assert_eq!(attr(&sysfs, "logo_matrix_effect_static"), [0x10, 0x20, 0x30]);
+        logo.set_effect(&mut dev, LedEffect::Breathing).unwrap();
+        assert_eq!(attr(&sysfs, "logo_matrix_effect_breath"), [0x10, 0x20, 0x30]);
+        assert_eq!(logo.read_effect(&mut dev).unwrap(), LedEffect::Breathing);
+        assert_eq!(logo.read_colour(&mut dev).unwrap(), [0x10, 0x20, 0x30]);
+
+        logo.set_on(&mut dev, false).unwrap();
+        assert_eq!(attr(&sysfs, "logo_matrix_effect_none"), b"1");
+        // Back on brings back the breathing, in the same colour
+        std::fs::write(sysfs.path().join(DEV).join("logo_matrix_effect_breath"), "").unwrap();
+        logo.set_on(&mut dev, true).unwrap();
+        assert_eq!(attr(&sysfs, "logo_matrix_effect_breath"), [0x10, 0x20, 0x30]);
+        // Nothing to read the state back from
+        assert!(matches!(logo.is_on(&mut dev), Err(RazerError::CmdNotSupported)));
+        assert!(matches!(logo.set_effect(&mut dev, LedEffect::Blinking), Err(RazerError::CmdNotSupported)));
+    }
+
+    #[test]
+    fn backlight_effects() {
+        let sysfs = fake_openrazer("openrazer-matrix", &["matrix_effect_none", "matrix_effect_spectrum", "matrix_effect_static"]);
+        let mut dev = device(&sysfs);
+        let backlight = LedController::new(Led::Backlight);
+        backlight.set_effect(&mut dev, LedEffect::Spectrum).unwrap();
+        assert_eq!(attr(&sysfs, "matrix_effect_spectrum"), b"1");
+        backlight.set_on(&mut dev, false).unwrap();
+        assert_eq!(attr(&sysfs, "matrix_effect_none"), b"1");
+        // No breath attribute on this one
+        assert!(matches!(backlight.set_effect(&mut dev, LedEffect::Breathing), Err(RazerError::CmdNotSupported)));
+    }
+
+    #[test]
+    fn custom_frame() {
+        let sysfs = fake_openrazer("openrazer-frame", &["matrix_custom_frame", "matrix_effect_custom"]);
+        let mut dev = device(&sysfs);
+        dev.execute(&SetCustomFrameRow { row: 2, start_col: 0, colours: vec![[1, 2, 3], [4, 5, 6]] }).unwrap();
+        assert_eq!(attr(&sysfs, "matrix_custom_frame"), [2, 0, 1, 1, 2, 3, 4, 5, 6]);
+        dev.execute(&ShowCustomFrame { storage: LedStorage::NoStore }).unwrap();
+        assert_eq!(attr(&sysfs, "matrix_effect_custom"), b"1");
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::VecDeque;
+
+use crate::{razer::{RazerError, RazerResult}, trace::{TraceDirection, TraceEntry}};
+
+use super::RazerTransport;
+
+/// Plays a recorded trace back as if it was the device.
+///
+/// Every read returns the next recorded incoming report. Outgoing reports are
+/// checked against the trace, and anything that does not match what was recorded
+/// fails with [RazerError::TransportError], since the replay is no longer accurate past that point
+pub struct ReplayTransport {
+    entries: VecDeque<TraceEntry>,
+    closed: bool
+}
+
+impl ReplayTransport {
+    /// `entries` should all be from the same device, in the order they were recorded
+    pub fn new(entries: Vec<TraceEntry>) -> Self {
+        Self { entries: entries.into(), closed: false }
+    }
+
+    /// Pops entries until one going in `dir` shows up
+    fn next(&mut self, dir: TraceDirection) -> RazerResult<TraceEntry> {
+        if self.closed {
+            return Err(RazerError::TransportClosed)
+        }
+        match self.entries.pop_front() {
+            Some(entry) if entry.dir == dir => Ok(entry),
+            Some(entry) => Err(RazerError::TransportError(format!(
+                "replay diverged: expected a {:?} report, trace has {:?} (recorded at {}us)", dir, entry.dir, entry.time_us))),
+            // Trace ran out, as far as we are concerned the device is gone
+            None => Err(RazerError::TransportClosed)
+        }
+    }
+}
+
+impl RazerTransport for ReplayTransport {
+    fn send_feature_report(&mut self, buf: &[u8]) -> RazerResult<()> {
+        let entry = self.next(TraceDirection::Out)?;
+        if let Some(e) = entry.error {
+            return Err(RazerError::TransportError(e))
+        }
+        if entry.bytes()? != buf {
+            return Err(RazerError::TransportError(format!("replay diverged: sent report differs from trace (recorded at {}us)", entry.time_us)))
+        }
+        Ok(())
+    }
+
+    fn get_feature_report(&mut self, buf: &mut [u8]) -> RazerResult<usize> {
+        let entry = self.next(TraceDirection::In)?;
+        if let Some(e) = entry.error {
+            return Err(RazerError::TransportError(e))
+        }
+        let data = entry.bytes()?;
+        let count = std::cmp::min(data.len(), buf.len());
+        buf[0..count].copy_from_slice(&data[0..count]);
+        Ok(count)
+    }
+
+    fn close(&mut self) -> RazerResult<()> {
+        self.closed = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{razer::RazerPacket, trace::{TraceDirection, to_hex}};
+
+    use super::*;
+
+    fn entry(dir: TraceDirection, packet: &RazerPacket) -> TraceEntry {
+        let data = to_hex(&packet.encode());
+        TraceEntry { time_us: 1, product_id: 0x0235, device: "Test".into(), serial: "SN".into(), dir, data, decoded: String::new(), error: None }
+    }
+
+    #[test]
+    fn replays_trace() {
+        let out = RazerPacket::new(0x00, 0x81, &[0, 0]);
+        let mut reply = out;
+        reply.set_args(&[1, 2]);
+        let mut replay = ReplayTransport::new(vec![entry(TraceDirection::Out, &out), entry(TraceDirection::In, &reply)]);
+        replay.send_feature_report(&out.encode()).unwrap();
+        let mut buf = [0u8; 91];
+        assert_eq!(replay.get_feature_report(&mut buf).unwrap(), 91);
+        assert_eq!(RazerPacket::decode(&buf).unwrap(), reply);
+        // Nothing left, the device is gone
+        assert!(matches!(replay.send_feature_report(&out.encode()), Err(RazerError::TransportClosed)));
+    }
+
+    #[test]
+    fn different_report_is_an_error() {
+        let out = RazerPacket::new(0x00, 0x81, &[0, 0]);
+        let mut replay = ReplayTransport::new(vec![entry(TraceDirection::Out, &out)]);
+        let other = RazerPacket::new(0x00, 0x82, &[0, 0]);
+        assert!(matches!(replay.send_feature_report(&other.encode()), Err(RazerError::TransportError(_))));
+    }
+
+    #[test]
+    fn out_of_order_is_an_error() {
+        let out = RazerPacket::new(0x00, 0x81, &[0, 0]);
+        let mut replay = ReplayTransport::new(vec![entry(TraceDirection::In, &out), entry(TraceDirection::Out, &out)]);
+        assert!(matches!(replay.send_feature_report(&out.encode()), Err(RazerError::TransportError(_))));
+    }
+}
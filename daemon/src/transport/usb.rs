@@ -0,0 +1,175 @@
+use std::{path::Path, time::Duration};
+
+use rusb::{Context, DeviceHandle};
+
+use crate::{razer::{RazerError, RazerResult}, topology::HidInterface};
+
+use super::RazerTransport;
+
+// HID class requests (HID 1.11, section 7.2)
+const HID_REQ_GET_REPORT: u8 = 0x01;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REPORT_TYPE_FEATURE: u16 = 0x03;
+// Class request, to an interface
+const REQ_TYPE_OUT: u8 = 0x21;
+const REQ_TYPE_IN: u8 = 0xA1;
+
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+// Interface descriptor codes (HID 1.11, section 4)
+const CLASS_HID: u8 = 0x03;
+const SUBCLASS_BOOT: u8 = 0x01;
+const PROTOCOL_KEYBOARD: u8 = 0x01;
+const PROTOCOL_MOUSE: u8 = 0x02;
+
+/// Usage page and usage of a keyboard's top level collection (Generic desktop / keyboard)
+pub const KEYBOARD_USAGE: (u16, u16) = (0x01, 0x06);
+const MOUSE_USAGE: (u16, u16) = (0x01, 0x02);
+
+/// Usage page and usage of a boot interface's top level collection. (0, 0) for anything
+/// else, as only the report descriptor says and the kernel driver has that
+pub fn boot_usage(sub_class: u8, protocol: u8) -> (u16, u16) {
+    match (sub_class, protocol) {
+        (SUBCLASS_BOOT, PROTOCOL_KEYBOARD) => KEYBOARD_USAGE,
+        (SUBCLASS_BOOT, PROTOCOL_MOUSE) => MOUSE_USAGE,
+        _ => (0, 0)
+    }
+}
+
+/// Every HID interface in the device's active configuration. Paths are `path` with the interface number on the end
+pub fn hid_interfaces(device: &rusb::Device<Context>, path: &str) -> RazerResult<Vec<HidInterface>> {
+    let config = device.active_config_descriptor()?;
+    Ok(config.interfaces()
+        .filter_map(|intf| intf.descriptors().next())
+        .filter(|desc| desc.class_code() == CLASS_HID)
+        .map(|desc| {
+            let (usage_page, usage) = boot_usage(desc.sub_class_code(), desc.protocol_code());
+            let number = desc.interface_number();
+            HidInterface { path: format!("{}:{}", path, number), interface: Some(number as i32), usage_page, usage }
+        })
+        .collect())
+}
+
+/// Where the device on `bus` behind `ports` sits, in the same form as the kernel's HID_PHYS
+/// (usb-0000:00:14.0-8), so it matches what the other backends report for the same device.
+///
+/// The host controller's name comes from `<sysfs_root>/bus/usb/devices/usb<bus>`. If that
+/// can't be read, the bus number stands in for it
+pub fn usb_bus_path(sysfs_root: &Path, bus: u8, ports: &[u8]) -> String {
+    let root_hub = sysfs_root.join(format!("bus/usb/devices/usb{}", bus));
+    let controller = std::fs::canonicalize(root_hub).ok()
+        .and_then(|p| p.parent().and_then(|c| c.file_name()).map(|c| c.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| bus.to_string());
+    let ports: Vec<String> = ports.iter().map(|p| p.to_string()).collect();
+    format!("usb-{}-{}", controller, ports.join("."))
+}
+
+/// Transport that sends feature reports as raw HID SET_REPORT/GET_REPORT control transfers
+/// through libusb, for machines where hidraw is off limits but usbfs is not.
+///
+/// The kernel only allows control transfers to an interface we have claimed, so the
+/// kernel driver is detached from `interface` for as long as the transport is open.
+/// Never open it on an interface that carries input (See [hid_interfaces]), or the
+/// device stops typing until it is closed
+pub struct UsbTransport {
+    handle: Option<DeviceHandle<Context>>,
+    interface: u8
+}
+
+impl UsbTransport {
+    pub fn open(device: &rusb::Device<Context>, interface: u8) -> RazerResult<Self> {
+        let mut handle = device.open()?;
+        // Not every platform can detach drivers, if it can't, claiming will tell us
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        handle.claim_interface(interface)?;
+        Ok(Self { handle: Some(handle), interface })
+    }
+
+    fn handle(&self) -> RazerResult<&DeviceHandle<Context>> {
+        self.handle.as_ref().ok_or(RazerError::TransportClosed)
+    }
+
+    /// wValue for a feature report with the given report ID
+    fn report_value(report_id: u8) -> u16 {
+        (HID_REPORT_TYPE_FEATURE << 8) | report_id as u16
+    }
+}
+
+impl RazerTransport for UsbTransport {
+    fn send_feature_report(&mut self, buf: &[u8]) -> RazerResult<()> {
+        // Report ID goes in wValue, not in the data stage
+        let (report_id, data) = buf.split_first().ok_or(RazerError::InvalidLength(0))?;
+        let value = Self::report_value(*report_id);
+        self.handle()?.write_control(REQ_TYPE_OUT, HID_REQ_SET_REPORT, value, self.interface as u16, data, TIMEOUT)?;
+        Ok(())
+    }
+
+    fn get_feature_report(&mut self, buf: &mut [u8]) -> RazerResult<usize> {
+        let (report_id, data) = buf.split_first_mut().ok_or(RazerError::InvalidLength(0))?;
+        let value = Self::report_value(*report_id);
+        let read = self.handle.as_ref().ok_or(RazerError::TransportClosed)?
+            .read_control(REQ_TYPE_IN, HID_REQ_GET_REPORT, value, self.interface as u16, data, TIMEOUT)?;
+        // Count the report ID, same as hidapi does
+        Ok(read + 1)
+    }
+
+    fn close(&mut self) -> RazerResult<()> {
+        if let Some(mut handle) = self.handle.take() {
+            // Gives the interface back to the kernel driver
+            handle.release_interface(self.interface)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::hw::ControlInterface;
+
+    use crate::topology::PhysicalDevice;
+
+    use super::*;
+
+    #[test]
+    fn boot_usages() {
+        assert_eq!(boot_usage(SUBCLASS_BOOT, PROTOCOL_KEYBOARD), KEYBOARD_USAGE);
+        assert_eq!(boot_usage(SUBCLASS_BOOT, PROTOCOL_MOUSE), MOUSE_USAGE);
+        assert_eq!(boot_usage(0x00, PROTOCOL_MOUSE), (0, 0));
+        assert_eq!(boot_usage(0x00, 0x00), (0, 0));
+    }
+
+    fn intf(number: i32, (usage_page, usage): (u16, u16)) -> HidInterface {
+        HidInterface { path: format!("usb:3-8:{}", number), interface: Some(number), usage_page, usage }
+    }
+
+    #[test]
+    fn control_interface_is_not_the_keyboard() {
+        // Typical Razer keyboard: boot keyboard, boot mouse (feature reports), then consumer keys
+        let phys = PhysicalDevice {
+            product_id: 0x026D,
+            bus_path: String::new(),
+            interfaces: vec![intf(0, KEYBOARD_USAGE), intf(1, MOUSE_USAGE), intf(2, (0, 0))]
+        };
+        assert_eq!(phys.control_interface(&ControlInterface::DEFAULT).unwrap().interface, Some(1));
+        // The database can still ask for a specific one
+        let spec = ControlInterface { interface: Some(2), ..ControlInterface::DEFAULT };
+        assert_eq!(phys.control_interface(&spec).unwrap().interface, Some(2));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bus_path_names_the_controller() {
+        let sysfs = crate::test_util::TempDir::new("usb-bus-path");
+        let controller = sysfs.path().join("devices/pci0000:00/0000:00:14.0/usb3");
+        std::fs::create_dir_all(&controller).unwrap();
+        std::fs::create_dir_all(sysfs.path().join("bus/usb/devices")).unwrap();
+        std::os::unix::fs::symlink(&controller, sysfs.path().join("bus/usb/devices/usb3")).unwrap();
+
+        assert_eq!(usb_bus_path(sysfs.path(), 3, &[8]), "usb-0000:00:14.0-8");
+        assert_eq!(usb_bus_path(sysfs.path(), 3, &[1, 4]), "usb-0000:00:14.0-1.4");
+        // Same as crate::topology::bus_path gives for the HID_PHYS of that device
+        assert_eq!(crate::topology::bus_path("usb-0000:00:14.0-1.4/input2"), usb_bus_path(sysfs.path(), 3, &[1, 4]));
+        // No sysfs to ask
+        assert_eq!(usb_bus_path(sysfs.path(), 5, &[2]), "usb-5-2");
+    }
+}
@@ -0,0 +1,149 @@
+//! One thread per device.
+//!
+//! Each [RazerDevice] is moved onto its own thread and only ever touched from there.
+//! Everything else talks to it through a [WorkerHandle], so a slow wireless mouse
+//! cannot hold up effects on the keyboard
+
+use std::{sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel}, thread::JoinHandle};
+
+use crate::{device::RazerDevice, razer::{RazerError, RazerResult}};
+
+/// How many jobs can be waiting on a worker before senders have to wait
+/// (or, with [WorkerHandle::try_send], give up)
+pub const WORKER_QUEUE_LEN: usize = 8;
+
+type Job = Box<dyn FnOnce(&mut RazerDevice) + Send>;
+
+enum WorkerMsg {
+    Run(Job),
+    Stop
+}
+
+/// Sends work to a device's worker thread. Clones all talk to the same worker
+#[derive(Clone)]
+pub struct WorkerHandle {
+    tx: SyncSender<WorkerMsg>
+}
+
+impl WorkerHandle {
+    /// Runs `f` on the device and waits for what it returns.
+    /// Fails with [RazerError::TransportClosed] if the worker has stopped
+    pub fn call<R, F>(&self, f: F) -> RazerResult<R>
+    where R: Send + 'static, F: FnOnce(&mut RazerDevice) -> R + Send + 'static {
+        let (tx, rx) = sync_channel(1);
+        let job: Job = Box::new(move |dev| { let _ = tx.send(f(dev)); });
+        self.tx.send(WorkerMsg::Run(job)).map_err(|_| RazerError::TransportClosed)?;
+        rx.recv().map_err(|_| RazerError::TransportClosed)
+    }
+
+    /// Queues `f` without waiting for it to run
+    pub fn send<F>(&self, f: F) -> RazerResult<()>
+    where F: FnOnce(&mut RazerDevice) + Send + 'static {
+        self.tx.send(WorkerMsg::Run(Box::new(f))).map_err(|_| RazerError::TransportClosed)
+    }
+
+    /// Like [WorkerHandle::send], but returns Ok(false) rather than waiting if the worker
+    /// is backed up. Meant for effect frames, where a dropped frame beats falling behind
+    pub fn try_send<F>(&self, f: F) -> RazerResult<bool>
+    where F: FnOnce(&mut RazerDevice) + Send + 'static {
+        match self.tx.try_send(WorkerMsg::Run(Box::new(f))) {
+            Ok(_) => Ok(true),
+            Err(TrySendError::Full(_)) => Ok(false),
+            Err(TrySendError::Disconnected(_)) => Err(RazerError::TransportClosed)
+        }
+    }
+}
+
+/// Owns a device's worker thread. Dropping it stops the worker, which closes the device
+pub struct DeviceWorker {
+    handle: WorkerHandle,
+    thread: Option<JoinHandle<()>>
+}
+
+impl DeviceWorker {
+    /// Moves `device` onto a new thread
+    pub fn spawn(device: RazerDevice) -> Self {
+        let (tx, rx) = sync_channel(WORKER_QUEUE_LEN);
+        let name = format!("worker {}", device.device_type.get_name());
+        let thread = std::thread::Builder::new()
+            .name(name)
+            .spawn(move || Self::run(device, rx))
+            .expect("Error spawning device worker");
+        Self { handle: WorkerHandle { tx }, thread: Some(thread) }
+    }
+
+    fn run(mut device: RazerDevice, rx: Receiver<WorkerMsg>) {
+        // Also ends if every handle is dropped
+        while let Ok(WorkerMsg::Run(job)) = rx.recv() {
+            job(&mut device);
+        }
+        let _ = device.close();
+    }
+
+    pub fn handle(&self) -> WorkerHandle {
+        self.handle.clone()
+    }
+}
+
+impl Drop for DeviceWorker {
+    fn drop(&mut self) {
+        let _ = self.handle.tx.send(WorkerMsg::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use crate::test_util::fake_device;
+
+    use super::*;
+
+    #[test]
+    fn runs_jobs_on_the_device() {
+        let (dev, _) = fake_device(0x0235);
+        let worker = DeviceWorker::spawn(dev);
+        let handle = worker.handle();
+        let (tx, rx) = channel();
+        handle.send(move |dev| tx.send(dev.device_type.get_id()).unwrap()).unwrap();
+        assert_eq!(handle.call(|dev| dev.device_type.get_id()).unwrap(), 0x0235);
+        // Queued work runs in order, so the send has already happened
+        assert_eq!(rx.try_recv().unwrap(), 0x0235);
+    }
+
+    #[test]
+    fn try_send_gives_up_when_backed_up() {
+        let (dev, _) = fake_device(0x0235);
+        let worker = DeviceWorker::spawn(dev);
+        let handle = worker.handle();
+        let (started_tx, started) = channel();
+        let (release, wait) = channel::<()>();
+        handle.send(move |_| {
+            started_tx.send(()).unwrap();
+            let _ = wait.recv();
+        }).unwrap();
+        started.recv().unwrap();
+
+        for _ in 0..WORKER_QUEUE_LEN {
+            assert!(handle.try_send(|_| ()).unwrap());
+        }
+        assert!(!handle.try_send(|_| ()).unwrap());
+        release.send(()).unwrap();
+        assert!(handle.call(|_| ()).is_ok());
+    }
+
+    #[test]
+    fn stopped_worker_fails_cleanly() {
+        let (dev, fake) = fake_device(0x0235);
+        let worker = DeviceWorker::spawn(dev);
+        let handle = worker.handle();
+        drop(worker);
+        assert!(fake.is_closed());
+        assert!(matches!(handle.call(|_| ()), Err(RazerError::TransportClosed)));
+        assert!(matches!(handle.send(|_| ()), Err(RazerError::TransportClosed)));
+        assert!(matches!(handle.try_send(|_| ()), Err(RazerError::TransportClosed)));
+    }
+}